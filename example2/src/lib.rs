@@ -9,10 +9,10 @@
 ///! Then look at src/modtest.rs to see why it works.
 ///! Every time you change modtest.rs you must copy it over to ./wasm_modules/
 
-use example2_derive::{wasm_meta, wasm_modules};
+use example2_derive::{wasm_finalize, wasm_meta, wasm_modules};
 
-// This macro expects to find a mymod.rs file in ./wasm_modules/
-wasm_modules!("mymod.rs");
+// This macro expects to find these files in ./wasm_modules/
+wasm_modules!("mymod.rs", "variants.rs", "fallback.rs", "add_field.rs", "derive_all.rs", "getters.rs", "no_floats.rs", "registered.rs");
 
 mod modtest;
 
@@ -26,3 +26,124 @@ mod modtest;
 pub struct Something {
     pub a: u64,
 }
+
+// modules can read enums (and impls, traits, type aliases, use items and macro_rules) too
+#[wasm_meta(|v: &mut variants::Variants| {
+    v.names = vec!["Red".into(), "Green".into(), "Blue".into()];
+})]
+pub enum Color {
+    Red,
+    Green,
+    Blue,
+}
+
+// this match only covers Red, so it only compiles because the module adds a `_` arm
+#[wasm_meta(|f: &mut fallback::Fallback| {
+    f.body = "()".into();
+})]
+const _: () = match Color::Red {
+    Color::Red => (),
+};
+
+// modules can change fields and signatures. the code below only compiles
+// because the module added `id` to Tagged, and `x` to double()
+#[wasm_meta(|f: &mut add_field::AddField| {
+    f.name = "id".into();
+    f.ty = "u64".into();
+})]
+pub struct Tagged {
+    pub name: String,
+}
+
+#[wasm_meta(|f: &mut add_field::AddField| {
+    f.name = "x".into();
+    f.ty = "u32".into();
+})]
+pub fn double() -> u32 {
+    x * 2
+}
+
+pub fn tagged_double(name: String) -> Tagged {
+    Tagged { name, id: double(21) as u64 }
+}
+
+// modules can add and remove attributes. Point can only be cloned and compared
+// because the module derived Clone and PartialEq for it
+#[wasm_meta(|d: &mut derive_all::DeriveAll| {
+    d.traits = "Clone, PartialEq, Debug".into();
+})]
+/// this doc comment is removed by the module
+pub struct Point {
+    pub x: i32,
+    pub y: i32,
+}
+
+pub fn same_point(p: &Point) -> bool {
+    p.clone() == *p
+}
+
+// modules can replace the item with any code, and add code before or after it.
+// this one adds Account::owner(), Account::balance() and ACCOUNT_FIELDS
+#[wasm_meta(|g: &mut getters::Getters| {
+    g.count_const = "ACCOUNT_FIELDS".into();
+})]
+pub struct Account {
+    owner: String,
+    balance: u64,
+}
+
+pub fn describe_account(a: &Account) -> String {
+    format!("{} has {} ({ACCOUNT_FIELDS} fields)", a.owner(), a.balance())
+}
+
+// modules can point their errors at a field, a param, or a statement of the closure.
+// try adding a `pub price: f64` field
+#[wasm_meta(|n: &mut no_floats::NoFloats| {
+    n.allow = vec!["weight".into()];
+})]
+pub struct Item {
+    pub cents: u64,
+    pub weight: f32,
+}
+
+// the module's path can have several segments. crate::shapes::sides is looked
+// for in wasm_modules/shapes/ first, and then in wasm_modules/
+mod shapes {
+    example2_derive::wasm_modules!("shapes/sides.rs");
+}
+
+#[wasm_meta(|s: &mut crate::shapes::sides::Sides| {
+    s.expected = 4;
+})]
+pub const SQUARE_SIDES: u32 = 4;
+
+// instead of a closure, you can pass the module and values for its ExportType's fields.
+// strings are .into()'d and arrays become vecs
+#[wasm_meta(variants, names = ["North", "South"])]
+pub enum Direction {
+    North,
+    South,
+}
+
+// several modules can run one after the other, separated by `;`. each one sees
+// the item as the one before it left it. Order gets an `id` and `total`, and derives Clone
+#[wasm_meta(
+    add_field, name = "id", ty = "u64";
+    |f: &mut add_field::AddField| {
+        f.name = "total".into();
+        f.ty = "u32".into();
+    };
+    derive_all, traits = "Clone"
+)]
+pub struct Order {}
+
+pub fn copy_order(id: u64) -> Order {
+    Order { id, total: 0 }.clone()
+}
+
+// runs modules over every item that has a wasm_meta. it goes last, so that it's expanded after them
+wasm_finalize!(registered, expected = ["Something", "Color", "Order", "SQUARE_SIDES"]);
+
+pub fn registered_items() -> &'static [&'static str] {
+    REGISTERED
+}
@@ -0,0 +1,21 @@
+/// adds a field to a struct, or a parameter to a function
+pub struct AddField {
+    pub name: String,
+    pub ty: String,
+}
+
+pub type ExportType = AddField;
+
+pub fn wasm_entrypoint(obj: &mut LibraryObj, cb: fn(&mut AddField)) {
+    let mut add = AddField { name: String::new(), ty: String::new() };
+    cb(&mut add);
+    match &mut obj.user_data {
+        UserData::Struct { fields, .. } => {
+            fields.push(UserField { is_public: true, name: add.name, ty: add.ty, attrs: vec![] });
+        }
+        UserData::Function { inputs, .. } => {
+            inputs.push(UserInput { is_self: false, name: add.name, ty: add.ty, attrs: vec![] });
+        }
+        _ => obj.compile_error("add_field only works on structs and functions"),
+    }
+}
@@ -0,0 +1,13 @@
+/// adds a derive to the item, and removes its doc comments
+pub struct DeriveAll {
+    pub traits: String,
+}
+
+pub type ExportType = DeriveAll;
+
+pub fn wasm_entrypoint(obj: &mut LibraryObj, cb: fn(&mut DeriveAll)) {
+    let mut derive = DeriveAll { traits: String::new() };
+    cb(&mut derive);
+    obj.remove_attributes("doc");
+    obj.add_attribute("derive", &format!("({})", derive.traits));
+}
@@ -0,0 +1,19 @@
+/// adds a `_ => body` arm to a match that doesnt have one
+pub struct Fallback {
+    pub body: String,
+}
+
+pub type ExportType = Fallback;
+
+pub fn wasm_entrypoint(obj: &mut LibraryObj, cb: fn(&mut Fallback)) {
+    let mut fallback = Fallback { body: "()".to_string() };
+    cb(&mut fallback);
+    match &mut obj.user_data {
+        UserData::Match { arms, .. } => {
+            if !arms.iter().any(|a| a.pattern == "_") {
+                arms.push(UserMatchArm { pattern: "_".to_string(), guard: String::new(), body: fallback.body });
+            }
+        }
+        _ => obj.compile_error("fallback only works on `const _: () = match ...`"),
+    }
+}
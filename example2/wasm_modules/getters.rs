@@ -0,0 +1,29 @@
+/// replaces a struct with itself plus a getter per field,
+/// and adds a const with the number of fields before it
+pub struct Getters {
+    pub count_const: String,
+}
+
+pub type ExportType = Getters;
+
+pub fn wasm_entrypoint(obj: &mut LibraryObj, cb: fn(&mut Getters)) {
+    let mut getters = Getters { count_const: String::new() };
+    cb(&mut getters);
+    let (name, fns) = match &obj.user_data {
+        UserData::Struct { name, fields, .. } => {
+            let fns: Vec<String> = fields.iter().map(|f| {
+                format!("pub fn {0}(&self) -> &{1} {{ &self.{0} }}", f.name, f.ty)
+            }).collect();
+            (name.clone(), fns)
+        }
+        _ => {
+            obj.compile_error("getters only works on structs");
+            return;
+        }
+    };
+    let code = format!("{}\nimpl {name} {{ {} }}", obj.item_source, fns.join("\n"));
+    obj.replace_item(code);
+    if !getters.count_const.is_empty() {
+        obj.add_code_before.push(format!("pub const {}: usize = {};", getters.count_const, fns.len()));
+    }
+}
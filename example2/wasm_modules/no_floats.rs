@@ -0,0 +1,27 @@
+/// errors on every float field of a struct, pointing at the field.
+/// fields listed in `allow` are skipped
+pub struct NoFloats {
+    pub allow: Vec<String>,
+}
+
+pub type ExportType = NoFloats;
+
+pub fn wasm_entrypoint(obj: &mut LibraryObj, cb: fn(&mut NoFloats)) {
+    let mut no_floats = NoFloats { allow: vec![] };
+    cb(&mut no_floats);
+    let floats: Vec<String> = match &obj.user_data {
+        UserData::Struct { fields, .. } => fields.iter()
+            .filter(|f| f.ty == "f32" || f.ty == "f64")
+            .map(|f| f.name.clone())
+            .collect(),
+        _ => {
+            obj.error("no_floats only works on structs", DiagnosticTarget::Item);
+            return;
+        }
+    };
+    for name in floats {
+        if !no_floats.allow.contains(&name) {
+            obj.error("floats arent allowed here", DiagnosticTarget::FieldName(name));
+        }
+    }
+}
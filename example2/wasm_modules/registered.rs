@@ -0,0 +1,20 @@
+/// for wasm_finalize!(). checks that every expected item has a wasm_meta,
+/// and adds a `REGISTERED` const with the names of all of them
+pub struct Registered {
+    pub expected: Vec<String>,
+}
+
+pub type ExportType = Registered;
+
+pub fn wasm_entrypoint(obj: &mut LibraryObj, cb: fn(&mut Registered)) {
+    let mut registered = Registered { expected: vec![] };
+    cb(&mut registered);
+    let names: Vec<String> = obj.registry.iter().map(|e| e.name.clone()).collect();
+    for expected in &registered.expected {
+        if !names.contains(expected) {
+            obj.compile_error(&format!("{expected} doesnt have a wasm_meta. found: {names:?}"));
+        }
+    }
+    let quoted: Vec<String> = names.iter().map(|n| format!("{n:?}")).collect();
+    obj.add_code_after.push(format!("pub const REGISTERED: &[&str] = &[{}];", quoted.join(", ")));
+}
@@ -0,0 +1,21 @@
+/// checks the value of a const
+pub struct Sides {
+    pub expected: u32,
+}
+
+pub type ExportType = Sides;
+
+pub fn wasm_entrypoint(obj: &mut LibraryObj, cb: fn(&mut Sides)) {
+    let mut sides = Sides { expected: 0 };
+    cb(&mut sides);
+    let actual = match &obj.user_data {
+        UserData::GlobalVariable { expr, .. } => expr.clone(),
+        _ => {
+            obj.compile_error("sides only works on consts and statics");
+            return;
+        }
+    };
+    if actual != sides.expected.to_string() {
+        obj.compile_error(&format!("expected {}, found {actual}", sides.expected));
+    }
+}
@@ -0,0 +1,21 @@
+/// the variants an enum must have, in order
+pub struct Variants {
+    pub names: Vec<String>,
+}
+
+pub type ExportType = Variants;
+
+pub fn wasm_entrypoint(obj: &mut LibraryObj, cb: fn(&mut Variants)) {
+    let mut expected = Variants { names: vec![] };
+    cb(&mut expected);
+    let names: Vec<String> = match &obj.user_data {
+        UserData::Enum { variants, .. } => variants.iter().map(|v| v.name.clone()).collect(),
+        _ => {
+            obj.compile_error("variants only works on enums");
+            return;
+        }
+    };
+    if names != expected.names {
+        obj.compile_error(&format!("expected the variants {:?}, found {:?}", expected.names, names));
+    }
+}
@@ -12,11 +12,19 @@ use syn::{
     ItemStatic,
     ItemConst,
     ItemMod,
+    ItemEnum,
+    ItemImpl,
+    ItemTrait,
+    ItemType,
+    ItemUse,
+    ItemMacro,
     Visibility,
     token::Pub,
+    parse::Parser,
+    punctuated::Punctuated,
     ExprMatch,
 };
-use quote::{quote, format_ident, ToTokens};
+use quote::{quote, quote_spanned, format_ident, ToTokens};
 use wasm_type_gen::*;
 
 // TODO: need to use locking? proc-macros run single threaded so i think this is safe?
@@ -38,25 +46,49 @@ fn get_wasmgen_base_dir() -> String {
     format!("{base_dir}/wasmgen")
 }
 
-fn should_do_file_operations() -> bool {
-    // through manual testing i've found that running cargo build uses RUST_BACKTRACE full
-    // whereas the cargo command used by IDEs sets this to short. basically: dont output command
-    // files every keystroke.. instead we only wish to do this when the user actually builds.
-    let mut should_do = false;
-    if let Ok(env) = std::env::var("RUST_BACKTRACE") {
-        if env == "full" {
-            should_do = true;
-        }
+/// Errors if WASM_TYPE_GEN_BUILD_MODE (or the config file) is set to something invalid
+fn should_do_file_operations() -> Result<bool, String> {
+    // dont output command files every keystroke.. instead we only wish to do this
+    // when the user actually builds. see wasm_type_gen::build_mode
+    build_mode().map(|mode| mode.should_write_files())
+}
+
+/// proc macros cant emit warnings on stable. instead we reference a deprecated const
+/// whose note is the message, which makes rustc print it as a warning pointing at `span`.
+fn emit_warning(message: &str, span: proc_macro2::Span) -> proc_macro2::TokenStream {
+    let name = Ident::new("wasm_meta_warning", span);
+    quote::quote_spanned! {span=>
+        const _: () = {
+            #[deprecated(note = #message)]
+            #[allow(non_upper_case_globals)]
+            const #name: () = ();
+            #name
+        };
     }
-    // check for optional env vars set by users:
-    if let Ok(env) = std::env::var("CARGO_WASMTYPEGEN_FILEOPS") {
-        if env == "false" || env == "0" {
-            should_do = false;
-        } else if env == "true" || env == "1" {
-            should_do = true;
-        }
+}
+
+fn first_span(tokens: &proc_macro2::TokenStream) -> proc_macro2::Span {
+    tokens.clone().into_iter().next().map(|t| t.span()).unwrap_or_else(proc_macro2::Span::call_site)
+}
+
+/// the crate's wasm_type_gen.toml, if it has one. it affects how every module runs (see wasm_type_gen::build_mode)
+fn config_file_path() -> Option<String> {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or(".".into());
+    let path = format!("{manifest_dir}/{}", build_mode::BUILD_MODE_CONFIG_FILE);
+    if PathBuf::from(&path).is_file() {
+        Some(path)
+    } else {
+        None
     }
-    should_do
+}
+
+/// tells rustc that the expanding crate depends on the given files, so that editing
+/// them triggers a rebuild, even though we read them ourselves.
+fn dependency_anchors<I: IntoIterator<Item = String>>(paths: I) -> proc_macro2::TokenStream {
+    let anchors = paths.into_iter().map(|path| quote! {
+        const _: &[u8] = include_bytes!(#path);
+    });
+    quote! { #(#anchors)* }
 }
 
 fn struct_item_to_doc_comment(item: &mut ItemStruct) -> String {
@@ -109,19 +141,35 @@ pub fn wasm_modules(items: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let base_dir = get_wasm_base_dir();
     let mut exports = vec![];
     let mut required_crates = vec![];
+    // every file we read. editing any of them must re-run this macro
+    let mut tracked_files = vec![];
     // load every wasm module and export its types into the file the user is editing
-    for mut path in module_paths {
+    for path in module_paths {
         let original_path = path.clone();
-        if !path.ends_with(".rs") {
-            path.push_str(".rs");
-        }
+        let path = find_module_file(&base_dir, &path);
         let path_name = PathBuf::from(&path);
         let path_name = match path_name.file_stem() {
             Some(f) => f.to_string_lossy().to_string(),
             None => panic!("Unable to create module name for '{}'", path),
         };
         let module_name = format_ident!("{}", path_name);
-        let path = format!("{base_dir}/{path}");
+        if GuestKind::from_path(&path) != GuestKind::Rust {
+            // prebuilt modules have no rust types to export. they still need a type for the
+            // #[wasm_meta] callback to refer to, but they never run the callback.
+            if let Err(e) = load_prebuilt_module(&path) {
+                let s = format!("Failed to load wasm module '{}'. {}", original_path, e);
+                return TokenStream::from(quote! { compile_error!(#s); });
+            }
+            tracked_files.push(path.clone());
+            let doc = format!("`{original_path}` is a prebuilt wasm module. It cannot run the #[wasm_meta] callback, so this type has no fields.");
+            exports.push(quote! {
+                pub(crate) mod #module_name {
+                    #[doc = #doc]
+                    pub struct ExportType {}
+                }
+            });
+            continue;
+        }
         let wasm_code = match load_rs_wasm_module(&path) {
             Ok(c) => c,
             Err(_) => {
@@ -132,6 +180,7 @@ pub fn wasm_modules(items: proc_macro::TokenStream) -> proc_macro::TokenStream {
                 return TokenStream::from(out);
             }
         };
+        tracked_files.push(path.clone());
         let mut parsed_wasm_code = match parse_file(&wasm_code) {
             Ok(p) => p,
             Err(e) => {
@@ -217,8 +266,9 @@ pub fn wasm_modules(items: proc_macro::TokenStream) -> proc_macro::TokenStream {
             }
         });
         if let Some((export, export_str, attrs)) = export_item {
+            // pub(crate) so that #[wasm_meta] can refer to it from anywhere, eg: crate::mods::mymod::MyStruct
             exports.push(quote! {
-                mod #module_name {
+                pub(crate) mod #module_name {
                     #(#attrs)*
                     #[doc = #export_str]
                     #export
@@ -230,10 +280,11 @@ pub fn wasm_modules(items: proc_macro::TokenStream) -> proc_macro::TokenStream {
     if !required_crates.is_empty() {
         let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or(".".into());
         let manifest_file_path = format!("{manifest_dir}/Cargo.toml");
-        let cargo_file_str = match std::fs::read_to_string(manifest_file_path) {
+        let cargo_file_str = match std::fs::read_to_string(&manifest_file_path) {
             Ok(o) => o,
             Err(e) => panic!("One or more of your wasm modules has REQUIRED_CRATES. But failed to find Cargo.toml.\nError:\n{:?}", e),
         };
+        tracked_files.push(manifest_file_path);
         let value = cargo_file_str.parse::<Table>().unwrap();
         let mut dependencies = vec![];
         if let Some(deps) = value.get("dependencies") {
@@ -256,13 +307,34 @@ pub fn wasm_modules(items: proc_macro::TokenStream) -> proc_macro::TokenStream {
         }
     }
 
+    let anchors = dependency_anchors(tracked_files);
     let expanded = quote! {
         #(#exports)*
+        #anchors
     };
 
     TokenStream::from(expanded)
 }
 
+/// find the file for a module name. rust modules (`name.rs`) take priority, otherwise
+/// a prebuilt `name.wat` or `name.wasm` is used. Names that already have an extension are used as is.
+fn find_module_file(base_dir: &str, name: &str) -> String {
+    if name.ends_with(".rs") || GuestKind::from_path(name) != GuestKind::Rust {
+        return format!("{base_dir}/{name}");
+    }
+    let rs_path = format!("{base_dir}/{name}.rs");
+    if PathBuf::from(&rs_path).exists() {
+        return rs_path;
+    }
+    for ext in ["wat", "wasm"] {
+        let path = format!("{base_dir}/{name}.{ext}");
+        if PathBuf::from(&path).exists() {
+            return path;
+        }
+    }
+    rs_path
+}
+
 /// given a module path (a string). open the file and read it to a string.
 /// this string will be compiled to a wasm file.
 fn load_rs_wasm_module(module_path: &str) -> Result<String, String> {
@@ -270,19 +342,443 @@ fn load_rs_wasm_module(module_path: &str) -> Result<String, String> {
         .map_err(|e| format!("Failed to read module path '{module_path}'\n{:?}", e))?)
 }
 
-#[derive(Debug)]
+/// what was passed to wasm_meta. either a closure, eg: `|obj: &mut crate::mods::mymod::MyStruct| { ... }`
+/// or a module path followed by values for its ExportType's fields, eg: `mymod, apples = 2, names = ["a", "b"]`
+struct MetaClosure {
+    /// None for the key/value form. its closure is made once we know the module's ExportType
+    closure: Option<syn::ExprClosure>,
+    /// the module as the user wrote it, eg: `crate::mods::mymod`
+    module_path: syn::Path,
+    /// the key/value form's `field = value`s
+    field_values: MetaFieldValues,
+    /// the module to run, eg: "mymod"
+    module_name: String,
+    /// the path segments before the module name, without crate / self / super. eg: ["mods"]
+    module_parents: Vec<String>,
+    /// eg: `MyStruct`. None for the key/value form, which always uses the module's ExportType
+    type_segment: Option<syn::PathSegment>,
+}
+
+impl MetaClosure {
+    /// the module's file. a module at `a::b::mymod` is looked for in wasm_modules/a/b/ first,
+    /// and then in wasm_modules/ (for when the user declared wasm_modules!() inside of `mod a { mod b { .. } }`)
+    fn module_file(&self, base_dir: &str) -> String {
+        if !self.module_parents.is_empty() {
+            let nested = format!("{}/{}", self.module_parents.join("/"), self.module_name);
+            let path = find_module_file(base_dir, &nested);
+            if PathBuf::from(&path).exists() {
+                return path;
+            }
+        }
+        find_module_file(base_dir, &self.module_name)
+    }
+
+    /// the closure as the user's crate sees it. for the key/value form this is
+    /// `|obj: &mut mymod::MyStruct| { obj.apples = 2; ... }` where MyStruct is the module's ExportType
+    fn closure(&self, export_type: &Ident) -> syn::ExprClosure {
+        if let Some(closure) = &self.closure {
+            return closure.clone();
+        }
+        let module_path = &self.module_path;
+        let assignments = self.field_values.iter().map(|(name, value)| {
+            let value = match value {
+                Some(v) => meta_field_value(v),
+                None => quote_spanned! { name.span()=> true },
+            };
+            quote_spanned! { name.span()=> obj.#name = #value; }
+        });
+        syn::parse_quote!(|obj: &mut #module_path::#export_type| { #(#assignments)* })
+    }
+
+    /// the closure as it appears in the guest, where the module is always at the root: `|obj: &mut mymod::MyStruct|`
+    fn guest_closure(&self, export_type: &Ident) -> syn::ExprClosure {
+        let mut closure = self.closure(export_type);
+        let module_ident = format_ident!("{}", self.module_name);
+        let type_segment = match &self.type_segment {
+            Some(s) => s.clone(),
+            None => export_type.clone().into(),
+        };
+        if let Some(syn::Pat::Type(pat_type)) = closure.inputs.first_mut() {
+            *pat_type.ty = syn::parse_quote!(&mut #module_ident::#type_segment);
+        }
+        closure
+    }
+
+    /// for the key/value form: every field being set must exist on the module's ExportType
+    fn check_field_values(&self, export_fields: &[String]) -> Result<(), syn::Error> {
+        let mut errors = self.field_values.iter()
+            .filter(|(name, _)| !export_fields.contains(&name.to_string()))
+            .map(|(name, _)| {
+                let msg = if export_fields.is_empty() {
+                    format!("wasm module '{}' doesnt take any values, but `{name}` was given", self.module_name)
+                } else {
+                    format!("wasm module '{}' has no field `{name}`. its fields are: {}", self.module_name, export_fields.join(", "))
+                };
+                syn::Error::new(name.span(), msg)
+            });
+        let mut first = match errors.next() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+        errors.for_each(|e| first.combine(e));
+        Err(first)
+    }
+}
+
+/// values in the key/value form are mostly used as is, except that string literals are `.into()`'d
+/// and arrays become vecs, so that `names = ["a", "b"]` works for a `Vec<String>` field
+fn meta_field_value(value: &syn::Expr) -> proc_macro2::TokenStream {
+    match value {
+        syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(s), .. }) => {
+            quote_spanned! { s.span()=> ::std::convert::Into::into(#s) }
+        }
+        syn::Expr::Array(arr) => {
+            let elems = arr.elems.iter().map(meta_field_value);
+            quote_spanned! { arr.bracket_token.span.join()=> vec![#(#elems),*] }
+        }
+        other => other.to_token_stream(),
+    }
+}
+
+/// splits a path like `crate::mods::mymod` into the module name and its parents, ignoring crate / self / super
+fn split_module_path(segments: Vec<&syn::PathSegment>) -> Result<(String, Vec<String>), syn::Error> {
+    if let Some(s) = segments.iter().find(|s| !s.arguments.is_none()) {
+        return Err(syn::Error::new_spanned(s, "wasm module paths cant have generic arguments"));
+    }
+    let mut names: Vec<String> = segments.iter()
+        .map(|s| s.ident.to_string())
+        .skip_while(|s| s == "crate" || s == "self" || s == "super")
+        .collect();
+    let module_name = names.pop().unwrap_or_default();
+    Ok((module_name, names))
+}
+
+fn parse_meta_closure(attr: proc_macro2::TokenStream) -> Result<MetaClosure, syn::Error> {
+    let is_closure = match attr.clone().into_iter().next() {
+        Some(proc_macro2::TokenTree::Punct(p)) => p.as_char() == '|',
+        Some(proc_macro2::TokenTree::Ident(i)) => i == "move" || i == "async" || i == "static",
+        _ => false,
+    };
+    if !is_closure {
+        return parse_meta_field_values(attr);
+    }
+    let closure = syn::parse2::<syn::ExprClosure>(attr.clone()).map_err(|e| {
+        syn::Error::new(e.span(), format!("wasm_meta expects a closure like `|obj: &mut modulename::StructName| {{ ... }}`. {e}"))
+    })?;
+    if closure.inputs.len() != 1 {
+        return Err(syn::Error::new_spanned(
+            &closure.inputs,
+            format!("the wasm_meta closure must take exactly 1 parameter, like `obj: &mut modulename::StructName`. found {}", closure.inputs.len()),
+        ));
+    }
+    let pat_type = match &closure.inputs[0] {
+        syn::Pat::Type(t) => t,
+        other => return Err(syn::Error::new_spanned(
+            other,
+            "the wasm_meta closure's parameter needs a type, like `obj: &mut modulename::StructName`. the type's path tells us which module to run",
+        )),
+    };
+    let reference = match &*pat_type.ty {
+        Type::Reference(r) if r.mutability.is_some() => r,
+        other => return Err(syn::Error::new_spanned(
+            other,
+            "the wasm_meta closure's parameter must be a mutable reference, like `&mut modulename::StructName`",
+        )),
+    };
+    let path = match &*reference.elem {
+        Type::Path(p) if p.qself.is_none() => &p.path,
+        other => return Err(syn::Error::new_spanned(
+            other,
+            "expected a path to a type in a wasm module, like `modulename::StructName`",
+        )),
+    };
+    let mut module_path = path.clone();
+    let type_segment = module_path.segments.pop().map(|s| s.into_value());
+    let (module_name, module_parents) = split_module_path(module_path.segments.iter().collect())?;
+    if module_name.is_empty() {
+        return Err(syn::Error::new_spanned(
+            path,
+            "expected a path to a type in a wasm module, like `modulename::StructName`. the module name is missing",
+        ));
+    }
+    Ok(MetaClosure { closure: Some(closure), module_path, field_values: vec![], module_name, module_parents, type_segment })
+}
+
+/// `field = value`s of the key/value form. a field without a value is set to true
+type MetaFieldValues = Vec<(Ident, Option<syn::Expr>)>;
+
+/// the key/value form: `modulename, field = value, flag, ...`
+fn parse_meta_field_values(attr: proc_macro2::TokenStream) -> Result<MetaClosure, syn::Error> {
+    let parser = |input: syn::parse::ParseStream| -> syn::Result<(syn::Path, MetaFieldValues)> {
+        let path = input.parse::<syn::Path>()?;
+        let mut field_values = vec![];
+        while !input.is_empty() {
+            input.parse::<syn::Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            let name = input.parse::<Ident>()?;
+            let value = if input.peek(syn::Token![=]) {
+                input.parse::<syn::Token![=]>()?;
+                Some(input.parse::<syn::Expr>()?)
+            } else {
+                None
+            };
+            field_values.push((name, value));
+        }
+        Ok((path, field_values))
+    };
+    let (module_path, field_values) = parser.parse2(attr).map_err(|e| {
+        syn::Error::new(e.span(), format!("wasm_meta expects a closure like `|obj: &mut modulename::StructName| {{ ... }}` or a module followed by values, like `modulename, apples = 2, names = [\"a\", \"b\"]`. {e}"))
+    })?;
+    let (module_name, module_parents) = split_module_path(module_path.segments.iter().collect())?;
+    if module_name.is_empty() {
+        return Err(syn::Error::new_spanned(&module_path, "expected the name of a wasm module, like `modulename` or `crate::mods::modulename`"));
+    }
+    Ok(MetaClosure { closure: None, module_path, field_values, module_name, module_parents, type_segment: None })
+}
+
+/// loads a rust wasm module for wasm_meta, returning its source and the name of its ExportType.
+/// Errors if the module cant be read or parsed, or doesnt have a wasm_entrypoint and ExportType
+fn load_meta_module(module_name: &str, module_path: &str) -> Result<(syn::File, Ident), String> {
+    let wasm_module_source = load_rs_wasm_module(module_path)
+        .map_err(|e| format!("Failed to load wasm module '{module_name}'. {e}"))?;
+    let parsed_wasm_code = parse_file(&wasm_module_source)
+        .map_err(|e| format!("Failed to parse wasm module '{module_name}' ({module_path}) as valid rust code. Error:\n{e}"))?;
+    let exported_type = parsed_wasm_code.items.iter().find_map(|item| match item {
+        syn::Item::Type(ty) => if ty.ident.to_string() == "ExportType" {
+            match *ty.ty {
+                Type::Path(ref ty) => {
+                    match ty.path.segments.last() {
+                        Some(seg) => {
+                            if ty.path.segments.len() == 1 {
+                                Some(seg.ident.clone())
+                            } else {
+                                None
+                            }
+                        }
+                        None => None,
+                    }
+                },
+                _ => None,
+            }
+        } else {
+            None
+        },
+        _ => None,
+    });
+    let entrypoint_fn = parsed_wasm_code.items.iter().find(|item| {
+        match item {
+            syn::Item::Fn(fn_item) => {
+                if fn_item.sig.ident.to_string() != "wasm_entrypoint" {
+                    return false
+                }
+                if let syn::ReturnType::Default = fn_item.sig.output {} else {
+                    return false
+                }
+                // enforce 2 args: the first is the LibraryObj
+                // the 2nd is the callback to the user's function.
+                // but too lazy to parse the callback signature right now. we just assume its valid..
+                let input = if fn_item.sig.inputs.len() != 2 {
+                    return false
+                } else {
+                    fn_item.sig.inputs.first().unwrap()
+                };
+                let input = match input {
+                    syn::FnArg::Typed(t) => t,
+                    _ => return false,
+                };
+                let reference = match *input.ty {
+                    Type::Reference(ref r) => r.clone(),
+                    _ => return false,
+                };
+                if reference.mutability.is_none() {
+                    return false
+                }
+                let type_path = match *reference.elem {
+                    Type::Path(p) => p,
+                    _ => return false,
+                };
+                let first = match type_path.path.segments.first() {
+                    Some(s) => s,
+                    None => return false,
+                };
+                if first.ident.to_string() != "LibraryObj" {
+                    return false
+                }
+                true
+            }
+            _ => false,
+        }
+    });
+    if entrypoint_fn.is_none() {
+        return Err(format!("Module '{}' is missing an entrypoint function. Valid modules must contain an entrypoint with the following signature:\npub fn wasm_entrypoint(obj: &mut LibraryObj, cb: fn(&mut SomeStruct));", module_path));
+    }
+    let exported_name = exported_type.ok_or(format!(
+        "Module '{}' is missing a valid ExportType. Expected to find statement like `pub type ExportType = SomeStruct;`", module_path
+    ))?;
+    Ok((parsed_wasm_code, exported_name))
+}
+
+/// wasm_meta can run several modules one after the other, separated by `;`, eg:
+/// `validation, max = 3; derive_helpers; |obj: &mut register_route::Route| { ... }`
+fn split_meta_stages(attr: proc_macro2::TokenStream) -> Vec<proc_macro2::TokenStream> {
+    let mut stages = vec![proc_macro2::TokenStream::new()];
+    for token in attr {
+        match &token {
+            proc_macro2::TokenTree::Punct(p) if p.as_char() == ';' => stages.push(proc_macro2::TokenStream::new()),
+            _ => if let Some(last) = stages.last_mut() {
+                last.extend([token]);
+            }
+        }
+    }
+    stages.retain(|s| !s.is_empty());
+    stages
+}
+
+/// one module that a wasm_meta runs
+struct MetaStage {
+    /// this module's part of the attribute
+    tokens: proc_macro2::TokenStream,
+    meta_closure: MetaClosure,
+    module_path: String,
+    /// the module's source and ExportType. None for prebuilt modules
+    rust_module: Option<(syn::File, Ident)>,
+    /// the closure as the user's crate sees it. its statements are what DiagnosticTarget::Statement points at
+    host_closure: syn::ExprClosure,
+    allowed_files: Vec<AllowedFile>,
+}
+
+/// loads the modules passed to wasm_meta / wasm_finalize!. each module's closure param type tells us which
+/// module to run: |obj: &mut modulename::StructName| { ... }
+/// or, for the key/value form, the first path does: modulename, apples = 2, ...
+/// several modules can be run one after the other by separating them with `;`
+fn load_meta_stages(attr: proc_macro2::TokenStream) -> Result<Vec<MetaStage>, syn::Error> {
+    let base_dir = get_wasm_base_dir();
+    let stage_tokens = split_meta_stages(attr.clone());
+    if stage_tokens.is_empty() {
+        return Err(syn::Error::new(first_span(&attr), "expected a closure like `|obj: &mut modulename::StructName| { ... }` or a module followed by values, like `modulename, apples = 2`"));
+    }
+    let mut stages: Vec<MetaStage> = vec![];
+    for tokens in stage_tokens {
+        let meta_closure = parse_meta_closure(tokens.clone())?;
+        let module_name = meta_closure.module_name.as_str();
+        let module_path = meta_closure.module_file(&base_dir);
+        // prebuilt modules (.wat / .wasm) cant run the user's callback, so there is no rust source
+        // to validate or generate for them. they get passed the LibraryObj as is.
+        let rust_module = if GuestKind::from_path(&module_path) != GuestKind::Rust {
+            None
+        } else {
+            Some(load_meta_module(module_name, &module_path).map_err(|e| syn::Error::new_spanned(&tokens, e))?)
+        };
+        let export_type = match &rust_module {
+            Some((_, exported_name)) => exported_name.clone(),
+            None => format_ident!("ExportType"),
+        };
+        let export_fields: Vec<String> = rust_module.as_ref().and_then(|(parsed_wasm_code, exported_name)| {
+            parsed_wasm_code.items.iter().find_map(|item| match item {
+                syn::Item::Struct(s) if s.ident == *exported_name => Some(s.fields.iter().filter_map(|f| f.ident.as_ref().map(|i| i.to_string())).collect()),
+                _ => None,
+            })
+        }).unwrap_or_default();
+        meta_closure.check_field_values(&export_fields)?;
+        // the guest puts every rust module at its root, so one wasm_meta cant use two different modules with the same name
+        if let Some(other) = stages.iter().find(|s| s.meta_closure.module_name == module_name && s.module_path != module_path) {
+            let msg = format!("wasm module '{module_name}' ({module_path}) cant be used alongside a different module of the same name ({})", other.module_path);
+            return Err(syn::Error::new_spanned(&tokens, msg));
+        }
+        let host_closure = meta_closure.closure(&export_type);
+        let allowed_files = allowed_reads(module_name).map_err(|e| {
+            syn::Error::new_spanned(&tokens, format!("Failed to load the files module '{module_name}' may read. {e}"))
+        })?;
+        stages.push(MetaStage { tokens, meta_closure, module_path, rust_module, host_closure, allowed_files });
+    }
+    Ok(stages)
+}
+
+/// the source compiled for consecutive rust modules of a wasm_meta. wasm_main runs each module's entrypoint
+/// in turn on the same LibraryObj, handing each one only the files it may read, and marking which
+/// module reported each diagnostic
+fn guest_pipeline_source(stages: &[(usize, &MetaStage)]) -> proc_macro2::TokenStream {
+    let mut modules = vec![];
+    let mut module_names: Vec<&str> = vec![];
+    let mut users_fns = vec![];
+    let mut runs = vec![];
+    for (index, stage) in stages {
+        let (parsed_wasm_code, exported_name) = match &stage.rust_module {
+            Some(m) => m,
+            None => continue,
+        };
+        let module_name = stage.meta_closure.module_name.as_str();
+        let module_ident = format_ident!("{module_name}");
+        if !module_names.contains(&module_name) {
+            module_names.push(module_name);
+            modules.push(quote! {
+                mod #module_ident {
+                    // LibraryObj, UserData, and all the types they're made of
+                    #[allow(unused_imports)]
+                    use super::*;
+                    #parsed_wasm_code
+                }
+            });
+        }
+        let users_fn = if *index == 0 { format_ident!("users_fn") } else { format_ident!("users_fn_{index}") };
+        let guest_closure = stage.meta_closure.guest_closure(exported_name);
+        users_fns.push(quote! {
+            pub fn #users_fn(data: &mut #module_ident::#exported_name) {
+                let cb = #guest_closure;
+                cb(data);
+            }
+        });
+        let allowed = stage.allowed_files.iter().map(|f| f.path.as_str());
+        let stage_index = *index as u32;
+        runs.push(quote! {
+            library_obj.input_files = all_files.iter()
+                .filter(|f| [#(#allowed),*].contains(&f.path.as_str()))
+                .map(|f| InputFile { path: f.path.clone(), data: f.data.clone() })
+                .collect();
+            let diagnostics_before = library_obj.diagnostics.len();
+            #module_ident::wasm_entrypoint(library_obj, #users_fn);
+            if !library_obj.compiler_error_message.is_empty() {
+                let message = std::mem::take(&mut library_obj.compiler_error_message);
+                library_obj.diagnostics.push(UserDiagnostic { severity: DiagnosticSeverity::Error, message, target: DiagnosticTarget::Attribute, stage: 0 });
+            }
+            for d in library_obj.diagnostics.iter_mut().skip(diagnostics_before) {
+                d.stage = #stage_index;
+            }
+        });
+    }
+    quote! {
+        pub fn wasm_main(library_obj: &mut LibraryObj) {
+            let all_files = std::mem::take(&mut library_obj.input_files);
+            #({ #runs })*
+            library_obj.input_files = vec![];
+        }
+        #(#modules)*
+        #(#users_fns)*
+    }
+}
+
+#[derive(Debug, Clone)]
 enum GlobalVariable {
     Constant(ItemConst),
     Static(ItemStatic),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum InputType {
     Struct(ItemStruct),
     Function(ItemFn),
     GlobalVar(GlobalVariable),
     Module(ItemMod),
     Match(ExprMatch),
+    Enum(ItemEnum),
+    Impl(ItemImpl),
+    Trait(ItemTrait),
+    TypeAlias(ItemType),
+    Use(ItemUse),
+    /// only `macro_rules! name { ... }`. other macro invocations aren't items we can describe
+    MacroRules(ItemMacro),
 }
 
 impl InputType {
@@ -296,6 +792,44 @@ impl InputType {
                 GlobalVariable::Static(c) => c.ident.to_string(),
             }
             InputType::Match(mi) => mi.expr.to_token_stream().to_string(),
+            InputType::Enum(ei) => ei.ident.to_string(),
+            InputType::Impl(ii) => ii.self_ty.to_token_stream().to_string().replace(' ', ""),
+            InputType::Trait(ti) => ti.ident.to_string(),
+            InputType::TypeAlias(ti) => ti.ident.to_string(),
+            InputType::Use(ui) => ui.tree.to_token_stream().to_string().replace(' ', ""),
+            InputType::MacroRules(mi) => mi.ident.as_ref().map(|i| i.to_string()).unwrap_or_default(),
+        }
+    }
+    pub fn attrs(&self) -> &[syn::Attribute] {
+        match self {
+            InputType::Struct(x) => &x.attrs,
+            InputType::Function(x) => &x.attrs,
+            InputType::GlobalVar(GlobalVariable::Constant(x)) => &x.attrs,
+            InputType::GlobalVar(GlobalVariable::Static(x)) => &x.attrs,
+            InputType::Module(x) => &x.attrs,
+            InputType::Match(x) => &x.attrs,
+            InputType::Enum(x) => &x.attrs,
+            InputType::Impl(x) => &x.attrs,
+            InputType::Trait(x) => &x.attrs,
+            InputType::TypeAlias(x) => &x.attrs,
+            InputType::Use(x) => &x.attrs,
+            InputType::MacroRules(x) => &x.attrs,
+        }
+    }
+    pub fn attrs_mut(&mut self) -> &mut Vec<syn::Attribute> {
+        match self {
+            InputType::Struct(x) => &mut x.attrs,
+            InputType::Function(x) => &mut x.attrs,
+            InputType::GlobalVar(GlobalVariable::Constant(x)) => &mut x.attrs,
+            InputType::GlobalVar(GlobalVariable::Static(x)) => &mut x.attrs,
+            InputType::Module(x) => &mut x.attrs,
+            InputType::Match(x) => &mut x.attrs,
+            InputType::Enum(x) => &mut x.attrs,
+            InputType::Impl(x) => &mut x.attrs,
+            InputType::Trait(x) => &mut x.attrs,
+            InputType::TypeAlias(x) => &mut x.attrs,
+            InputType::Use(x) => &mut x.attrs,
+            InputType::MacroRules(x) => &mut x.attrs,
         }
     }
     /// use_name is only necessary for Match input types. for match statements
@@ -318,6 +852,12 @@ impl InputType {
                 }
             }
             InputType::Module(m) => m.into_token_stream(),
+            InputType::Enum(e) => e.into_token_stream(),
+            InputType::Impl(i) => i.into_token_stream(),
+            InputType::Trait(t) => t.into_token_stream(),
+            InputType::TypeAlias(t) => t.into_token_stream(),
+            InputType::Use(u) => u.into_token_stream(),
+            InputType::MacroRules(m) => m.into_token_stream(),
         }
     }
 }
@@ -348,6 +888,26 @@ fn get_input_type(item: proc_macro2::TokenStream) -> Option<InputType> {
     if let Some(input) = is_mod_input {
         return Some(InputType::Module(input));
     }
+    if let Ok(input) = syn::parse2::<ItemEnum>(item.clone()) {
+        return Some(InputType::Enum(input));
+    }
+    if let Ok(input) = syn::parse2::<ItemImpl>(item.clone()) {
+        return Some(InputType::Impl(input));
+    }
+    if let Ok(input) = syn::parse2::<ItemTrait>(item.clone()) {
+        return Some(InputType::Trait(input));
+    }
+    if let Ok(input) = syn::parse2::<ItemType>(item.clone()) {
+        return Some(InputType::TypeAlias(input));
+    }
+    if let Ok(input) = syn::parse2::<ItemUse>(item.clone()) {
+        return Some(InputType::Use(input));
+    }
+    if let Ok(input) = syn::parse2::<ItemMacro>(item) {
+        if input.ident.is_some() && input.mac.path.is_ident("macro_rules") {
+            return Some(InputType::MacroRules(input));
+        }
+    }
     None
 }
 
@@ -359,6 +919,24 @@ fn rename_ident(id: &mut Ident, name: &str) {
     }
 }
 
+/// parse something a module produced. errors with what the module got wrong
+fn parse_module_output<T: syn::parse::Parse>(what: &str, s: &str) -> Result<T, String> {
+    parse_module_output_with(what, T::parse, s)
+}
+
+fn parse_module_output_with<T, P: Parser<Output = T>>(what: &str, parser: P, s: &str) -> Result<T, String> {
+    parser.parse_str(s).map_err(|e| format!("Module produced an invalid {what}:\n{s}\nError:\n{e}"))
+}
+
+/// code a module wants to emit alongside (or instead of) the user's item. it must be zero or more items
+fn parse_module_items(what: &str, code: &str) -> Result<proc_macro2::TokenStream, String> {
+    parse_module_output::<syn::File>(what, code).map(|f| f.into_token_stream())
+}
+
+fn has_macro_export(attrs: &[syn::Attribute]) -> bool {
+    attrs.iter().any(|a| a.path().is_ident("macro_export"))
+}
+
 fn is_public(vis: &Visibility) -> bool {
     match vis {
         Visibility::Public(_) => true,
@@ -481,17 +1059,55 @@ fn output_shared_files(
 
 #[proc_macro_attribute]
 pub fn wasm_meta(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    expand_wasm_meta(attr.into(), Some(item.into()))
+}
+
+/// runs modules over every item in the crate that has a #[wasm_meta], eg: for route tables or checks across items.
+/// takes the same modules as wasm_meta, eg: `wasm_finalize!(routes; check_names, strict);`
+/// the modules find the items in LibraryObj::registry, and their add_code_before / add_code_after is emitted here.
+/// put it at the end of your lib.rs / main.rs, after your `mod`s, so that it's expanded after the items are.
+/// items in files that havent been expanded yet come from the previous build's manifest in wasmgen/registry/,
+/// and you get a warning when that happens
+#[proc_macro]
+pub fn wasm_finalize(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    expand_wasm_meta(input.into(), None)
+}
+
+/// the types modules see, and how they are read from and written back to the user's item.
+/// they are in a module because a proc macro crate cant have pub items at its root
+mod meta {
+    use super::*;
+
     // this is the data the end user passed to the macro, and we serialize it
     // and pass it to the wasm module that the user specified
-    #[derive(WasmTypeGen, Debug)]
+    #[derive(WasmTypeGen, Debug, Clone)]
     pub enum UserData {
-        /// fields are read only. modifying them in your wasm_module has no effect.
-        Struct { name: String, is_pub: bool, fields: Vec<UserField> },
-        /// inputs are read only. modifying them in your wasm_module has no effect.
-        Function { name: String, is_pub: bool, is_async: bool, inputs: Vec<UserInput>, return_ty: String },
-        Module { name: String, is_pub: bool, },
-        GlobalVariable { name: String, is_pub: bool, },
-        Match { name: String, is_pub: bool },
+        /// fields can be added, removed, retyped, or made pub / private.
+        /// named fields are matched to the original fields by name, tuple fields by position.
+        Struct { name: String, is_pub: bool, attrs: Vec<UserAttribute>, fields: Vec<UserField> },
+        /// inputs can be added, removed or retyped. return_ty (empty for `()`) and is_async can be changed too.
+        /// body is the function's block, including its braces. it can be rewritten
+        Function { name: String, is_pub: bool, attrs: Vec<UserAttribute>, is_async: bool, inputs: Vec<UserInput>, return_ty: String, body: String },
+        Module { name: String, is_pub: bool, attrs: Vec<UserAttribute>, },
+        /// a const or static. ty and expr (what it's set to) can be rewritten
+        GlobalVariable { name: String, is_pub: bool, attrs: Vec<UserAttribute>, ty: String, expr: String },
+        /// for `const _: () = match x { ... };`. name is the expression being matched on.
+        /// arms can be added, removed, reordered, or rewritten.
+        Match { name: String, is_pub: bool, attrs: Vec<UserAttribute>, arms: Vec<UserMatchArm> },
+        /// variants are read only. modifying them in your wasm_module has no effect.
+        Enum { name: String, is_pub: bool, attrs: Vec<UserAttribute>, variants: Vec<UserVariant> },
+        /// name is the type the impl is for, eg: "MyStruct" for `impl Display for MyStruct`.
+        /// trait_name is empty for inherent impls. everything in an impl is read only.
+        Impl { name: String, is_pub: bool, attrs: Vec<UserAttribute>, trait_name: String, items: Vec<UserAssocItem> },
+        /// items are read only. modifying them in your wasm_module has no effect.
+        Trait { name: String, is_pub: bool, attrs: Vec<UserAttribute>, items: Vec<UserAssocItem> },
+        /// ty is the aliased type, eg: "Vec < u8 >" for `type Bytes = Vec<u8>;`. ty is read only.
+        TypeAlias { name: String, is_pub: bool, attrs: Vec<UserAttribute>, ty: String },
+        /// name is the whole use tree, eg: "std::collections::HashMap". only is_pub can be modified.
+        Use { name: String, is_pub: bool, attrs: Vec<UserAttribute> },
+        /// is_pub is whether the macro has #[macro_export]. body is the tokens inside the macro_rules! braces.
+        /// only the name can be modified.
+        MacroRules { name: String, is_pub: bool, attrs: Vec<UserAttribute>, body: String },
         Missing,
     }
     impl Default for UserData {
@@ -500,20 +1116,102 @@ pub fn wasm_meta(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -
         }
     }
 
-    #[derive(WasmTypeGen, Debug)]
+    /// an outer attribute, eg: `#[serde(rename = "a")]` is path "serde" and tokens "(rename = \"a\")".
+    /// doc comments are attributes too: `/// hi` is path "doc" and tokens "= \" hi\""
+    #[derive(WasmTypeGen, Debug, Clone)]
+    pub struct UserAttribute {
+        pub path: String,
+        /// everything after the path. empty for attributes like `#[non_exhaustive]`
+        pub tokens: String,
+    }
+
+    #[derive(WasmTypeGen, Debug, Clone)]
     pub struct UserField {
         /// only relevant for struct fields. not applicable to function params.
         pub is_public: bool,
         pub name: String,
         pub ty: String,
+        pub attrs: Vec<UserAttribute>,
     }
 
-    #[derive(WasmTypeGen, Debug)]
+    #[derive(WasmTypeGen, Debug, Clone)]
     pub struct UserInput {
         /// only relevant for input params to a function. not applicable to struct fields.
+        /// for a self param, name is the whole param, eg: "& mut self" or "self : Box < Self >".
+        /// change name to change the param. to add a self param, set this and put the whole param in name
         pub is_self: bool,
         pub name: String,
         pub ty: String,
+        pub attrs: Vec<UserAttribute>,
+    }
+
+    #[derive(WasmTypeGen, Debug, Clone)]
+    pub struct UserVariant {
+        pub name: String,
+        /// for tuple variants the field names are empty
+        pub fields: Vec<UserField>,
+        /// the explicit discriminant, eg: "2" for `A = 2`. empty if there is none
+        pub discriminant: String,
+    }
+
+    #[derive(WasmTypeGen, Debug, Clone)]
+    pub struct UserMatchArm {
+        /// eg: "Some (x)" or "1 | 2"
+        pub pattern: String,
+        /// the expression after `if`. empty if the arm has no guard
+        pub guard: String,
+        pub body: String,
+    }
+
+    /// an item inside of an impl block or a trait definition
+    #[derive(WasmTypeGen, Debug, Clone)]
+    pub enum UserAssocItem {
+        /// has_body is false for trait methods without a default implementation
+        Method { name: String, is_pub: bool, is_async: bool, inputs: Vec<UserInput>, return_ty: String, has_body: bool },
+        /// ty is the type of the constant
+        Const { name: String, is_pub: bool, ty: String },
+        /// ty is the type it's set to. for trait associated types it's the default, or empty if there is none
+        Type { name: String, is_pub: bool, ty: String },
+        /// anything else, eg: a macro invocation
+        Other { tokens: String },
+    }
+
+    #[derive(WasmTypeGen, Debug)]
+    pub enum DiagnosticSeverity {
+        /// fails the build
+        Error,
+        Warning,
+        /// shown as a warning that starts with "note: "
+        Note,
+    }
+
+    /// what a diagnostic points at. if the target doesnt exist, it points at the wasm_meta attribute instead.
+    /// targets refer to the user's item as they wrote it, not to changes the module made
+    #[derive(WasmTypeGen, Debug)]
+    pub enum DiagnosticTarget {
+        /// the closure passed to wasm_meta
+        Attribute,
+        /// the whole item
+        Item,
+        /// a struct field, by name
+        FieldName(String),
+        /// a struct field, by position. 0 is the first field
+        FieldIndex(u32),
+        /// a function param, by name. self params can be named "self", or written out, eg: "&mut self"
+        ParamName(String),
+        /// a function param, by position. 0 is the first param
+        ParamIndex(u32),
+        /// a statement in the body of the wasm_meta closure. 0 is the first statement
+        Statement(u32),
+    }
+
+    #[derive(WasmTypeGen, Debug)]
+    pub struct UserDiagnostic {
+        pub severity: DiagnosticSeverity,
+        pub message: String,
+        pub target: DiagnosticTarget,
+        /// which of the wasm_meta's modules reported this, counting from 0. set for you
+        pub stage: u32,
     }
 
     #[derive(WasmTypeGen, Debug)]
@@ -531,17 +1229,62 @@ pub fn wasm_meta(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -
         pub after: Option<String>,
     }
 
+    #[derive(WasmTypeGen, Debug)]
+    pub struct InputFile {
+        /// relative to the crate's directory, eg: "queries/users.sql"
+        pub path: String,
+        pub data: Vec<u8>,
+    }
+
+    /// an item that has a wasm_meta, as its modules left it. see wasm_finalize!()
+    #[derive(WasmTypeGen, Debug, Clone)]
+    pub struct RegistryEntry {
+        /// the file the item is in, as rustc was given it. eg: "src/routes.rs"
+        pub file: String,
+        pub name: String,
+        /// the modules its wasm_meta ran, in order
+        pub modules: Vec<String>,
+        pub user_data: UserData,
+    }
+    impl RegistryEntry {
+        /// changing which modules an item's wasm_meta runs doesnt make it a different item
+        pub(super) fn same_item(&self, other: &RegistryEntry) -> bool {
+            self.file == other.file && self.name == other.name
+        }
+    }
+
+    /// what gets saved to wasmgen/registry/
+    #[derive(WasmTypeGen, Debug)]
+    pub struct RegistryManifest {
+        pub entries: Vec<RegistryEntry>,
+    }
+
     #[derive(WasmTypeGen, Debug, Default)]
     pub struct LibraryObj {
+        /// an error on the wasm_meta attribute. prefer compile_error / error, which support multiple errors
         pub compiler_error_message: String,
+        /// errors, warnings and notes to show the user. see error / warning / note
+        pub diagnostics: Vec<UserDiagnostic>,
+        /// items to emit before the user's item. must be valid rust items
+        pub add_code_before: Vec<String>,
+        /// items to emit after the user's item. must be valid rust items
         pub add_code_after: Vec<String>,
+        /// set by replace_item
+        pub item_replacement: Option<String>,
+        /// the user's item as written, eg: "pub fn hello () { ... }". read only
+        pub item_source: String,
         /// crate_name is read only. modifying this has no effect.
         pub crate_name: String,
         pub user_data: UserData,
         pub shared_output_data: Vec<SharedOutputEntry>,
+        /// the files this module is allowed to read. read only. use read_file / read_file_to_string
+        pub input_files: Vec<InputFile>,
+        /// every item in the crate that has a wasm_meta, sorted by file and name.
+        /// only filled in for wasm_finalize!(), where user_data is Missing. read only
+        pub registry: Vec<RegistryEntry>,
     }
 
-    fn to_map_entry(data: Vec<SharedOutputEntry>) -> Vec<MapEntry<MapEntry<(bool, String, Option<String>)>>> {
+    pub(super) fn to_map_entry(data: Vec<SharedOutputEntry>) -> Vec<MapEntry<MapEntry<(bool, String, Option<String>)>>> {
         let mut map_entries: Vec<MapEntry<MapEntry<(bool, String, Option<String>)>>> = vec![];
         for d in data {
             if let Some(m) = map_entries.iter_mut().find(|x| x.key == d.filename) {
@@ -569,186 +1312,819 @@ pub fn wasm_meta(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -
     impl From<&InputType> for UserData {
         fn from(value: &InputType) -> Self {
             let name = value.get_name();
+            let attrs = user_attributes(value.attrs());
             match value {
                 InputType::Struct(x) => {
-                    let mut fields = vec![];
-                    for field in x.fields.iter() {
-                        let usr_field = UserField {
-                            is_public: is_public(&field.vis),
-                            name: field.ident.as_ref().map(|i| i.to_string()).unwrap_or_default(),
-                            ty: field.ty.to_token_stream().to_string(),
-                        };
-                        fields.push(usr_field);
-                    }
-                    Self::Struct { name, is_pub: is_public(&x.vis), fields }
+                    Self::Struct { name, attrs, is_pub: is_public(&x.vis), fields: user_fields(&x.fields) }
                 },
                 InputType::Function(x) => {
-                    let mut inputs = vec![];
-                    for input in x.sig.inputs.iter() {
-                        let usr_field = UserInput {
-                            is_self: match input {
-                                syn::FnArg::Receiver(_) => true,
-                                syn::FnArg::Typed(_) => false,
-                            },
-                            name: match input {
-                                syn::FnArg::Receiver(_) => "&self".into(),
-                                syn::FnArg::Typed(ty) => ty.pat.to_token_stream().to_string(),
-                            },
-                            ty: match input {
-                                syn::FnArg::Receiver(_) => "".into(),
-                                syn::FnArg::Typed(ty) => ty.ty.to_token_stream().to_string(),
-                            }
-                        };
-                        inputs.push(usr_field);
+                    Self::Function {
+                        name,
+                        attrs,
+                        is_pub: is_public(&x.vis),
+                        inputs: user_inputs(&x.sig),
+                        is_async: x.sig.asyncness.is_some(),
+                        return_ty: return_type(&x.sig),
+                        body: x.block.to_token_stream().to_string(),
                     }
-                    let return_ty = match &x.sig.output {
-                        syn::ReturnType::Default => "".into(),
-                        syn::ReturnType::Type(_, b) => b.to_token_stream().to_string(),
-                    };
-                    Self::Function { name, is_pub: is_public(&x.vis), inputs, is_async: x.sig.asyncness.is_some(), return_ty }
                 }
                 InputType::GlobalVar(GlobalVariable::Constant(x)) => {
-                    Self::GlobalVariable { name, is_pub: is_public(&x.vis) }
+                    Self::GlobalVariable {
+                        name,
+                        attrs,
+                        is_pub: is_public(&x.vis),
+                        ty: x.ty.to_token_stream().to_string(),
+                        expr: x.expr.to_token_stream().to_string(),
+                    }
                 }
                 InputType::GlobalVar(GlobalVariable::Static(x)) => {
-                    Self::GlobalVariable { name, is_pub: is_public(&x.vis) }
+                    Self::GlobalVariable {
+                        name,
+                        attrs,
+                        is_pub: is_public(&x.vis),
+                        ty: x.ty.to_token_stream().to_string(),
+                        expr: x.expr.to_token_stream().to_string(),
+                    }
                 }
                 InputType::Module(x) => {
-                    Self::Module { name, is_pub: is_public(&x.vis) }
+                    Self::Module { name, attrs, is_pub: is_public(&x.vis) }
+                }
+                InputType::Match(x) => {
+                    Self::Match { name, attrs, is_pub: false, arms: x.arms.iter().map(user_match_arm).collect() }
                 }
-                InputType::Match(_x) => {
-                    // TODO: implement iterating match arms
-                    Self::Match { name, is_pub: false }
+                InputType::Enum(x) => {
+                    let variants = x.variants.iter().map(|v| UserVariant {
+                        name: v.ident.to_string(),
+                        fields: user_fields(&v.fields),
+                        discriminant: v.discriminant.as_ref().map(|(_, e)| e.to_token_stream().to_string()).unwrap_or_default(),
+                    }).collect();
+                    Self::Enum { name, attrs, is_pub: is_public(&x.vis), variants }
+                }
+                InputType::Impl(x) => {
+                    let trait_name = x.trait_.as_ref().map(|(_, path, _)| path.to_token_stream().to_string()).unwrap_or_default();
+                    let items = x.items.iter().map(|item| match item {
+                        syn::ImplItem::Fn(f) => UserAssocItem::Method {
+                            name: f.sig.ident.to_string(),
+                            is_pub: is_public(&f.vis),
+                            is_async: f.sig.asyncness.is_some(),
+                            inputs: user_inputs(&f.sig),
+                            return_ty: return_type(&f.sig),
+                            has_body: true,
+                        },
+                        syn::ImplItem::Const(c) => UserAssocItem::Const {
+                            name: c.ident.to_string(), is_pub: is_public(&c.vis), ty: c.ty.to_token_stream().to_string(),
+                        },
+                        syn::ImplItem::Type(t) => UserAssocItem::Type {
+                            name: t.ident.to_string(), is_pub: is_public(&t.vis), ty: t.ty.to_token_stream().to_string(),
+                        },
+                        other => UserAssocItem::Other { tokens: other.to_token_stream().to_string() },
+                    }).collect();
+                    Self::Impl { name, attrs, is_pub: false, trait_name, items }
+                }
+                InputType::Trait(x) => {
+                    // trait items dont have their own visibility, they're as visible as the trait
+                    let is_pub = is_public(&x.vis);
+                    let items = x.items.iter().map(|item| match item {
+                        syn::TraitItem::Fn(f) => UserAssocItem::Method {
+                            name: f.sig.ident.to_string(),
+                            is_pub,
+                            is_async: f.sig.asyncness.is_some(),
+                            inputs: user_inputs(&f.sig),
+                            return_ty: return_type(&f.sig),
+                            has_body: f.default.is_some(),
+                        },
+                        syn::TraitItem::Const(c) => UserAssocItem::Const {
+                            name: c.ident.to_string(), is_pub, ty: c.ty.to_token_stream().to_string(),
+                        },
+                        syn::TraitItem::Type(t) => UserAssocItem::Type {
+                            name: t.ident.to_string(),
+                            is_pub,
+                            ty: t.default.as_ref().map(|(_, ty)| ty.to_token_stream().to_string()).unwrap_or_default(),
+                        },
+                        other => UserAssocItem::Other { tokens: other.to_token_stream().to_string() },
+                    }).collect();
+                    Self::Trait { name, attrs, is_pub, items }
+                }
+                InputType::TypeAlias(x) => {
+                    Self::TypeAlias { name, attrs, is_pub: is_public(&x.vis), ty: x.ty.to_token_stream().to_string() }
+                }
+                InputType::Use(x) => {
+                    Self::Use { name, attrs, is_pub: is_public(&x.vis) }
+                }
+                InputType::MacroRules(x) => {
+                    Self::MacroRules { name, attrs, is_pub: has_macro_export(&x.attrs), body: x.mac.tokens.to_string() }
                 }
             }
         }
     }
 
-    impl InputType {
-        pub fn apply_library_obj_changes(&mut self, lib_obj: LibraryObj) {
-            let user_data = lib_obj.user_data;
-            match (self, user_data) {
-                (InputType::Struct(x), UserData::Struct { name, is_pub, .. }) => {
-                    rename_ident(&mut x.ident, &name);
+    pub(super) fn user_attributes(attrs: &[syn::Attribute]) -> Vec<UserAttribute> {
+        attrs.iter().filter(|a| matches!(a.style, syn::AttrStyle::Outer)).map(|a| {
+            let tokens = match &a.meta {
+                syn::Meta::Path(_) => "".to_string(),
+                syn::Meta::List(l) => {
+                    let inner = l.tokens.to_string();
+                    match l.delimiter {
+                        syn::MacroDelimiter::Paren(_) => format!("({inner})"),
+                        syn::MacroDelimiter::Brace(_) => format!("{{{inner}}}"),
+                        syn::MacroDelimiter::Bracket(_) => format!("[{inner}]"),
+                    }
+                }
+                syn::Meta::NameValue(nv) => format!("= {}", nv.value.to_token_stream()),
+            };
+            UserAttribute { path: a.path().to_token_stream().to_string().replace(' ', ""), tokens }
+        }).collect()
+    }
+
+    /// replace the outer attributes with the ones the module wants. inner attributes (`#![...]`) are kept as is
+    pub(super) fn apply_attribute_changes(attrs: &mut Vec<syn::Attribute>, new_attrs: Vec<UserAttribute>) -> Result<(), String> {
+        let (inner, outer): (Vec<_>, Vec<_>) = std::mem::take(attrs).into_iter()
+            .partition(|a| matches!(a.style, syn::AttrStyle::Inner(_)));
+        let mut original: Vec<Option<syn::Attribute>> = outer.into_iter().map(Some).collect();
+        for attr in new_attrs {
+            // attributes the module didnt touch keep their spans
+            let unchanged = original.iter_mut().find(|o| match o {
+                Some(o) => {
+                    let o = &user_attributes(std::slice::from_ref(o))[0];
+                    o.path == attr.path && o.tokens == attr.tokens
+                }
+                None => false,
+            }).and_then(Option::take);
+            match unchanged {
+                Some(a) => attrs.push(a),
+                None => attrs.extend(parse_module_output_with(
+                    "attribute", syn::Attribute::parse_outer, &format!("#[{}{}]", attr.path, attr.tokens)
+                )?),
+            }
+        }
+        attrs.extend(inner);
+        Ok(())
+    }
+
+    /// the tokens of the user's item (or of the wasm_meta closure) that a diagnostic points at
+    pub(super) fn diagnostic_target(input: Option<&InputType>, attr: &proc_macro2::TokenStream, closure: &syn::ExprClosure, target: &DiagnosticTarget) -> Option<proc_macro2::TokenStream> {
+        let fields = match input {
+            Some(InputType::Struct(x)) => Some(&x.fields),
+            _ => None,
+        };
+        let params = match input {
+            Some(InputType::Function(x)) => Some(&x.sig.inputs),
+            _ => None,
+        };
+        match target {
+            DiagnosticTarget::Attribute => Some(attr.clone()),
+            DiagnosticTarget::Item => Some(input?.clone().back_to_stream("_")),
+            DiagnosticTarget::FieldName(name) => fields?.iter()
+                .find(|f| f.ident.as_ref().map(|i| i == name).unwrap_or(false))
+                .map(|f| f.to_token_stream()),
+            DiagnosticTarget::FieldIndex(i) => fields?.iter().nth(*i as usize).map(|f| f.to_token_stream()),
+            DiagnosticTarget::ParamName(name) => params?.iter().find(|p| match p {
+                syn::FnArg::Receiver(r) => name == "self" || name.replace(' ', "") == receiver_name(r).replace(' ', ""),
+                syn::FnArg::Typed(t) => t.pat.to_token_stream().to_string() == *name,
+            }).map(|p| p.to_token_stream()),
+            DiagnosticTarget::ParamIndex(i) => params?.iter().nth(*i as usize).map(|p| p.to_token_stream()),
+            DiagnosticTarget::Statement(i) => {
+                match &*closure.body {
+                    syn::Expr::Block(b) => b.block.stmts.get(*i as usize).map(|s| s.to_token_stream()),
+                    body if *i == 0 => Some(body.to_token_stream()),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    pub(super) fn user_match_arm(arm: &syn::Arm) -> UserMatchArm {
+        UserMatchArm {
+            pattern: arm.pat.to_token_stream().to_string(),
+            guard: arm.guard.as_ref().map(|(_, g)| g.to_token_stream().to_string()).unwrap_or_default(),
+            body: arm.body.to_token_stream().to_string(),
+        }
+    }
+
+    pub(super) fn user_fields(fields: &syn::Fields) -> Vec<UserField> {
+        fields.iter().map(|field| UserField {
+            is_public: is_public(&field.vis),
+            name: field.ident.as_ref().map(|i| i.to_string()).unwrap_or_default(),
+            ty: field.ty.to_token_stream().to_string(),
+            attrs: user_attributes(&field.attrs),
+        }).collect()
+    }
+
+    /// the whole receiver as the user wrote it, without its attributes. eg: "& mut self", "self : Box < Self >"
+    pub(super) fn receiver_name(r: &syn::Receiver) -> String {
+        let mut r = r.clone();
+        r.attrs.clear();
+        r.to_token_stream().to_string()
+    }
+
+    pub(super) fn user_inputs(sig: &syn::Signature) -> Vec<UserInput> {
+        sig.inputs.iter().map(|input| match input {
+            syn::FnArg::Receiver(r) => UserInput { is_self: true, name: receiver_name(r), ty: "".into(), attrs: user_attributes(&r.attrs) },
+            syn::FnArg::Typed(ty) => UserInput {
+                is_self: false,
+                name: ty.pat.to_token_stream().to_string(),
+                ty: ty.ty.to_token_stream().to_string(),
+                attrs: user_attributes(&ty.attrs),
+            },
+        }).collect()
+    }
+
+    pub(super) fn return_type(sig: &syn::Signature) -> String {
+        match &sig.output {
+            syn::ReturnType::Default => "".into(),
+            syn::ReturnType::Type(_, b) => b.to_token_stream().to_string(),
+        }
+    }
+
+    pub(super) fn apply_field_changes(x: &mut ItemStruct, fields: Vec<UserField>) -> Result<(), String> {
+        let was_unit = matches!(x.fields, syn::Fields::Unit);
+        let is_named = match &x.fields {
+            syn::Fields::Named(_) => true,
+            syn::Fields::Unnamed(_) => false,
+            // a unit struct becomes a tuple struct only if the module added unnamed fields
+            syn::Fields::Unit => fields.iter().any(|f| !f.name.is_empty()),
+        };
+        let original_fields = std::mem::replace(&mut x.fields, syn::Fields::Unit);
+        let mut original: Vec<Option<syn::Field>> = original_fields.into_iter().map(Some).collect();
+        let mut out: Punctuated<syn::Field, syn::Token![,]> = Punctuated::new();
+        for (i, field) in fields.into_iter().enumerate() {
+            let existing = if is_named {
+                original.iter_mut().find(|o| match o {
+                    Some(o) => o.ident.as_ref().map(|id| *id == field.name).unwrap_or(false),
+                    None => false,
+                }).and_then(Option::take)
+            } else {
+                original.get_mut(i).and_then(Option::take)
+            };
+            // fields the module kept keep their attributes, and the spans of their types if unchanged
+            let mut new_field = match existing {
+                Some(f) => f,
+                None if is_named => parse_module_output_with("field", syn::Field::parse_named, &format!("{}: {}", field.name, field.ty))?,
+                None => parse_module_output_with("field", syn::Field::parse_unnamed, &field.ty)?,
+            };
+            if new_field.ty.to_token_stream().to_string() != field.ty {
+                new_field.ty = parse_module_output("field type", &field.ty)?;
+            }
+            set_visibility(&mut new_field.vis, field.is_public);
+            apply_attribute_changes(&mut new_field.attrs, field.attrs)?;
+            out.push(new_field);
+        }
+        if was_unit && out.is_empty() {
+            x.fields = syn::Fields::Unit;
+        } else if is_named {
+            x.fields = syn::Fields::Named(syn::FieldsNamed { brace_token: Default::default(), named: out });
+            x.semi_token = None;
+        } else {
+            x.fields = syn::Fields::Unnamed(syn::FieldsUnnamed { paren_token: Default::default(), unnamed: out });
+            x.semi_token = Some(Default::default());
+        }
+        Ok(())
+    }
+
+    pub(super) fn apply_signature_changes(sig: &mut syn::Signature, inputs: Vec<UserInput>, is_async: bool, return_ty: &str) -> Result<(), String> {
+        let mut original: Vec<Option<syn::FnArg>> = std::mem::take(&mut sig.inputs).into_iter().map(Some).collect();
+        for input in inputs {
+            let existing = original.iter_mut().find(|o| match o {
+                Some(syn::FnArg::Receiver(_)) => input.is_self,
+                Some(syn::FnArg::Typed(t)) => !input.is_self && t.pat.to_token_stream().to_string() == input.name,
+                None => false,
+            }).and_then(Option::take);
+            let mut arg = match existing {
+                Some(syn::FnArg::Typed(mut t)) => {
+                    if t.ty.to_token_stream().to_string() != input.ty {
+                        *t.ty = parse_module_output("parameter type", &input.ty)?;
+                    }
+                    syn::FnArg::Typed(t)
+                }
+                Some(syn::FnArg::Receiver(mut r)) => {
+                    if receiver_name(&r) != input.name {
+                        let attrs = std::mem::take(&mut r.attrs);
+                        r = parse_module_output("self parameter", &input.name)?;
+                        r.attrs = attrs;
+                    }
+                    syn::FnArg::Receiver(r)
+                }
+                None if input.is_self => parse_module_output("self parameter", &input.name)?,
+                None => parse_module_output("parameter", &format!("{}: {}", input.name, input.ty))?,
+            };
+            match &mut arg {
+                syn::FnArg::Receiver(r) => apply_attribute_changes(&mut r.attrs, input.attrs)?,
+                syn::FnArg::Typed(t) => apply_attribute_changes(&mut t.attrs, input.attrs)?,
+            }
+            sig.inputs.push(arg);
+        }
+        sig.asyncness = if is_async { sig.asyncness.or(Some(Default::default())) } else { None };
+        if return_type(sig) != return_ty {
+            sig.output = if return_ty.is_empty() {
+                syn::ReturnType::Default
+            } else {
+                syn::ReturnType::Type(Default::default(), Box::new(parse_module_output("return type", return_ty)?))
+            };
+        }
+        Ok(())
+    }
+
+    impl InputType {
+        /// Errors if the module changed something into invalid rust, eg: a field type that doesnt parse
+        pub fn apply_library_obj_changes(&mut self, lib_obj: LibraryObj) -> Result<(), String> {
+            let mut user_data = lib_obj.user_data;
+            if !matches!(user_data, UserData::Missing) {
+                apply_attribute_changes(self.attrs_mut(), std::mem::take(user_data.get_attributes()))?;
+            }
+            match (self, user_data) {
+                (InputType::Struct(x), UserData::Struct { name, is_pub, fields, .. }) => {
+                    rename_ident(&mut x.ident, &name);
                     set_visibility(&mut x.vis, is_pub);
+                    apply_field_changes(x, fields)?;
                 }
-                (InputType::Function(x), UserData::Function { name, is_pub, .. }) => {
+                (InputType::Function(x), UserData::Function { name, is_pub, is_async, inputs, return_ty, body, .. }) => {
                     rename_ident(&mut x.sig.ident, &name);
                     set_visibility(&mut x.vis, is_pub);
+                    apply_signature_changes(&mut x.sig, inputs, is_async, &return_ty)?;
+                    if x.block.to_token_stream().to_string() != body {
+                        *x.block = parse_module_output("function body", &body)?;
+                    }
                 }
-                (InputType::GlobalVar(GlobalVariable::Constant(x)), UserData::GlobalVariable { name, is_pub, .. }) => {
+                (InputType::GlobalVar(GlobalVariable::Constant(x)), UserData::GlobalVariable { name, is_pub, ty, expr, .. }) => {
                     rename_ident(&mut x.ident, &name);
                     set_visibility(&mut x.vis, is_pub);
+                    if x.ty.to_token_stream().to_string() != ty {
+                        *x.ty = parse_module_output("type", &ty)?;
+                    }
+                    if x.expr.to_token_stream().to_string() != expr {
+                        *x.expr = parse_module_output("expression", &expr)?;
+                    }
                 }
-                (InputType::GlobalVar(GlobalVariable::Static(x)), UserData::GlobalVariable { name, is_pub, .. }) => {
+                (InputType::GlobalVar(GlobalVariable::Static(x)), UserData::GlobalVariable { name, is_pub, ty, expr, .. }) => {
                     rename_ident(&mut x.ident, &name);
                     set_visibility(&mut x.vis, is_pub);
+                    if x.ty.to_token_stream().to_string() != ty {
+                        *x.ty = parse_module_output("type", &ty)?;
+                    }
+                    if x.expr.to_token_stream().to_string() != expr {
+                        *x.expr = parse_module_output("expression", &expr)?;
+                    }
                 }
                 (InputType::Module(x), UserData::Module { name, is_pub, .. }) => {
                     rename_ident(&mut x.ident, &name);
                     set_visibility(&mut x.vis, is_pub);
                 }
+                (InputType::Enum(x), UserData::Enum { name, is_pub, .. }) => {
+                    rename_ident(&mut x.ident, &name);
+                    set_visibility(&mut x.vis, is_pub);
+                }
+                (InputType::Trait(x), UserData::Trait { name, is_pub, .. }) => {
+                    rename_ident(&mut x.ident, &name);
+                    set_visibility(&mut x.vis, is_pub);
+                }
+                (InputType::TypeAlias(x), UserData::TypeAlias { name, is_pub, .. }) => {
+                    rename_ident(&mut x.ident, &name);
+                    set_visibility(&mut x.vis, is_pub);
+                }
+                (InputType::Use(x), UserData::Use { is_pub, .. }) => {
+                    set_visibility(&mut x.vis, is_pub);
+                }
+                (InputType::Match(x), UserData::Match { arms, .. }) => {
+                    // arms the module didnt touch keep their original tokens, and therefore their spans
+                    let mut original: Vec<Option<syn::Arm>> = std::mem::take(&mut x.arms).into_iter().map(Some).collect();
+                    for arm in arms {
+                        let unchanged = original.iter_mut().find(|o| match o {
+                            Some(o) => {
+                                let o = user_match_arm(o);
+                                o.pattern == arm.pattern && o.guard == arm.guard && o.body == arm.body
+                            }
+                            None => false,
+                        }).and_then(|o| o.take());
+                        let new_arm = match unchanged {
+                            Some(a) => a,
+                            None => {
+                                let guard = if arm.guard.is_empty() { "".to_string() } else { format!(" if {}", arm.guard) };
+                                parse_module_output("match arm", &format!("{}{guard} => {},", arm.pattern, arm.body))?
+                            }
+                        };
+                        x.arms.push(new_arm);
+                    }
+                }
+                (InputType::MacroRules(x), UserData::MacroRules { name, .. }) => {
+                    if let Some(id) = x.ident.as_mut() {
+                        rename_ident(id, &name);
+                    }
+                }
                 _ => {}
             }
+            Ok(())
         }
     }
 
-    #[output_and_stringify_basic(library_obj_extra_impl)]
-    impl LibraryObj {
-        #[allow(dead_code)]
-        fn compile_error(&mut self, err_msg: &str) {
-            self.compiler_error_message = err_msg.into();
-        }
-        /// given a file name (no paths. the file will appear in ./wasmgen/{filename})
-        /// and a label, and a line (string) append to the file. create the file if it doesnt exist.
-        /// the label is used to sort lines between your wasm module and other invocations.
-        /// the label is also embedded to the file. so if you are outputing to a .sh file, for example,
-        /// your label should start with '#'. The labels are sorted alphabetically.
-        /// Example:
-        /// ```rust,ignore
-        /// # wasm module 1 does:
-        /// append_to_file("hello.txt", "b", "line1");
-        /// # wasm module 2 does:
-        /// append_to_file("hello.txt", "b", "line2");
-        /// # wasm module 3 does:
-        /// append_to_file("hello.txt", "a", "line3");
-        /// # wasm moudle 4 does:
-        /// append_to_file("hello.txt", "a", "line4");
-        /// 
-        /// # the output:
-        /// a
-        /// line3
-        /// line4
-        /// b
-        /// line1
-        /// line2
-        /// ```
-        #[allow(dead_code)]
-        fn append_to_file(&mut self, name: &str, label: &str, line: String) {
-            self.shared_output_data.push(SharedOutputEntry { label: label.into(), line, filename: name.into(), unique: false, after: None });
-        }
-
-        /// same as append_to_file, but the line will be unique within the label
-        #[allow(dead_code)]
-        fn append_to_file_unique(&mut self, name: &str, label: &str, line: String) {
-            self.shared_output_data.push(SharedOutputEntry { label: label.into(), line, filename: name.into(), unique: true, after: None });
-        }
-
-        /// like append_to_file, but given a search string, find that search string in that label
-        /// and then append the `after` portion immediately after the search string. Example:
-        /// ```rust,ignore
-        /// // "hello " doesnt exist yet, so the whole "hello , and also my friend Tim!" gets added
-        /// append_to_line("hello.txt", "a", "hello ", ", and also my friend Tim!");
-        /// append_to_line("hello.txt", "a", "hello ", "world"); 
-        /// 
-        /// # the output:
-        /// hello world, and also my friend Tim!
-        /// ```
-        #[allow(dead_code)]
-        fn append_to_line(&mut self, name: &str, label: &str, search_str: String, after: String) {
-            self.shared_output_data.push(SharedOutputEntry { label: label.into(), line: search_str, filename: name.into(), unique: false, after: Some(after) });
-        }
-    }
-    #[output_and_stringify_basic(user_data_extra_impl)]
-    impl UserData {
-        /// Get the name of the user's data that they put this macro over.
-        /// for example `struct MyStruct { ... }` returns "MyStruct"
-        /// 
-        /// or `pub fn helloworld(a: u32) { ... }` returns "helloworld"
-        /// Can rename the user's data type by modifying this string directly
-        #[allow(dead_code)]
-        fn get_name(&mut self) -> &mut String {
-            match self {
-                UserData::Struct { name, .. } => name,
-                UserData::Function { name, .. } => name,
-                UserData::Module { name, .. } => name,
-                UserData::GlobalVariable { name, .. } => name,
-                UserData::Match { name, .. } => name,
-                UserData::Missing => unreachable!(),
-            }
-        }
-        /// Returns a bool of whether or not the user marked their data as pub or not.
-        /// Can set this value to true or false depending on your module's purpose.
-        #[allow(dead_code)]
-        fn get_public_vis(&mut self) -> &mut bool {
-            match self {
-                UserData::Struct { is_pub, .. } => is_pub,
-                UserData::Function { is_pub, .. } => is_pub,
-                UserData::Module { is_pub, .. } => is_pub,
-                UserData::GlobalVariable { is_pub, .. } => is_pub,
-                UserData::Match { is_pub, .. } => is_pub,
-                UserData::Missing => unreachable!(),
+
+    /// the runtime modules run on. by default its wasmi, which keeps this crate light to build. its output is
+    /// reproducible, except for the bits of NaNs, which it cant canonicalize (see wasm_type_gen::runtime).
+    /// LibraryObj has no floats, so a module would have to format a NaN's bits into code on purpose.
+    /// with the `wasmtime` feature, modules run on wasmtime's deterministic config instead, which canonicalizes them
+    #[cfg(feature = "wasmtime")]
+    pub(super) fn module_runtime() -> runtime::WasmtimeRuntime {
+        runtime::WasmtimeRuntime::default()
+    }
+
+    #[cfg(not(feature = "wasmtime"))]
+    pub(super) fn module_runtime() -> runtime::WasmiRuntime {
+        runtime::WasmiRuntime::default()
+    }
+
+    pub(super) fn get_wasm_output(
+        out_name_hash: &str,
+        wasm_source: &str,
+        add_to_source: Option<String>,
+        data_to_pass: &LibraryObj,
+    ) -> Result<(Option<LibraryObj>, Option<String>), String> {
+        let build = compile_string_to_wasm(out_name_hash, wasm_source, add_to_source, None)
+            .map_err(|e| format!("failed to compile:\n{e}"))?;
+        let stale_error = build.error().map(|e| e.to_string());
+        let wasm_file = std::fs::read(build.path()).map_err(|e| format!("failed to read its wasm binary {:?}. {e}", build.path()))?;
+        let out = run_wasm_with(&module_runtime(), &wasm_file, data_to_pass.to_binary_slice())
+            .map_err(|e| format!("failed to run:\n{e}"))?;
+        Ok((LibraryObj::from_binary_slice(out), stale_error))
+    }
+
+    /// runs the modules over lib_obj. also returns warnings for modules that failed to compile.
+    /// consecutive rust modules are compiled together and run in one go. prebuilt ones run on their own.
+    /// either way, each module gets the LibraryObj as the one before it left it.
+    /// if a module cant be built or run at all, thats an error on its part of the attribute
+    pub(super) fn run_stages(stages: &[MetaStage], mut lib_obj: LibraryObj, item_name: &str, add_to_code: &str) -> Result<(LibraryObj, Vec<proc_macro2::TokenStream>), syn::Error> {
+        let mut segments: Vec<Vec<usize>> = vec![];
+        for (i, stage) in stages.iter().enumerate() {
+            match segments.last_mut() {
+                Some(last) if stage.rust_module.is_some() && stages[last[0]].rust_module.is_some() => last.push(i),
+                _ => segments.push(vec![i]),
+            }
+        }
+        // every segment gets the registry. modules cant change it, so what they pass back is dropped
+        let registry = std::mem::take(&mut lib_obj.registry);
+        let mut stale_warnings = vec![];
+        for (segment_index, segment) in segments.iter().enumerate() {
+            // every file that the segment's modules may read. the guest hands each module only its own
+            let first = &stages[segment[0]];
+            let names = segment.iter().map(|i| format!("'{}'", stages[*i].meta_closure.module_name)).collect::<Vec<_>>().join(", ");
+            let stage_error = |e: String| syn::Error::new_spanned(&first.tokens, format!("wasm module {names} {e}"));
+            let mut input_files: Vec<InputFile> = vec![];
+            for file in segment.iter().flat_map(|i| stages[*i].allowed_files.iter()) {
+                if input_files.iter().any(|f| f.path == file.path) {
+                    continue;
+                }
+                let data = std::fs::read(&file.full_path)
+                    .map_err(|e| stage_error(format!("failed to read {:?}. {e}", file.full_path)))?;
+                input_files.push(InputFile { path: file.path.clone(), data });
+            }
+            lib_obj.input_files = input_files;
+            lib_obj.registry = registry.clone();
+            let diagnostics_before = lib_obj.diagnostics.len();
+            let (out, stale_error) = if first.rust_module.is_some() {
+                let segment_stages: Vec<(usize, &MetaStage)> = segment.iter().map(|i| (*i, &stages[*i])).collect();
+                let final_wasm_source = guest_pipeline_source(&segment_stages);
+                // the first compile keeps using the item's name, so it finds the builds from before pipelines existed
+                let out_name = if segment_index == 0 { item_name.to_string() } else { format!("{item_name}_{segment_index}") };
+                // TODO: instead of hashing the whole item input, use the item name, for eg function name or struct name.
+                // this way it wont change as often
+                // let item_hash = adler32::adler32(item_str.as_bytes()).unwrap_or(0);
+                get_wasm_output(&out_name, &final_wasm_source.to_string(), Some(add_to_code.to_string()), &lib_obj).map_err(stage_error)?
+            } else {
+                let wasm_file = load_prebuilt_module(&first.module_path).map_err(|e| stage_error(format!("failed to load:\n{e}")))?;
+                let out = run_wasm_with(&module_runtime(), &wasm_file, lib_obj.to_binary_slice())
+                    .map_err(|e| stage_error(format!("failed to run:\n{e}")))?;
+                (LibraryObj::from_binary_slice(out), None)
+            };
+            lib_obj = out.unwrap_or_default();
+            lib_obj.input_files = vec![];
+            lib_obj.registry = vec![];
+            // the guest marks which module reported each diagnostic. prebuilt modules dont know about that,
+            // and may set compiler_error_message directly, which becomes an error on their part of the attribute
+            if first.rust_module.is_none() {
+                if !lib_obj.compiler_error_message.is_empty() {
+                    let message = std::mem::take(&mut lib_obj.compiler_error_message);
+                    lib_obj.diagnostics.push(UserDiagnostic { severity: DiagnosticSeverity::Error, message, target: DiagnosticTarget::Attribute, stage: 0 });
+                }
+                for d in lib_obj.diagnostics.iter_mut().skip(diagnostics_before) {
+                    d.stage = segment[0] as u32;
+                }
+            }
+            // the module failed to compile, so we ran the last version that did. dont fail the build
+            // over it, but make sure the user knows their latest changes arent being used.
+            if let Some(e) = stale_error {
+                let msg = format!("wasm module {names} failed to compile, using its previous build instead:\n{e}");
+                stale_warnings.push(emit_warning(&msg, first_span(&first.tokens)));
             }
         }
+        Ok((lib_obj, stale_warnings))
     }
 
+    /// the modules' diagnostics as errors / warnings. input is None for wasm_finalize!, which has no item to point at
+    pub(super) fn stage_diagnostics(diagnostics: Vec<UserDiagnostic>, stages: &[MetaStage], input: Option<&InputType>) -> Vec<proc_macro2::TokenStream> {
+        diagnostics.into_iter().map(|d| {
+            let stage = stages.get(d.stage as usize).unwrap_or(&stages[0]);
+            let target = diagnostic_target(input, &stage.tokens, &stage.host_closure, &d.target).unwrap_or_else(|| stage.tokens.clone());
+            match d.severity {
+                DiagnosticSeverity::Error => syn::Error::new_spanned(target, &d.message).to_compile_error(),
+                DiagnosticSeverity::Warning => emit_warning(&d.message, first_span(&target)),
+                DiagnosticSeverity::Note => emit_warning(&format!("note: {}", d.message), first_span(&target)),
+            }
+        }).collect()
+    }
+
+    /// the modules' output depends on their source, the config, and the files they read,
+    /// so make sure cargo re-expands us when any of them change.
+    /// cargo cant watch directories, so a file added to an allow-listed directory doesnt re-expand us.
+    /// see wasm_type_gen::file_access
+    pub(super) fn stage_anchors(stages: &[MetaStage]) -> proc_macro2::TokenStream {
+        let mut tracked_files: Vec<String> = stages.iter().map(|s| s.module_path.clone()).collect();
+        tracked_files.extend(config_file_path());
+        tracked_files.extend(stages.iter().flat_map(|s| s.allowed_files.iter()).map(|f| f.full_path.to_string_lossy().to_string()));
+        dependency_anchors(tracked_files)
+    }
+
+    /// the user's closures, so that they get type checked. we use a random hash for the func names
+    /// to not conflict with other invocations of this macro
+    pub(super) fn host_closure_fns(stages: &[MetaStage], func_name: &Ident) -> Vec<proc_macro2::TokenStream> {
+        stages.iter().enumerate().map(|(i, stage)| {
+            let func_name = if i == 0 { func_name.clone() } else { format_ident!("{func_name}_{i}") };
+            let host_closure = &stage.host_closure;
+            quote! {
+                fn #func_name() {
+                    let cb = #host_closure;
+                }
+            }
+        }).collect()
+    }
+
+    /// every item that has a wasm_meta. `current` is what this build has expanded so far, and `previous`
+    /// is what the last build saved to the manifest. the previous build's entries stand in for the items of
+    /// files that havent been expanded yet, since wasm_finalize!() might be expanded before some of them
+    pub(super) struct Registry {
+        current: Vec<RegistryEntry>,
+        previous: Vec<RegistryEntry>,
+    }
+    pub(super) static REGISTRY: std::sync::Mutex<Option<Registry>> = std::sync::Mutex::new(None);
+
+    pub(super) fn registry_manifest_path() -> String {
+        let crate_name = std::env::var("CARGO_CRATE_NAME").unwrap_or("".into());
+        format!("{}/registry/{crate_name}.bin", get_wasmgen_base_dir())
+    }
+
+    pub(super) fn with_registry<T>(f: impl FnOnce(&mut Registry) -> T) -> T {
+        let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+        let registry = registry.get_or_insert_with(|| {
+            // an unreadable manifest (eg: from before LibraryObj changed) is the same as not having one
+            let previous = std::fs::read(registry_manifest_path()).ok()
+                .and_then(RegistryManifest::from_binary_slice)
+                .map(|m| m.entries)
+                .unwrap_or_default();
+            Registry { current: vec![], previous }
+        });
+        f(registry)
+    }
+
+    pub(super) fn record_in_registry(entry: RegistryEntry, write_manifest: bool) -> Result<(), String> {
+        with_registry(|registry| {
+            registry.current.retain(|e| !e.same_item(&entry));
+            registry.current.push(entry);
+            if !write_manifest {
+                return Ok(());
+            }
+            // only this build's items are saved, so items that no longer have a wasm_meta drop out of it
+            let manifest = RegistryManifest { entries: registry.current.clone() };
+            let path = registry_manifest_path();
+            if let Some(dir) = PathBuf::from(&path).parent() {
+                std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create registry directory {:?}\n{:?}", dir, e))?;
+            }
+            // other crates' builds may be reading it while we write
+            wasm_type_gen::artifact::write_atomic(&path, manifest.to_binary_slice())
+                .map_err(|e| format!("Failed to write registry manifest '{path}'\n{e}"))
+        })
+    }
+
+    /// this build's entries, plus the previous build's entries for files this build hasnt expanded yet.
+    /// a file that has been expanded has all of its items in current (so far), so its previous entries
+    /// are left out: they are items that were removed or renamed. also returns how many entries came from previous
+    pub(super) fn merge_registry(current: &[RegistryEntry], previous: &[RegistryEntry]) -> (Vec<RegistryEntry>, usize) {
+        let mut entries = current.to_vec();
+        entries.extend(previous.iter().filter(|p| !current.iter().any(|c| c.file == p.file)).cloned());
+        let from_previous = entries.len() - current.len();
+        entries.sort_by(|a, b| (&a.file, &a.name).cmp(&(&b.file, &b.name)));
+        (entries, from_previous)
+    }
+
+    pub(super) fn registry_entries() -> (Vec<RegistryEntry>, usize) {
+        with_registry(|registry| merge_registry(&registry.current, &registry.previous))
+    }
+}
+
+use meta::*;
+
+#[output_and_stringify_basic_const(LIBRARY_OBJ_EXTRA_IMPL)]
+impl LibraryObj {
+    /// fail the build with an error on the wasm_meta attribute. can be called more than once
+    #[allow(dead_code)]
+    fn compile_error(&mut self, err_msg: &str) {
+        self.error(err_msg, DiagnosticTarget::Attribute);
+    }
+    /// fail the build with an error pointing at `target`, eg:
+    /// `obj.error("must be a u64", DiagnosticTarget::FieldName("created_at".into()))`
+    #[allow(dead_code)]
+    fn error(&mut self, message: &str, target: DiagnosticTarget) {
+        self.diagnostics.push(UserDiagnostic { severity: DiagnosticSeverity::Error, message: message.into(), target, stage: 0 });
+    }
+    /// show a warning pointing at `target`. doesnt fail the build
+    #[allow(dead_code)]
+    fn warning(&mut self, message: &str, target: DiagnosticTarget) {
+        self.diagnostics.push(UserDiagnostic { severity: DiagnosticSeverity::Warning, message: message.into(), target, stage: 0 });
+    }
+    /// show a note pointing at `target`. doesnt fail the build
+    #[allow(dead_code)]
+    fn note(&mut self, message: &str, target: DiagnosticTarget) {
+        self.diagnostics.push(UserDiagnostic { severity: DiagnosticSeverity::Note, message: message.into(), target, stage: 0 });
+    }
+    /// replace the user's item with `code`, which can be any number of items (including none).
+    /// other changes to user_data are ignored if this is used
+    #[allow(dead_code)]
+    fn replace_item(&mut self, code: String) {
+        self.item_replacement = Some(code);
+    }
+    /// read a file relative to the crate's directory, eg: `read_file("queries/users.sql")`.
+    /// Modules can only read files that the crate allow-listed for them in its wasm_type_gen.toml:
+    /// ```toml
+    /// [modules.mymodule]
+    /// allow_read = ["queries/"]
+    /// ```
+    #[allow(dead_code)]
+    fn read_file(&self, path: &str) -> Result<&[u8], String> {
+        let path = path.trim_start_matches("./");
+        self.input_files.iter().find(|f| f.path == path).map(|f| f.data.as_slice())
+            .ok_or(format!("Cannot read '{path}'. It was not allow-listed for this module. Add it to allow_read for this module in wasm_type_gen.toml"))
+    }
+    /// add an attribute to the user's item. eg: `add_attribute("derive", "(Debug, Clone)")`
+    /// adds `#[derive(Debug, Clone)]`
+    #[allow(dead_code)]
+    fn add_attribute(&mut self, path: &str, tokens: &str) {
+        self.user_data.get_attributes().push(UserAttribute { path: path.into(), tokens: tokens.into() });
+    }
+    /// remove every attribute of the user's item that has this path. eg: "doc" removes its doc comments
+    #[allow(dead_code)]
+    fn remove_attributes(&mut self, path: &str) {
+        self.user_data.get_attributes().retain(|a| a.path != path);
+    }
+    /// add an attribute to a struct field or function param of the user's item.
+    /// eg: `add_field_attribute("user_id", "serde", "(rename = \"userId\")")`
+    #[allow(dead_code)]
+    fn add_field_attribute(&mut self, field_name: &str, path: &str, tokens: &str) -> Result<(), String> {
+        self.user_data.get_field_attributes(field_name)?.push(UserAttribute { path: path.into(), tokens: tokens.into() });
+        Ok(())
+    }
+    /// remove every attribute of a struct field or function param that has this path
+    #[allow(dead_code)]
+    fn remove_field_attributes(&mut self, field_name: &str, path: &str) -> Result<(), String> {
+        self.user_data.get_field_attributes(field_name)?.retain(|a| a.path != path);
+        Ok(())
+    }
+    /// same as read_file, but errors if the file isnt valid utf8
+    #[allow(dead_code)]
+    fn read_file_to_string(&self, path: &str) -> Result<String, String> {
+        let data = self.read_file(path)?;
+        String::from_utf8(data.to_vec()).map_err(|e| format!("'{path}' is not valid utf8. {e}"))
+    }
+    /// given a file name (no paths. the file will appear in ./wasmgen/{filename})
+    /// and a label, and a line (string) append to the file. create the file if it doesnt exist.
+    /// the label is used to sort lines between your wasm module and other invocations.
+    /// the label is also embedded to the file. so if you are outputing to a .sh file, for example,
+    /// your label should start with '#'. The labels are sorted alphabetically.
+    /// Example:
+    /// ```rust,ignore
+    /// # wasm module 1 does:
+    /// append_to_file("hello.txt", "b", "line1");
+    /// # wasm module 2 does:
+    /// append_to_file("hello.txt", "b", "line2");
+    /// # wasm module 3 does:
+    /// append_to_file("hello.txt", "a", "line3");
+    /// # wasm moudle 4 does:
+    /// append_to_file("hello.txt", "a", "line4");
+    /// 
+    /// # the output:
+    /// a
+    /// line3
+    /// line4
+    /// b
+    /// line1
+    /// line2
+    /// ```
+    #[allow(dead_code)]
+    fn append_to_file(&mut self, name: &str, label: &str, line: String) {
+        self.shared_output_data.push(SharedOutputEntry { label: label.into(), line, filename: name.into(), unique: false, after: None });
+    }
+
+    /// same as append_to_file, but the line will be unique within the label
+    #[allow(dead_code)]
+    fn append_to_file_unique(&mut self, name: &str, label: &str, line: String) {
+        self.shared_output_data.push(SharedOutputEntry { label: label.into(), line, filename: name.into(), unique: true, after: None });
+    }
+
+    /// like append_to_file, but given a search string, find that search string in that label
+    /// and then append the `after` portion immediately after the search string. Example:
+    /// ```rust,ignore
+    /// // "hello " doesnt exist yet, so the whole "hello , and also my friend Tim!" gets added
+    /// append_to_line("hello.txt", "a", "hello ", ", and also my friend Tim!");
+    /// append_to_line("hello.txt", "a", "hello ", "world"); 
+    /// 
+    /// # the output:
+    /// hello world, and also my friend Tim!
+    /// ```
+    #[allow(dead_code)]
+    fn append_to_line(&mut self, name: &str, label: &str, search_str: String, after: String) {
+        self.shared_output_data.push(SharedOutputEntry { label: label.into(), line: search_str, filename: name.into(), unique: false, after: Some(after) });
+    }
+}
+
+#[output_and_stringify_basic_const(USER_DATA_EXTRA_IMPL)]
+impl UserData {
+    /// Get the name of the user's data that they put this macro over.
+    /// for example `struct MyStruct { ... }` returns "MyStruct"
+    /// 
+    /// or `pub fn helloworld(a: u32) { ... }` returns "helloworld"
+    /// Can rename the user's data type by modifying this string directly
+    #[allow(dead_code)]
+    fn get_name(&mut self) -> &mut String {
+        match self {
+            UserData::Struct { name, .. } => name,
+            UserData::Function { name, .. } => name,
+            UserData::Module { name, .. } => name,
+            UserData::GlobalVariable { name, .. } => name,
+            UserData::Match { name, .. } => name,
+            UserData::Enum { name, .. } => name,
+            UserData::Impl { name, .. } => name,
+            UserData::Trait { name, .. } => name,
+            UserData::TypeAlias { name, .. } => name,
+            UserData::Use { name, .. } => name,
+            UserData::MacroRules { name, .. } => name,
+            UserData::Missing => unreachable!(),
+        }
+    }
+    /// The outer attributes of the user's item, including derives and doc comments.
+    /// Can add, remove, or modify them. See also `LibraryObj::add_attribute`
+    #[allow(dead_code)]
+    fn get_attributes(&mut self) -> &mut Vec<UserAttribute> {
+        match self {
+            UserData::Struct { attrs, .. } => attrs,
+            UserData::Function { attrs, .. } => attrs,
+            UserData::Module { attrs, .. } => attrs,
+            UserData::GlobalVariable { attrs, .. } => attrs,
+            UserData::Match { attrs, .. } => attrs,
+            UserData::Enum { attrs, .. } => attrs,
+            UserData::Impl { attrs, .. } => attrs,
+            UserData::Trait { attrs, .. } => attrs,
+            UserData::TypeAlias { attrs, .. } => attrs,
+            UserData::Use { attrs, .. } => attrs,
+            UserData::MacroRules { attrs, .. } => attrs,
+            UserData::Missing => unreachable!(),
+        }
+    }
+    /// The attributes of a struct field, or a function parameter, by name.
+    /// Errors if the user's item isn't a struct or function, or it has no such field / param
+    #[allow(dead_code)]
+    fn get_field_attributes(&mut self, field_name: &str) -> Result<&mut Vec<UserAttribute>, String> {
+        let found = match self {
+            UserData::Struct { fields, .. } => fields.iter_mut().find(|f| f.name == field_name).map(|f| &mut f.attrs),
+            UserData::Function { inputs, .. } => inputs.iter_mut().find(|i| i.name == field_name).map(|i| &mut i.attrs),
+            _ => return Err("Only structs and functions have fields / params".into()),
+        };
+        found.ok_or(format!("No field or param named '{field_name}'"))
+    }
+    /// Returns a bool of whether or not the user marked their data as pub or not.
+    /// Can set this value to true or false depending on your module's purpose.
+    #[allow(dead_code)]
+    fn get_public_vis(&mut self) -> &mut bool {
+        match self {
+            UserData::Struct { is_pub, .. } => is_pub,
+            UserData::Function { is_pub, .. } => is_pub,
+            UserData::Module { is_pub, .. } => is_pub,
+            UserData::GlobalVariable { is_pub, .. } => is_pub,
+            UserData::Match { is_pub, .. } => is_pub,
+            UserData::Enum { is_pub, .. } => is_pub,
+            UserData::Impl { is_pub, .. } => is_pub,
+            UserData::Trait { is_pub, .. } => is_pub,
+            UserData::TypeAlias { is_pub, .. } => is_pub,
+            UserData::Use { is_pub, .. } => is_pub,
+            UserData::MacroRules { is_pub, .. } => is_pub,
+            UserData::Missing => unreachable!(),
+        }
+    }
+}
+
+/// expands both wasm_meta and wasm_finalize!(). item is None for wasm_finalize!()
+fn expand_wasm_meta(attr: proc_macro2::TokenStream, item: Option<proc_macro2::TokenStream>) -> proc_macro::TokenStream {
     // this is a hack to allow people who write wasm_modules easy type hints.
     // if we detect no attributes, then we just output all of the types that
     // wasm module writers depend on, like UserData, and LibraryObj
-    if attr.is_empty() {
+    if attr.is_empty() && item.is_some() {
         let mut include_str = LibraryObj::include_in_rs_wasm();
-        include_str.push_str(library_obj_extra_impl);
-        include_str.push_str(user_data_extra_impl);
+        include_str.push_str(LIBRARY_OBJ_EXTRA_IMPL);
+        include_str.push_str(USER_DATA_EXTRA_IMPL);
         let include_tokens = proc_macro2::TokenStream::from_str(&include_str).unwrap_or_default();
         let parsing_tokens = proc_macro2::TokenStream::from_str(WASM_PARSING_TRAIT_STR).unwrap_or_default();
         let out = quote! {
@@ -759,225 +2135,843 @@ pub fn wasm_meta(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -
         return TokenStream::from(out);
     }
     
-    let mut attr = proc_macro2::TokenStream::from(attr);
-    let item = proc_macro2::TokenStream::from(item);
+    let is_finalize = item.is_none();
+    let item = item.unwrap_or_default();
     let item_str = item.to_string();
     let attr_str = attr.to_string();
     let combined = format!("{item_str}{attr_str}");
     let hash = adler32::adler32(combined.as_bytes()).unwrap_or(0);
     let func_name = format_ident!("_a{hash}");
-    let err_str = "Failed to parse signature of macro attribute. Expected a closure like |obj: &mut modulename::StructName| {{ ... }}";
-    let input_type = get_input_type(item);
+    let should_output_command_files = match should_do_file_operations() {
+        Ok(should_do) => should_do,
+        Err(e) => {
+            let err = syn::Error::new(first_span(&attr), e).to_compile_error();
+            return TokenStream::from(quote! { #err #item });
+        }
+    };
+    let mut add_to_code = LibraryObj::include_in_rs_wasm();
+    add_to_code.push_str(LibraryObj::gen_entrypoint());
+    add_to_code.push_str(WASM_PARSING_TRAIT_STR);
+    add_to_code.push_str(LIBRARY_OBJ_EXTRA_IMPL);
+    add_to_code.push_str(USER_DATA_EXTRA_IMPL);
+
+    // wasm_finalize!(): there is no item. instead the modules get every item that has a wasm_meta
+    if is_finalize {
+        let stages = match load_meta_stages(attr.clone()) {
+            Ok(s) => s,
+            Err(e) => return TokenStream::from(e.to_compile_error()),
+        };
+        let module_names: Vec<&str> = stages.iter().map(|s| s.meta_closure.module_name.as_str()).collect();
+        let module_label = module_names.iter().map(|n| format!("'{n}'")).collect::<Vec<_>>().join(", ");
+        let (registry, from_previous) = registry_entries();
+        // those items may have changed since, so whatever the modules generated from them may be out of date
+        let previous_warning = (from_previous > 0).then(|| {
+            let msg = format!("wasm_finalize!() was expanded before {from_previous} item(s) with a wasm_meta, so it used what the previous build recorded for them. put wasm_finalize!() after your `mod`s so that it sees them");
+            emit_warning(&msg, first_span(&attr))
+        });
+        let lib_obj = LibraryObj {
+            crate_name: std::env::var("CARGO_CRATE_NAME").unwrap_or("".into()),
+            registry,
+            ..Default::default()
+        };
+        let (mut lib_obj, stale_warnings) = match run_stages(&stages, lib_obj, "wasm_finalize", &add_to_code) {
+            Ok(out) => out,
+            Err(e) => return TokenStream::from(e.to_compile_error()),
+        };
+        let diagnostics = stage_diagnostics(std::mem::take(&mut lib_obj.diagnostics), &stages, None);
+        let mut module_errors = vec![];
+        let mut parse_all = |what: &str, code: Vec<String>| -> Vec<proc_macro2::TokenStream> {
+            code.iter().filter_map(|c| parse_module_items(what, c).map_err(|e| module_errors.push(e)).ok()).collect()
+        };
+        let add_before = parse_all("add_code_before", std::mem::take(&mut lib_obj.add_code_before));
+        let add_after = parse_all("add_code_after", std::mem::take(&mut lib_obj.add_code_after));
+        if lib_obj.item_replacement.is_some() {
+            module_errors.push("replace_item has no effect in wasm_finalize!(), there is no item to replace. use add_code_after instead".to_string());
+        }
+        if should_output_command_files {
+            if let Err(e) = lib_obj.handle_file_ops(&module_names.join(", "), "wasm_finalize") {
+                panic!("{}", e);
+            }
+        }
+        let attr_span = first_span(&attr);
+        let module_errors = module_errors.into_iter().map(|e| {
+            syn::Error::new(attr_span, format!("wasm module {module_label}: {e}")).to_compile_error()
+        });
+        let host_closures = host_closure_fns(&stages, &func_name);
+        let anchors = stage_anchors(&stages);
+        return TokenStream::from(quote! {
+            #(#host_closures)*
+            #(#add_before)*
+            #(#add_after)*
+            #(#diagnostics)*
+            #(#module_errors)*
+            #(#stale_warnings)*
+            #previous_warning
+            #anchors
+        });
+    }
+    let input_type = get_input_type(item.clone());
 
     // verify the input is something that we support. currently:
     // - entire functions, signature + body.
-    // - derive input, ie: struct defs, enums.
+    // - structs, enums, mods, consts/statics, and `const _: () = match ...`
+    // - impl blocks, traits, type aliases, use items, and macro_rules! definitions
     let mut input_type = if let Some(input) = input_type {
         input
     } else {
-        panic!("wasm_meta was applied to an item that we currently do not support parsing. Currently only supports functions and deriveInputs");
+        panic!("wasm_meta was applied to an item that we currently do not support parsing. Currently supports structs, enums, functions, consts, statics, mods, impl blocks, traits, type aliases, use items, and macro_rules!");
     };
     // println!("{:#?}", input_type);
 
-    // get everything in callback input signature |mything: &mut modulename::StructName| { ... }
-    let splits: Vec<_> = attr_str.split("|").collect();
-    let signature = match splits.get(1) {
-        Some(s) => *s,
-        None => panic!("{}", err_str),
-    };
-    // now signature looks like
-    // mything: &mut modulename::StructName
-    // actually it has spaces around it, but we can solve that by just removing the spaces
-    let signature_nospace = signature.replace(" ", "");
-    let after_mut = if let Some((_, b)) = signature_nospace.split_once("&mut") {
-        b.trim()
-    } else {
-        panic!("{}", err_str);
-    };
-    let module_name = if let Some((mod_name, _)) = after_mut.split_once("::") {
-        mod_name
-    } else {
-        panic!("{}", err_str);
-    };
-    let module_name_ident = format_ident!("{module_name}");
-    let base_dir = get_wasm_base_dir();
-    let module_path = format!("{base_dir}/{module_name}.rs");
-    let wasm_module_source = match load_rs_wasm_module(&module_path) {
-        Ok(c) => c,
+    let stages = match load_meta_stages(attr.clone()) {
+        Ok(s) => s,
         Err(e) => {
-            panic!("Error while reading file '{}' for module {module_name}. {:?}", module_path, e);
+            let err = e.to_compile_error();
+            return TokenStream::from(quote! { #err #item });
         }
     };
-    let parsed_wasm_code = match parse_file(&wasm_module_source) {
-        Ok(p) => p,
+    let module_names: Vec<&str> = stages.iter().map(|s| s.meta_closure.module_name.as_str()).collect();
+    let module_label = module_names.iter().map(|n| format!("'{n}'")).collect::<Vec<_>>().join(", ");
+
+    // this is necessary to allow the compile function to find previously compiled versions in case it fails to compile.
+    // it groups it by this "item_hash".
+    let item_name = input_type.get_name();
+
+    let mut lib_obj = LibraryObj::default();
+    lib_obj.user_data = (&input_type).into();
+    lib_obj.crate_name = std::env::var("CARGO_CRATE_NAME").unwrap_or("".into());
+    lib_obj.item_source = item_str.clone();
+    let anchors = stage_anchors(&stages);
+    let (mut lib_obj, stale_warnings) = match run_stages(&stages, lib_obj, &item_name, &add_to_code) {
+        Ok(out) => out,
         Err(e) => {
-            panic!("Failed to parse {} as valid rust code. Error:\n{:?}", module_path, e);
+            let err = e.to_compile_error();
+            return TokenStream::from(quote! { #err #item });
         }
     };
-    let exported_type = parsed_wasm_code.items.iter().find_map(|item| match item {
-        syn::Item::Type(ty) => if ty.ident.to_string() == "ExportType" {
-            match *ty.ty {
-                Type::Path(ref ty) => {
-                    match ty.path.segments.last() {
-                        Some(seg) => {
-                            if ty.path.segments.len() == 1 {
-                                Some(seg.ident.clone())
-                            } else {
-                                None
-                            }
-                        }
-                        None => None,
-                    }
-                },
-                _ => None,
-            }
-        } else {
-            None
-        },
-        _ => None,
-    });
-    let entrypoint_fn = parsed_wasm_code.items.iter().find(|item| {
-        match item {
-            syn::Item::Fn(fn_item) => {
-                if fn_item.sig.ident.to_string() != "wasm_entrypoint" {
-                    return false
-                }
-                if let syn::ReturnType::Default = fn_item.sig.output {} else {
-                    return false
-                }
-                // enforce 2 args: the first is the LibraryObj
-                // the 2nd is the callback to the user's function.
-                // but too lazy to parse the callback signature right now. we just assume its valid..
-                let input = if fn_item.sig.inputs.len() != 2 {
-                    return false
-                } else {
-                    fn_item.sig.inputs.first().unwrap()
-                };
-                let input = match input {
-                    syn::FnArg::Typed(t) => t,
-                    _ => return false,
-                };
-                let reference = match *input.ty {
-                    Type::Reference(ref r) => r.clone(),
-                    _ => return false,
-                };
-                if reference.mutability.is_none() {
-                    return false
-                }
-                let type_path = match *reference.elem {
-                    Type::Path(p) => p,
-                    _ => return false,
-                };
-                let first = match type_path.path.segments.first() {
-                    Some(s) => s,
-                    None => return false,
-                };
-                if first.ident.to_string() != "LibraryObj" {
-                    return false
+    let attr_span = first_span(&attr);
+    // println!("GOT BACK FROM WASM:\n{:#?}", lib_obj);
+
+    // resolve what the diagnostics point at now, before the modules' changes are applied to the item
+    let diagnostics = stage_diagnostics(std::mem::take(&mut lib_obj.diagnostics), &stages, Some(&input_type));
+
+    // mistakes in what the modules produced are reported as errors on the attribute.
+    // the user's item is still emitted (unchanged) so that they dont get a pile of unrelated errors too
+    let mut module_errors = vec![];
+    let mut parse_all = |what: &str, code: Vec<String>| -> Vec<proc_macro2::TokenStream> {
+        code.iter().filter_map(|c| parse_module_items(what, c).map_err(|e| module_errors.push(e)).ok()).collect()
+    };
+    let add_before = parse_all("add_code_before", std::mem::take(&mut lib_obj.add_code_before));
+    let add_after = parse_all("add_code_after", std::mem::take(&mut lib_obj.add_code_after));
+    let replacement = lib_obj.item_replacement.take().map(|r| parse_module_items("replace_item", &r));
+
+    if should_output_command_files {
+        if let Err(e) = lib_obj.handle_file_ops(&module_names.join(", "), &item_name) {
+            panic!("{}", e);
+        }
+    }
+
+    // for the registry, in case what the modules left isnt a single item we can read back
+    let original_data: UserData = (&input_type).into();
+    let use_name = format!("_b{hash}");
+    let item = match replacement {
+        Some(Ok(replacement)) => replacement,
+        Some(Err(e)) => {
+            module_errors.push(e);
+            input_type.back_to_stream(&use_name)
+        }
+        None => {
+            let original = input_type.clone();
+            match input_type.apply_library_obj_changes(lib_obj) {
+                Ok(_) => input_type.back_to_stream(&use_name),
+                Err(e) => {
+                    module_errors.push(e);
+                    original.back_to_stream(&use_name)
                 }
-                true
             }
-            _ => false,
         }
+    };
+    let module_errors = module_errors.into_iter().map(|e| {
+        syn::Error::new(attr_span, format!("wasm module {module_label}: {e}")).to_compile_error()
     });
-    if entrypoint_fn.is_none() {
-        panic!("Module '{}' is missing an entrypoint function. Valid modules must contain an entrypoint within the following signature:\npub fn wasm_entrypoint(obj: &mut LibraryObj);", module_path);
+    // record the item as the modules left it, for wasm_finalize!()
+    let (registered_name, registered_data): (String, UserData) = match get_input_type(item.clone()) {
+        Some(final_input) => (final_input.get_name(), (&final_input).into()),
+        None => (item_name.clone(), original_data),
+    };
+    let entry = RegistryEntry {
+        file: proc_macro::Span::call_site().file(),
+        name: registered_name,
+        modules: module_names.iter().map(|n| n.to_string()).collect(),
+        user_data: registered_data,
+    };
+    if let Err(e) = record_in_registry(entry, should_output_command_files) {
+        panic!("{}", e);
     }
-    let exported_name = match exported_type {
-        Some(n) => n,
-        None => panic!("Module '{}' is missing a valid ExportType. Expected to find statement like `pub type ExportType = SomeStruct;`", module_path)
+    let host_closures = host_closure_fns(&stages, &func_name);
+    let user_out = quote! {
+        #(#host_closures)*
+        #(#add_before)*
+        #item
+
+        #(#add_after)*
+        #(#diagnostics)*
+        #(#module_errors)*
+        #(#stale_warnings)*
+        #anchors
     };
 
-    let should_output_command_files = should_do_file_operations();
-    // this is necessary to allow the compile function to find previously compiled versions in case it fails to compile.
-    // it groups it by this "item_hash".
-    let item_name = input_type.get_name();
+    TokenStream::from(user_out)
+}
 
-    let mut pass_this = LibraryObj::default();
-    pass_this.user_data = (&input_type).into();
-    pass_this.crate_name = std::env::var("CARGO_CRATE_NAME").unwrap_or("".into());
-    let mut add_to_code = LibraryObj::include_in_rs_wasm();
-    add_to_code.push_str(LibraryObj::gen_entrypoint());
-    add_to_code.push_str(WASM_PARSING_TRAIT_STR);
-    add_to_code.push_str(library_obj_extra_impl);
-    add_to_code.push_str(user_data_extra_impl);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let final_wasm_source = quote! {
-        pub fn wasm_main(library_obj: &mut LibraryObj) {
-            #module_name_ident::wasm_entrypoint(library_obj, users_fn);
+    fn input(tokens: proc_macro2::TokenStream) -> InputType {
+        get_input_type(tokens).expect("item should be supported")
+    }
+
+    #[test]
+    fn new_item_kinds_are_supported() {
+        let items = [
+            (quote! { enum Color { Red, Green = 5 } }, "Color"),
+            (quote! { impl std::fmt::Display for Wrapper<u8> { } }, "Wrapper<u8>"),
+            (quote! { pub trait Shape { fn area(&self) -> f32; } }, "Shape"),
+            (quote! { type Bytes = Vec<u8>; }, "Bytes"),
+            (quote! { use std::collections::HashMap; }, "std::collections::HashMap"),
+            (quote! { macro_rules! twice { ($e:expr) => { $e * 2 } } }, "twice"),
+        ];
+        for (tokens, name) in items {
+            assert_eq!(input(tokens).get_name(), name);
         }
-        mod #module_name_ident {
-            use super::LibraryObj;
-            use super::UserData;
-            #parsed_wasm_code
+        // other macro invocations arent items we can describe
+        assert!(get_input_type(quote! { println!("hi"); }).is_none());
+    }
+
+    #[test]
+    fn enums_expose_their_variants() {
+        let data: UserData = (&input(quote! { pub enum Color { Red, Green = 5, Rgb(u8, u8, u8), Named { x: i32 } } })).into();
+        let UserData::Enum { name, is_pub, variants, .. } = data else { panic!("expected an enum, got {data:?}") };
+        assert_eq!(name, "Color");
+        assert!(is_pub);
+        let described: Vec<_> = variants.iter().map(|v| (v.name.as_str(), v.discriminant.as_str(), v.fields.len())).collect();
+        assert_eq!(described, [("Red", "", 0), ("Green", "5", 0), ("Rgb", "", 3), ("Named", "", 1)]);
+        assert_eq!(variants[3].fields[0].name, "x");
+    }
+
+    #[test]
+    fn impls_and_traits_expose_their_items() {
+        let data: UserData = (&input(quote! {
+            impl Shape for Square {
+                const SIDES: u32 = 4;
+                type Unit = f32;
+                pub fn area(&self) -> f32 { 1.0 }
+            }
+        })).into();
+        let UserData::Impl { name, trait_name, items, .. } = data else { panic!("expected an impl, got {data:?}") };
+        assert_eq!((name.as_str(), trait_name.as_str()), ("Square", "Shape"));
+        assert!(matches!(&items[0], UserAssocItem::Const { name, ty, .. } if name == "SIDES" && ty == "u32"));
+        assert!(matches!(&items[1], UserAssocItem::Type { name, ty, .. } if name == "Unit" && ty == "f32"));
+        assert!(matches!(&items[2], UserAssocItem::Method { name, is_pub: true, has_body: true, return_ty, .. } if name == "area" && return_ty == "f32"));
+
+        let data: UserData = (&input(quote! {
+            pub trait Shape {
+                fn area(&self) -> f32;
+                fn name(&self) -> String { String::new() }
+            }
+        })).into();
+        let UserData::Trait { is_pub, items, .. } = data else { panic!("expected a trait, got {data:?}") };
+        assert!(is_pub);
+        // trait items are as visible as the trait
+        assert!(matches!(&items[0], UserAssocItem::Method { is_pub: true, has_body: false, .. }));
+        assert!(matches!(&items[1], UserAssocItem::Method { has_body: true, .. }));
+    }
+
+    #[test]
+    fn macro_export_makes_macro_rules_pub() {
+        let data: UserData = (&input(quote! { #[macro_export] macro_rules! twice { ($e:expr) => { $e * 2 } } })).into();
+        assert!(matches!(data, UserData::MacroRules { is_pub: true, .. }));
+        let data: UserData = (&input(quote! { macro_rules! twice { () => {} } })).into();
+        assert!(matches!(data, UserData::MacroRules { is_pub: false, .. }));
+    }
+
+    #[test]
+    fn renames_write_back_to_new_item_kinds() {
+        let mut item = input(quote! { enum Color { Red } });
+        let mut lib_obj = LibraryObj { user_data: (&item).into(), ..Default::default() };
+        if let UserData::Enum { name, is_pub, .. } = &mut lib_obj.user_data {
+            *name = "Colour".to_string();
+            *is_pub = true;
         }
-        pub fn users_fn(data: &mut #module_name_ident::#exported_name) {
-            let cb = #attr;
-            cb(data);
+        item.apply_library_obj_changes(lib_obj).unwrap();
+        assert_eq!(item.back_to_stream("_").to_string(), quote! { pub enum Colour { Red } }.to_string());
+    }
+
+    fn match_input() -> InputType {
+        input(quote! {
+            const _: () = match x {
+                1 => "one",
+                n if n > 10 => "big",
+                _ => "other",
+            };
+        })
+    }
+
+    fn with_arms(item: &mut InputType, f: impl FnOnce(&mut Vec<UserMatchArm>)) -> Result<(), String> {
+        let mut lib_obj = LibraryObj { user_data: (&*item).into(), ..Default::default() };
+        if let UserData::Match { arms, .. } = &mut lib_obj.user_data {
+            f(arms);
         }
-    };
+        item.apply_library_obj_changes(lib_obj)
+    }
 
-    fn get_wasm_output(
-        out_name_hash: &str,
-        wasm_source: &str,
-        add_to_source: Option<String>,
-        data_to_pass: &LibraryObj,
-    ) -> Option<LibraryObj> {
-        let out_file = compile_string_to_wasm(out_name_hash, wasm_source, add_to_source, None).expect("compilation error");
-        let wasm_file = std::fs::read(out_file).expect("failed to read wasm binary");
-        let out = run_wasm(&wasm_file, data_to_pass.to_binary_slice()).expect("runtime error running wasm");
-        LibraryObj::from_binary_slice(out)
-    }
-
-    // TODO: instead of hashing the whole item input, use the item name, for eg function name or struct name.
-    // this way it wont change as often
-    // let item_hash = adler32::adler32(item_str.as_bytes()).unwrap_or(0);
-    let mut lib_obj = get_wasm_output(
-        &item_name,
-        &final_wasm_source.to_string(),
-        Some(add_to_code), 
-        &pass_this
-    ).unwrap_or_default();
-    // println!("GOT BACK FROM WASM:\n{:#?}", lib_obj);
+    fn arms_of(item: &InputType) -> Vec<String> {
+        match item {
+            InputType::Match(m) => m.arms.iter().map(|a| a.to_token_stream().to_string()).collect(),
+            _ => panic!("expected a match"),
+        }
+    }
+
+    #[test]
+    fn match_arms_are_exposed() {
+        let UserData::Match { name, arms, .. } = (&match_input()).into() else { panic!("expected a match") };
+        assert_eq!(name, "x");
+        let guard = &arms[1];
+        assert_eq!((guard.pattern.as_str(), guard.guard.as_str(), guard.body.as_str()), ("n", "n > 10", "\"big\""));
+        assert!(arms[0].guard.is_empty());
+    }
+
+    #[test]
+    fn match_arm_changes_are_written_back() {
+        let mut item = match_input();
+        let original = arms_of(&item);
+        with_arms(&mut item, |arms| {
+            // drop the guarded arm, move the wildcard to the front and add a new arm
+            arms.remove(1);
+            arms.swap(0, 1);
+            arms.insert(1, UserMatchArm { pattern: "2 | 3".into(), guard: "true".into(), body: "\"few\"".into() });
+        }).unwrap();
+        let arms = arms_of(&item);
+        assert_eq!(arms.len(), 3);
+        assert_eq!(arms[0], original[2]);
+        assert_eq!(arms[1], quote! { 2 | 3 if true => "few", }.to_string());
+        assert_eq!(arms[2], original[0]);
+    }
+
+    #[test]
+    fn invalid_match_arms_are_errors() {
+        let mut item = match_input();
+        let err = with_arms(&mut item, |arms| arms[0].pattern = "1 +".into()).unwrap_err();
+        assert!(err.contains("match arm"), "{err}");
+    }
+
+    fn field(name: &str, ty: &str, is_public: bool) -> UserField {
+        UserField { is_public, name: name.into(), ty: ty.into(), attrs: vec![] }
+    }
+
+    fn changed_struct(item: proc_macro2::TokenStream, f: impl FnOnce(&mut Vec<UserField>)) -> Result<String, String> {
+        let mut x: ItemStruct = syn::parse2(item).unwrap();
+        let mut fields = user_fields(&x.fields);
+        f(&mut fields);
+        apply_field_changes(&mut x, fields)?;
+        Ok(x.to_token_stream().to_string())
+    }
+
+    #[test]
+    fn named_fields_are_matched_by_name() {
+        let out = changed_struct(quote! { struct S { #[serde(skip)] a: u32, b: String, c: bool } }, |fields| {
+            fields.remove(1);
+            fields.swap(0, 1);
+            fields[1].ty = "u64".into();
+            fields[1].is_public = true;
+            fields.push(field("d", "Vec<u8>", false));
+        }).unwrap();
+        // a keeps its attribute even though it was retyped and moved
+        assert_eq!(out, quote! { struct S { c: bool, #[serde(skip)] pub a: u64, d: Vec<u8> } }.to_string());
+    }
+
+    #[test]
+    fn tuple_fields_are_matched_by_position() {
+        let out = changed_struct(quote! { pub struct S(#[doc = "x"] u32, String); }, |fields| {
+            fields[1].ty = "&'static str".into();
+            fields.push(field("", "bool", true));
+        }).unwrap();
+        assert_eq!(out, quote! { pub struct S(#[doc = "x"] u32, &'static str, pub bool); }.to_string());
+    }
+
+    #[test]
+    fn unit_structs_take_the_shape_of_the_added_fields() {
+        let out = changed_struct(quote! { struct S; }, |fields| fields.push(field("", "u8", false))).unwrap();
+        assert_eq!(out, quote! { struct S(u8); }.to_string());
+        let out = changed_struct(quote! { struct S; }, |fields| fields.push(field("a", "u8", true))).unwrap();
+        assert_eq!(out, quote! { struct S { pub a: u8 } }.to_string());
+        let out = changed_struct(quote! { struct S; }, |_| {}).unwrap();
+        assert_eq!(out, quote! { struct S; }.to_string());
+        // removing every field of a non unit struct keeps its braces
+        let out = changed_struct(quote! { struct S { a: u8 } }, |fields| fields.clear()).unwrap();
+        assert_eq!(out, quote! { struct S { } }.to_string());
+    }
+
+    #[test]
+    fn invalid_field_types_are_errors() {
+        let err = changed_struct(quote! { struct S { a: u8 } }, |fields| fields[0].ty = "Vec<".into()).unwrap_err();
+        assert!(err.contains("field type"), "{err}");
+        let err = changed_struct(quote! { struct S { a: u8 } }, |fields| fields.push(field("1x", "u8", false))).unwrap_err();
+        assert!(err.contains("field"), "{err}");
+    }
+
+    fn changed_fn(item: proc_macro2::TokenStream, f: impl FnOnce(&mut Vec<UserInput>, &mut bool, &mut String)) -> Result<String, String> {
+        let mut x: ItemFn = syn::parse2(item).unwrap();
+        let mut inputs = user_inputs(&x.sig);
+        let mut is_async = x.sig.asyncness.is_some();
+        let mut return_ty = return_type(&x.sig);
+        f(&mut inputs, &mut is_async, &mut return_ty);
+        apply_signature_changes(&mut x.sig, inputs, is_async, &return_ty)?;
+        Ok(x.sig.to_token_stream().to_string())
+    }
+
+    #[test]
+    fn signature_changes_are_written_back() {
+        let out = changed_fn(quote! { fn f(a: u32, #[allow(unused)] b: String) -> u8 { 0 } }, |inputs, is_async, return_ty| {
+            inputs.remove(0);
+            inputs[0].ty = "&str".into();
+            inputs.push(UserInput { is_self: false, name: "c".into(), ty: "bool".into(), attrs: vec![] });
+            inputs.insert(0, UserInput { is_self: true, name: "&mut self".into(), ty: String::new(), attrs: vec![] });
+            *is_async = true;
+            return_ty.clear();
+        }).unwrap();
+        assert_eq!(out, quote! { async fn f(&mut self, #[allow(unused)] b: &str, c: bool) }.to_string());
 
-    if !lib_obj.compiler_error_message.is_empty() {
-        // TODO: currently we just add a compile_error to the end of the stream..
-        // in the future maybe search for a string, and replace the right hand side to compile_error
-        // so that we can put it on a specific line
-        let err = format!("compile_error!(r#\"{}\"#);", lib_obj.compiler_error_message);
-        if let Ok(err) = proc_macro2::TokenStream::from_str(&err) {
-            attr.extend([err]);
+        let out = changed_fn(quote! { async fn f(&self) {} }, |inputs, is_async, return_ty| {
+            inputs[0].attrs.push(UserAttribute { path: "allow".into(), tokens: "(unused)".into() });
+            *is_async = false;
+            *return_ty = "Result<(), String>".into();
+        }).unwrap();
+        assert_eq!(out, quote! { fn f(#[allow(unused)] &self) -> Result<(), String> }.to_string());
+    }
+
+    #[test]
+    fn receivers_are_reported_and_written_back_as_written() {
+        let receivers = [quote! { &self }, quote! { &mut self }, quote! { self }, quote! { mut self: Box<Self> }];
+        for receiver in receivers {
+            let sig: syn::Signature = syn::parse2(quote! { fn f(#receiver, x: u8) }).unwrap();
+            let inputs = user_inputs(&sig);
+            assert!(inputs[0].is_self);
+            assert_eq!(inputs[0].name, receiver.to_string());
         }
+        let out = changed_fn(quote! { fn f(#[allow(unused)] &self) {} }, |inputs, _, _| inputs[0].name = "&mut self".into()).unwrap();
+        // the attributes stay on the rewritten receiver
+        assert_eq!(out, quote! { fn f(#[allow(unused)] &mut self) }.to_string());
+        let out = changed_fn(quote! { fn f(&mut self) {} }, |inputs, _, _| inputs[0].name = "self: Box<Self>".into()).unwrap();
+        assert_eq!(out, quote! { fn f(self: Box<Self>) }.to_string());
+        let err = changed_fn(quote! { fn f(&self) {} }, |inputs, _, _| inputs[0].name = "&&self".into()).unwrap_err();
+        assert!(err.contains("self parameter"), "{err}");
     }
 
-    let mut add_after = vec![];
-    for s in lib_obj.add_code_after.drain(..) {
-        let tokens = match proc_macro2::TokenStream::from_str(&s) {
-            Ok(o) => o,
-            Err(e) => {
-                panic!("Module '{}' produced invalid after_code tokens:\n{}\nError:\n{:?}", module_name, s, e);
+    #[test]
+    fn invalid_signatures_are_errors() {
+        let err = changed_fn(quote! { fn f(a: u32) {} }, |inputs, _, _| inputs[0].ty = "u32 u32".into()).unwrap_err();
+        assert!(err.contains("parameter type"), "{err}");
+        let err = changed_fn(quote! { fn f() {} }, |_, _, return_ty| *return_ty = "->".into()).unwrap_err();
+        assert!(err.contains("return type"), "{err}");
+    }
+
+    fn attrs_of(item: proc_macro2::TokenStream) -> Vec<syn::Attribute> {
+        input(item).attrs().to_vec()
+    }
+
+    #[test]
+    fn attributes_are_exposed_as_path_and_tokens() {
+        let attrs = user_attributes(&attrs_of(quote! {
+            #[doc = " docs"]
+            #[derive(Debug, Clone)]
+            #[serde::skip]
+            #[cfg_attr(test, derive(Default))]
+            struct S;
+        }));
+        let described: Vec<_> = attrs.iter().map(|a| (a.path.as_str(), a.tokens.as_str())).collect();
+        assert_eq!(described, [
+            ("doc", "= \" docs\""),
+            ("derive", "(Debug , Clone)"),
+            ("serde::skip", ""),
+            ("cfg_attr", "(test , derive (Default))"),
+        ]);
+        // inner attributes arent the item's to change
+        assert!(user_attributes(&attrs_of(quote! { mod m { #![allow(dead_code)] } })).is_empty());
+    }
+
+    #[test]
+    fn attribute_changes_are_written_back() {
+        let mut attrs = attrs_of(quote! {
+            #[derive(Debug)]
+            #[inline]
+            #[doc = "hi"]
+            fn f() {}
+        });
+        let mut new_attrs = user_attributes(&attrs);
+        new_attrs.remove(1);
+        new_attrs.swap(0, 1);
+        new_attrs.push(UserAttribute { path: "must_use".into(), tokens: "= \"why\"".into() });
+        apply_attribute_changes(&mut attrs, new_attrs).unwrap();
+        let out: Vec<_> = attrs.iter().map(|a| a.to_token_stream().to_string()).collect();
+        assert_eq!(out, [
+            quote! { #[doc = "hi"] }.to_string(),
+            quote! { #[derive(Debug)] }.to_string(),
+            quote! { #[must_use = "why"] }.to_string(),
+        ]);
+    }
+
+    #[test]
+    fn inner_attributes_are_kept() {
+        let item = quote! { mod m { #![allow(dead_code)] } };
+        let mut attrs = attrs_of(item);
+        apply_attribute_changes(&mut attrs, vec![UserAttribute { path: "cfg".into(), tokens: "(test)".into() }]).unwrap();
+        let out: Vec<_> = attrs.iter().map(|a| a.to_token_stream().to_string()).collect();
+        assert_eq!(out, [quote! { #[cfg(test)] }.to_string(), quote! { #![allow(dead_code)] }.to_string()]);
+    }
+
+    #[test]
+    fn invalid_attributes_are_errors() {
+        let mut attrs = vec![];
+        let err = apply_attribute_changes(&mut attrs, vec![UserAttribute { path: "derive".into(), tokens: "(Debug".into() }]).unwrap_err();
+        assert!(err.contains("attribute"), "{err}");
+    }
+
+    #[test]
+    fn field_and_param_attributes_are_found_by_name() {
+        let mut data: UserData = (&input(quote! { struct S { #[serde(skip)] a: u8 } })).into();
+        data.get_field_attributes("a").unwrap().clear();
+        let UserData::Struct { fields, .. } = &data else { unreachable!() };
+        assert!(fields[0].attrs.is_empty());
+        assert!(data.get_field_attributes("b").is_err());
+
+        let mut data: UserData = (&input(quote! { fn f(x: u8) {} })).into();
+        data.get_field_attributes("x").unwrap().push(UserAttribute { path: "allow".into(), tokens: "(unused)".into() });
+        let mut item = input(quote! { fn f(x: u8) {} });
+        item.apply_library_obj_changes(LibraryObj { user_data: data, ..Default::default() }).unwrap();
+        assert_eq!(item.back_to_stream("_").to_string(), quote! { fn f(#[allow(unused)] x: u8) {} }.to_string());
+
+        let mut data: UserData = (&input(quote! { enum E {} })).into();
+        assert!(data.get_field_attributes("a").is_err());
+    }
+
+    #[test]
+    fn module_code_is_zero_or_more_items() {
+        let code = parse_module_items("add_code_before", "const A: u8 = 1; fn b() {}").unwrap();
+        assert_eq!(code.to_string(), quote! { const A: u8 = 1; fn b() {} }.to_string());
+        assert!(parse_module_items("replace_item", "").unwrap().is_empty());
+        // a statement isnt an item
+        let err = parse_module_items("add_code_after", "let x = 1;").unwrap_err();
+        assert!(err.contains("invalid add_code_after") && err.contains("let x = 1;"), "{err}");
+    }
+
+    #[test]
+    fn replace_item_is_recorded() {
+        let mut lib_obj = LibraryObj { item_source: "struct A;".into(), ..Default::default() };
+        let source = lib_obj.item_source.replace('A', "B");
+        lib_obj.replace_item(source);
+        assert_eq!(lib_obj.item_replacement.as_deref(), Some("struct B;"));
+    }
+
+    #[test]
+    fn invalid_bodies_and_expressions_are_errors() {
+        let mut item = input(quote! { fn f() {} });
+        let mut data: UserData = (&item).into();
+        if let UserData::Function { body, .. } = &mut data {
+            *body = "{ let }".into();
+        }
+        let err = item.apply_library_obj_changes(LibraryObj { user_data: data, ..Default::default() }).unwrap_err();
+        assert!(err.contains("function body"), "{err}");
+
+        let mut item = input(quote! { static S: u8 = 1; });
+        let mut data: UserData = (&item).into();
+        if let UserData::GlobalVariable { expr, .. } = &mut data {
+            *expr = "1 +".into();
+        }
+        let err = item.apply_library_obj_changes(LibraryObj { user_data: data, ..Default::default() }).unwrap_err();
+        assert!(err.contains("expression"), "{err}");
+    }
+
+    fn target_of(item: proc_macro2::TokenStream, closure: proc_macro2::TokenStream, target: DiagnosticTarget) -> Option<String> {
+        let item = input(item);
+        let closure: syn::ExprClosure = syn::parse2(closure).unwrap();
+        diagnostic_target(Some(&item), &quote! { attr }, &closure, &target).map(|t| t.to_string())
+    }
+
+    #[test]
+    fn diagnostics_point_at_their_target() {
+        let s = quote! { struct S { a: u8, pub b: String } };
+        let f = quote! { fn f(&self, x: u32, y: bool) {} };
+        let c = quote! { |o: &mut m::T| { o.a = 1; o.b = 2; } };
+        assert_eq!(target_of(s.clone(), c.clone(), DiagnosticTarget::Attribute).unwrap(), "attr");
+        assert_eq!(target_of(s.clone(), c.clone(), DiagnosticTarget::Item).unwrap(), s.to_string());
+        assert_eq!(target_of(s.clone(), c.clone(), DiagnosticTarget::FieldName("b".into())).unwrap(), quote! { pub b: String }.to_string());
+        assert_eq!(target_of(s.clone(), c.clone(), DiagnosticTarget::FieldIndex(0)).unwrap(), quote! { a: u8 }.to_string());
+        assert_eq!(target_of(f.clone(), c.clone(), DiagnosticTarget::ParamName("y".into())).unwrap(), quote! { y: bool }.to_string());
+        assert_eq!(target_of(f.clone(), c.clone(), DiagnosticTarget::ParamName("self".into())).unwrap(), quote! { &self }.to_string());
+        assert_eq!(target_of(f.clone(), c.clone(), DiagnosticTarget::ParamName("&self".into())).unwrap(), quote! { &self }.to_string());
+        assert!(target_of(f.clone(), c.clone(), DiagnosticTarget::ParamName("&mut self".into())).is_none());
+        assert_eq!(target_of(f.clone(), c.clone(), DiagnosticTarget::ParamIndex(1)).unwrap(), quote! { x: u32 }.to_string());
+        assert_eq!(target_of(s.clone(), c.clone(), DiagnosticTarget::Statement(1)).unwrap(), quote! { o.b = 2; }.to_string());
+        // a closure without braces is a single statement
+        let short = quote! { |o: &mut m::T| o.a = 1 };
+        assert_eq!(target_of(s.clone(), short.clone(), DiagnosticTarget::Statement(0)).unwrap(), quote! { o.a = 1 }.to_string());
+        assert!(target_of(s.clone(), short, DiagnosticTarget::Statement(1)).is_none());
+    }
+
+    #[test]
+    fn missing_targets_are_none() {
+        let s = quote! { struct S { a: u8 } };
+        let f = quote! { fn f(x: u32) {} };
+        let c = quote! { |o: &mut m::T| {} };
+        assert!(target_of(s.clone(), c.clone(), DiagnosticTarget::FieldName("x".into())).is_none());
+        assert!(target_of(s.clone(), c.clone(), DiagnosticTarget::FieldIndex(1)).is_none());
+        assert!(target_of(s.clone(), c.clone(), DiagnosticTarget::Statement(0)).is_none());
+        // fields and params only exist on structs and functions
+        assert!(target_of(s, c.clone(), DiagnosticTarget::ParamIndex(0)).is_none());
+        assert!(target_of(f, c.clone(), DiagnosticTarget::FieldIndex(0)).is_none());
+        // wasm_finalize!() has no item
+        let closure: syn::ExprClosure = syn::parse2(c).unwrap();
+        assert!(diagnostic_target(None, &quote! { attr }, &closure, &DiagnosticTarget::Item).is_none());
+    }
+
+    fn closure_error(attr: proc_macro2::TokenStream) -> String {
+        match parse_meta_closure(attr) {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn closures_name_their_module() {
+        let closure = parse_meta_closure(quote! { |obj: &mut crate::mods::nested::mymod::MyStruct| { obj.apples = 2; } }).unwrap();
+        assert_eq!(closure.module_name, "mymod");
+        assert_eq!(closure.module_parents, ["mods", "nested"]);
+        assert_eq!(closure.type_segment.unwrap().ident, "MyStruct");
+        let closure = parse_meta_closure(quote! { move |obj: &mut super::mymod::MyStruct| {} }).unwrap();
+        assert_eq!(closure.module_name, "mymod");
+        assert!(closure.module_parents.is_empty());
+    }
+
+    #[test]
+    fn invalid_closures_are_errors() {
+        assert!(closure_error(quote! { |obj: &mut m::T| obj. }).contains("wasm_meta expects a closure"));
+        let err = closure_error(quote! { |a: &mut m::T, b: &mut m::T| {} });
+        assert!(err.contains("exactly 1 parameter") && err.ends_with("found 2"), "{err}");
+        assert!(closure_error(quote! { || {} }).ends_with("found 0"));
+        assert!(closure_error(quote! { |obj| {} }).contains("needs a type"));
+        assert!(closure_error(quote! { |obj: &m::T| {} }).contains("must be a mutable reference"));
+        assert!(closure_error(quote! { |obj: m::T| {} }).contains("must be a mutable reference"));
+        assert!(closure_error(quote! { |obj: &mut [u8]| {} }).contains("expected a path to a type"));
+        assert!(closure_error(quote! { |obj: &mut <m::T as X>::Y| {} }).contains("expected a path to a type"));
+        assert!(closure_error(quote! { |obj: &mut MyStruct| {} }).contains("module name is missing"));
+        assert!(closure_error(quote! { |obj: &mut crate::MyStruct| {} }).contains("module name is missing"));
+        assert!(closure_error(quote! { |obj: &mut m<u8>::T| {} }).contains("cant have generic arguments"));
+    }
+
+    #[test]
+    fn invalid_modules_are_errors() {
+        let dir = std::env::temp_dir().join(format!("example2_derive_meta_module_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, code: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, code).unwrap();
+            path.to_string_lossy().to_string()
+        };
+        let entrypoint = "pub fn wasm_entrypoint(obj: &mut LibraryObj, cb: fn(&mut A)) {}";
+        let valid = write("valid.rs", &format!("pub struct A {{}} pub type ExportType = A; {entrypoint}"));
+        let (_, export_type) = load_meta_module("valid", &valid).unwrap();
+        assert_eq!(export_type, "A");
+
+        let missing = dir.join("missing.rs").to_string_lossy().to_string();
+        assert!(load_meta_module("missing", &missing).unwrap_err().contains("Failed to load wasm module 'missing'"));
+        let invalid = write("invalid.rs", "pub fn (");
+        assert!(load_meta_module("invalid", &invalid).unwrap_err().contains("Failed to parse wasm module 'invalid'"));
+        let no_entrypoint = write("no_entrypoint.rs", "pub struct A {} pub type ExportType = A; pub fn wasm_entrypoint() {}");
+        assert!(load_meta_module("no_entrypoint", &no_entrypoint).unwrap_err().contains("missing an entrypoint"));
+        let no_export = write("no_export.rs", &format!("pub struct A {{}} {entrypoint}"));
+        assert!(load_meta_module("no_export", &no_export).unwrap_err().contains("missing a valid ExportType"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn field_values_become_a_closure() {
+        let meta = parse_meta_closure(quote! { crate::mods::mymod, apples = 2, name = "x", names = ["a", "b"], verbose, }).unwrap();
+        assert_eq!(meta.module_name, "mymod");
+        assert_eq!(meta.module_parents, ["mods"]);
+        assert!(meta.type_segment.is_none());
+        let names: Vec<String> = meta.field_values.iter().map(|(name, _)| name.to_string()).collect();
+        assert_eq!(names, ["apples", "name", "names", "verbose"]);
+        let export_type = format_ident!("MyStruct");
+        let expected = quote! {
+            |obj: &mut crate::mods::mymod::MyStruct| {
+                obj.apples = 2;
+                obj.name = ::std::convert::Into::into("x");
+                obj.names = vec![::std::convert::Into::into("a"), ::std::convert::Into::into("b")];
+                obj.verbose = true;
             }
         };
-        add_after.push(tokens);
+        assert_eq!(meta.closure(&export_type).to_token_stream().to_string(), expected.to_string());
+        // the guest always has the module at its root
+        let guest = meta.guest_closure(&export_type);
+        assert_eq!(guest.inputs.to_token_stream().to_string(), quote! { obj: &mut mymod::MyStruct }.to_string());
+        // just the module is fine too
+        assert!(parse_meta_closure(quote! { mymod }).unwrap().field_values.is_empty());
     }
 
-    if should_output_command_files {
-        if let Err(e) = lib_obj.handle_file_ops(module_name, &item_name) {
-            panic!("{}", e);
-        }
+    #[test]
+    fn invalid_field_values_are_errors() {
+        assert!(closure_error(quote! { mymod apples = 2 }).contains("or a module followed by values"));
+        assert!(closure_error(quote! { mymod, 2 = apples }).contains("or a module followed by values"));
+        assert!(closure_error(quote! { mymod, apples = }).contains("or a module followed by values"));
+        assert!(closure_error(quote! { "mymod", apples = 2 }).contains("or a module followed by values"));
+        assert!(closure_error(quote! { crate, apples = 2 }).contains("expected the name of a wasm module"));
+        assert!(closure_error(quote! { mymod<u8>, apples = 2 }).contains("cant have generic arguments"));
     }
 
-    input_type.apply_library_obj_changes(lib_obj);
-    let item = input_type.back_to_stream(&format!("_b{hash}"));
-    let user_out = quote! {
-        // we use a random hash for the func name to not conflict with other invocations of this macro
-        fn #func_name() {
-            let cb = #attr;
+    #[test]
+    fn field_values_must_exist_on_the_export_type() {
+        let meta = parse_meta_closure(quote! { mymod, apples = 2, pears = 3, plums }).unwrap();
+        assert!(meta.check_field_values(&["apples".into(), "pears".into(), "plums".into()]).is_ok());
+        let err = meta.check_field_values(&["apples".into()]).unwrap_err();
+        // every unknown field gets its own error
+        let messages: Vec<String> = err.into_iter().map(|e| e.to_string()).collect();
+        assert_eq!(messages, [
+            "wasm module 'mymod' has no field `pears`. its fields are: apples",
+            "wasm module 'mymod' has no field `plums`. its fields are: apples",
+        ]);
+        let err = meta.check_field_values(&[]).unwrap_err();
+        assert!(err.to_string().contains("doesnt take any values, but `apples` was given"));
+    }
+
+    #[test]
+    fn stages_are_split_on_top_level_semicolons() {
+        let stages = split_meta_stages(quote! {
+            validation, max = 3;
+            derive_helpers;
+            |obj: &mut register_route::Route| { obj.a = 1; obj.b = 2; };
+        });
+        let stages: Vec<String> = stages.iter().map(|s| s.to_string()).collect();
+        assert_eq!(stages, [
+            quote! { validation, max = 3 }.to_string(),
+            quote! { derive_helpers }.to_string(),
+            // the closure's own semicolons are inside its braces
+            quote! { |obj: &mut register_route::Route| { obj.a = 1; obj.b = 2; } }.to_string(),
+        ]);
+        // empty stages are dropped
+        assert_eq!(split_meta_stages(quote! { ;; mymod ;; }).len(), 1);
+        assert!(split_meta_stages(quote! {}).is_empty());
+    }
+
+    fn rust_stage(tokens: proc_macro2::TokenStream) -> MetaStage {
+        let meta_closure = parse_meta_closure(tokens.clone()).unwrap();
+        let export_type = format_ident!("Config");
+        let module_ident = format_ident!("{}", meta_closure.module_name);
+        let module: syn::File = syn::parse_quote! {
+            pub struct Config { pub n: u32 }
+            pub type ExportType = Config;
+            pub fn wasm_entrypoint(obj: &mut LibraryObj, cb: fn(&mut Config)) {}
+        };
+        MetaStage {
+            host_closure: meta_closure.closure(&export_type),
+            module_path: format!("wasm_modules/{module_ident}.rs"),
+            rust_module: Some((module, export_type)),
+            tokens,
+            meta_closure,
+            allowed_files: vec![],
         }
-        #item
+    }
 
-        #(#add_after)*
-    };
+    #[test]
+    fn pipelines_run_every_stage_in_order() {
+        let stages = [rust_stage(quote! { first, n = 1 }), rust_stage(quote! { second, n = 2 }), rust_stage(quote! { first, n = 3 })];
+        let segment: Vec<(usize, &MetaStage)> = stages.iter().enumerate().collect();
+        let source: syn::File = syn::parse2(guest_pipeline_source(&segment)).unwrap();
+        let names: Vec<String> = source.items.iter().filter_map(|item| match item {
+            syn::Item::Mod(m) => Some(m.ident.to_string()),
+            syn::Item::Fn(f) => Some(f.sig.ident.to_string()),
+            _ => None,
+        }).collect();
+        // a module used twice is only included once, but gets a closure per use
+        assert_eq!(names, ["wasm_main", "first", "second", "users_fn", "users_fn_1", "users_fn_2"]);
+        let main = source.items[0].to_token_stream().to_string();
+        let runs: Vec<usize> = ["first :: wasm_entrypoint (library_obj , users_fn)", "second :: wasm_entrypoint (library_obj , users_fn_1)", "first :: wasm_entrypoint (library_obj , users_fn_2)"]
+            .iter().map(|run| main.find(run).unwrap_or_else(|| panic!("{run} isnt in {main}"))).collect();
+        assert!(runs.windows(2).all(|w| w[0] < w[1]), "{main}");
+        // later segments keep their indices, so diagnostics are marked with the right stage
+        let segment = [(4, &stages[1])];
+        let source = guest_pipeline_source(&segment).to_string();
+        assert!(source.contains("users_fn_4") && source.contains("d . stage = 4u32"), "{source}");
+    }
 
-    TokenStream::from(user_out)
+    fn entry(file: &str, name: &str, modules: &[&str]) -> RegistryEntry {
+        RegistryEntry { file: file.into(), name: name.into(), modules: modules.iter().map(|m| m.to_string()).collect(), user_data: UserData::Missing }
+    }
+
+    fn described(entries: &[RegistryEntry]) -> Vec<(String, String, Vec<String>)> {
+        entries.iter().map(|e| (e.file.clone(), e.name.clone(), e.modules.clone())).collect()
+    }
+
+    #[test]
+    fn items_are_the_same_regardless_of_their_modules() {
+        assert!(entry("src/a.rs", "A", &["x"]).same_item(&entry("src/a.rs", "A", &["x", "y"])));
+        assert!(!entry("src/a.rs", "A", &["x"]).same_item(&entry("src/b.rs", "A", &["x"])));
+        assert!(!entry("src/a.rs", "A", &["x"]).same_item(&entry("src/a.rs", "B", &["x"])));
+    }
+
+    #[test]
+    fn previous_entries_only_stand_in_for_unexpanded_files() {
+        let current = [entry("src/b.rs", "B2", &["y"]), entry("src/a.rs", "A", &["x", "y"])];
+        let previous = [
+            // a.rs was expanded, so these are either in current or were removed / renamed since
+            entry("src/a.rs", "A", &["x"]),
+            entry("src/a.rs", "Removed", &["x"]),
+            entry("src/b.rs", "B1", &["y"]),
+            // c.rs hasnt been expanded yet
+            entry("src/c.rs", "C", &["z"]),
+        ];
+        let (entries, from_previous) = merge_registry(&current, &previous);
+        assert_eq!(described(&entries), described(&[
+            entry("src/a.rs", "A", &["x", "y"]),
+            entry("src/b.rs", "B2", &["y"]),
+            entry("src/c.rs", "C", &["z"]),
+        ]));
+        assert_eq!(from_previous, 1);
+        let (entries, from_previous) = merge_registry(&current, &[]);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(from_previous, 0);
+    }
+
+    #[test]
+    fn registry_manifests_round_trip() {
+        let manifest = RegistryManifest { entries: vec![entry("src/a.rs", "A", &["x"])] };
+        let read = RegistryManifest::from_binary_slice(manifest.to_binary_slice()).unwrap();
+        assert_eq!(described(&read.entries), described(&manifest.entries));
+        // an unreadable manifest is treated as not having one
+        assert!(RegistryManifest::from_binary_slice(vec![1, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn modules_floats_are_reproducible() {
+        // the bits of sqrt(-1.0) and 0 / 0 depend on the cpu, unless the runtime canonicalizes them
+        let wasm = wat::parse_str(r#"
+            (module
+                (import "env" "set_entrypoint_data" (func $set (param i32 i32)))
+                (memory (export "memory") 1)
+                (func (export "wasm_entrypoint") (result i32)
+                    (f64.store (i32.const 0) (f64.sqrt (f64.const -1)))
+                    (f64.store (i32.const 8) (f64.div (f64.const 0) (f64.const 0)))
+                    (f64.store (i32.const 16) (f64.div (f64.const 1) (f64.const 3)))
+                    (call $set (i32.const 0) (i32.const 24))
+                    (i32.const 0))
+            )
+        "#).unwrap();
+        let out = run_wasm_with(&module_runtime(), &wasm, vec![]).unwrap();
+        let bits: Vec<u64> = out.chunks(8).map(|c| u64::from_le_bytes(c.try_into().unwrap())).collect();
+        assert_eq!(bits[2], (1.0f64 / 3.0).to_bits());
+        assert!(f64::from_bits(bits[0]).is_nan() && f64::from_bits(bits[1]).is_nan());
+        #[cfg(feature = "wasmtime")]
+        assert_eq!(bits[..2], [0x7ff8_0000_0000_0000; 2]);
+    }
 }
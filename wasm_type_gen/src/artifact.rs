@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// returns a path next to `path` that is unique to this process (and this call)
+/// such that writing to it and then renaming it over `path` is atomic.
+pub fn tmp_path_for(path: &str) -> String {
+    let n = TMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+    format!("{path}.{}.{n}.tmp", std::process::id())
+}
+
+/// write to a temporary file, and then rename it to `path`.
+/// this way readers of `path` either see the old contents, or the new contents, but
+/// never a partially written file.
+pub fn write_atomic<C: AsRef<[u8]>>(path: &str, contents: C) -> Result<(), String> {
+    let tmp = tmp_path_for(path);
+    if let Err(e) = std::fs::write(&tmp, contents) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(format!("Failed to write {tmp}\n{:?}", e));
+    }
+    rename_atomic(&tmp, path)
+}
+
+/// same as `write_atomic` but the contents come from another file
+pub fn copy_atomic(from: &str, to: &str) -> Result<(), String> {
+    let tmp = tmp_path_for(to);
+    if let Err(e) = std::fs::copy(from, &tmp) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(format!("Failed to copy {from} to {tmp}\n{:?}", e));
+    }
+    rename_atomic(&tmp, to)
+}
+
+/// rename `from` to `to`. if it fails, `from` is removed.
+pub fn rename_atomic(from: &str, to: &str) -> Result<(), String> {
+    if let Err(e) = std::fs::rename(from, to) {
+        let _ = std::fs::remove_file(from);
+        return Err(format!("Failed to rename {from} to {to}\n{:?}", e));
+    }
+    Ok(())
+}
+
+/// true if the bytes are a valid wasm module
+pub fn is_valid_wasm(data: &[u8]) -> bool {
+    wasmparser::Validator::new().validate_all(data).is_ok()
+}
+
+/// Check that a previously compiled artifact can actually be used.
+/// If the file exists but is corrupt (for example it was truncated by a process that got killed
+/// mid-write before we had atomic writes) it is removed so it can be rebuilt.
+/// Returns true if the file exists and is valid.
+pub fn validate_cached_wasm(path: &str) -> bool {
+    let data = match std::fs::read(path) {
+        Ok(d) => d,
+        Err(_) => return false,
+    };
+    if is_valid_wasm(&data) {
+        return true;
+    }
+    let _ = std::fs::remove_file(path);
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_wasm_gets_removed() {
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_artifact_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let path = dir.join("a.wasm").to_string_lossy().to_string();
+        // valid, empty wasm module: magic + version
+        write_atomic(&path, [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00]).unwrap();
+        assert!(validate_cached_wasm(&path));
+        // truncated:
+        write_atomic(&path, [0x00, 0x61, 0x73]).unwrap();
+        assert!(!validate_cached_wasm(&path));
+        assert!(std::fs::File::open(&path).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn atomic_writes_leave_no_tmp_files() {
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_artifact2_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let path = dir.join("a.txt").to_string_lossy().to_string();
+        let path2 = dir.join("b.txt").to_string_lossy().to_string();
+        write_atomic(&path, "hello").unwrap();
+        copy_atomic(&path, &path2).unwrap();
+        assert_eq!(std::fs::read_to_string(&path2).unwrap(), "hello");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
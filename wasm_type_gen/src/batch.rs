@@ -0,0 +1,235 @@
+use std::sync::{Mutex, atomic::{AtomicUsize, Ordering}};
+
+use crate::{compile_string_to_wasm_unoptimized, resolve_wasm_out_dir, fallback_to_last, last_module_path, WasmBuild, artifact::copy_atomic, optimize::{OptimizeConfig, optimize_build}};
+
+/// options for compiling a single guest module.
+/// see `compile_string_to_wasm` for what these do. unlike `compile_string_to_wasm`,
+/// the crate's `[optimize]` config isnt used, only `optimize` here.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct CompileConfig {
+    pub add_to_code: Option<String>,
+    pub output_dir: Option<String>,
+    /// if set, the compiled module is post processed to make it smaller. see `optimize`
+    pub optimize: Option<OptimizeConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompileJob {
+    /// used as the wasm output name, ie: `{name}.{hash}.wasm`
+    pub name: String,
+    /// contents of the .rs file to compile
+    pub source: String,
+    pub config: CompileConfig,
+}
+
+impl CompileJob {
+    pub fn new<S1: Into<String>, S2: Into<String>>(name: S1, source: S2, config: CompileConfig) -> Self {
+        Self { name: name.into(), source: source.into(), config }
+    }
+}
+
+/// given a list of jobs, returns the index of the job that will actually be compiled
+/// for each job. Jobs that have the same source, add_to_code and output directory
+/// will produce the same .wasm file, so only the first one gets compiled.
+fn dedup_jobs(jobs: &[CompileJob]) -> Vec<usize> {
+    let mut seen: Vec<(u32, usize)> = vec![];
+    let mut out = Vec::with_capacity(jobs.len());
+    for (i, job) in jobs.iter().enumerate() {
+        let mut key = job.source.clone();
+        key.push('\0');
+        key.push_str(job.config.add_to_code.as_deref().unwrap_or_default());
+        key.push('\0');
+        key.push_str(&resolve_wasm_out_dir(job.config.output_dir.clone()));
+        let hash = adler32::adler32(key.as_bytes()).unwrap_or(0);
+        let existing = seen.iter().find(|(h, index)| {
+            *h == hash
+                && jobs[*index].source == job.source
+                && jobs[*index].config == job.config
+        });
+        match existing {
+            Some((_, index)) => out.push(*index),
+            None => {
+                seen.push((hash, i));
+                out.push(i);
+            }
+        }
+    }
+    out
+}
+
+fn compile_job(job: &CompileJob) -> Result<WasmBuild, String> {
+    let mut res = compile_string_to_wasm_unoptimized(&job.name, &job.source, job.config.add_to_code.clone(), job.config.output_dir.clone());
+    if let Some(optimize) = &job.config.optimize {
+        res = res.and_then(|build| optimize_build(build, optimize));
+    }
+    res
+}
+
+/// a deduped job reuses the module of the job it's a duplicate of. if their names differ, it still needs
+/// its own `{name}.{hash}.wasm` and `{name}.last.wasm`, otherwise a later failed compile of it has nothing
+/// to fall back to. if the other job failed, this one would fail the same way, so instead of compiling it
+/// again it gets the same error, along with its own last build if it has one. same for skipped builds.
+fn alias_build(res: Result<WasmBuild, String>, original: &CompileJob, job: &CompileJob) -> Result<WasmBuild, String> {
+    let own_last = || last_module_path(&resolve_wasm_out_dir(job.config.output_dir.clone()), &job.name);
+    let path = match res {
+        Ok(WasmBuild::Fresh(path)) => path,
+        Ok(WasmBuild::Stale { error, .. }) | Err(error) => return fallback_to_last(own_last(), error),
+        // without its own last build, the original's is still built from the same source
+        Ok(WasmBuild::Skipped(path)) => return Ok(WasmBuild::Skipped(own_last().unwrap_or(path))),
+    };
+    let path_buf = std::path::PathBuf::from(&path);
+    let file_name = path_buf.file_name().and_then(|f| f.to_str()).unwrap_or_default();
+    let hash_part = if original.name.is_empty() {
+        Some(file_name)
+    } else {
+        file_name.strip_prefix(&format!("{}.", original.name))
+    };
+    let (dir, hash_part) = match (path_buf.parent(), hash_part) {
+        (Some(dir), Some(h)) => (dir.to_string_lossy().to_string(), h),
+        _ => return Ok(WasmBuild::Fresh(path)),
+    };
+    // same naming as compile_string_to_wasm
+    let (own_path, own_last) = if job.name.is_empty() {
+        (format!("{dir}/{hash_part}"), format!("{dir}/last.wasm"))
+    } else {
+        (format!("{dir}/{}.{hash_part}", job.name), format!("{dir}/{}.last.wasm", job.name))
+    };
+    copy_atomic(&path, &own_path)?;
+    copy_atomic(&own_path, &own_last)?;
+    Ok(WasmBuild::Fresh(own_path))
+}
+
+/// Compile many guest modules at once. Identical jobs are only compiled once,
+/// and the rest are compiled by at most `max_workers` threads at a time.
+/// If `max_workers` is 0 we use the number of available cpus.
+/// Returns one result per job, in the same order as the input.
+/// Concurrent compilations (even from other processes) into the same output directory
+/// are safe: see `CompileLock`.
+pub fn compile_batch(jobs: &[CompileJob], max_workers: usize) -> Vec<Result<WasmBuild, String>> {
+    let assignments = dedup_jobs(jobs);
+    let mut unique: Vec<usize> = assignments.clone();
+    unique.sort();
+    unique.dedup();
+
+    let max_workers = if max_workers == 0 {
+        std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    } else {
+        max_workers
+    };
+    let num_workers = max_workers.min(unique.len());
+
+    let results: Vec<Mutex<Option<Result<WasmBuild, String>>>> = jobs.iter().map(|_| Mutex::new(None)).collect();
+    let next = AtomicUsize::new(0);
+    std::thread::scope(|s| {
+        for _ in 0..num_workers {
+            s.spawn(|| loop {
+                let n = next.fetch_add(1, Ordering::SeqCst);
+                let job_index = match unique.get(n) {
+                    Some(i) => *i,
+                    None => break,
+                };
+                let res = compile_job(&jobs[job_index]);
+                if let Ok(mut slot) = results[job_index].lock() {
+                    *slot = Some(res);
+                }
+            });
+        }
+    });
+
+    let compiled: Vec<Option<Result<WasmBuild, String>>> = results.into_iter()
+        .map(|m| m.into_inner().unwrap_or(None))
+        .collect();
+    assignments.iter().enumerate().map(|(i, index)| {
+        let res = compiled[*index].clone().unwrap_or_else(|| Err("Compilation worker failed to produce a result".into()));
+        if *index != i && jobs[*index].name != jobs[i].name {
+            alias_build(res, &jobs[*index], &jobs[i])
+        } else {
+            res
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_jobs_are_deduped() {
+        let config = CompileConfig { add_to_code: Some("struct A;".into()), output_dir: Some("/tmp/x".into()), optimize: None };
+        let jobs = [
+            CompileJob::new("a", "fn main() {}", config.clone()),
+            CompileJob::new("b", "fn other() {}", config.clone()),
+            CompileJob::new("c", "fn main() {}", config.clone()),
+            CompileJob::new("d", "fn main() {}", CompileConfig::default()),
+        ];
+        assert_eq!(dedup_jobs(&jobs), vec![0, 1, 0, 3]);
+    }
+
+    #[test]
+    fn deduped_jobs_get_their_own_artifacts() {
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_batch_alias_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let dir = dir.to_string_lossy().to_string();
+        let module = format!("{dir}/a.123.wasm");
+        std::fs::write(&module, [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00]).unwrap();
+        let config = CompileConfig { output_dir: Some(dir.clone()), ..Default::default() };
+        let a = CompileJob::new("a", "fn main() {}", config.clone());
+        let b = CompileJob::new("b", "fn main() {}", config);
+        let build = alias_build(Ok(WasmBuild::Fresh(module.clone())), &a, &b).unwrap();
+        assert_eq!(build, WasmBuild::Fresh(format!("{dir}/b.123.wasm")));
+        assert_eq!(std::fs::read(format!("{dir}/b.last.wasm")).unwrap(), std::fs::read(&module).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn deduped_failed_jobs_use_their_own_fallback() {
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_batch_stale_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let dir = dir.to_string_lossy().to_string();
+        let last = format!("{dir}/b.last.wasm");
+        crate::artifact::write_atomic(&last, [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00]).unwrap();
+        // unparseable, so this fails before ever invoking rustc
+        let config = CompileConfig { output_dir: Some(dir.clone()), ..Default::default() };
+        let jobs = [
+            CompileJob::new("a", "fn broken( {", config.clone()),
+            CompileJob::new("b", "fn broken( {", config),
+        ];
+        let results = compile_batch(&jobs, 2);
+        assert!(results[0].is_err());
+        let b = results[1].clone().unwrap();
+        assert!(!b.is_fresh());
+        assert_eq!(b.path(), last);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn deduped_jobs_dont_recompile_failures() {
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_batch_failed_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let dir = dir.to_string_lossy().to_string();
+        let wasm = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        crate::artifact::write_atomic(&format!("{dir}/a.last.wasm"), wasm).unwrap();
+        // this source compiles fine, so a Fresh result would mean alias_build ran rustc
+        let config = CompileConfig { output_dir: Some(dir.clone()), ..Default::default() };
+        let a = CompileJob::new("a", "pub fn f() {}", config.clone());
+        let b = CompileJob::new("b", "pub fn f() {}", config);
+
+        assert_eq!(alias_build(Err("boom".into()), &a, &b), Err("boom".to_string()));
+        let stale = || Ok(WasmBuild::Stale { path: format!("{dir}/a.last.wasm"), error: "boom".into() });
+        // b has no fallback of its own yet
+        assert_eq!(alias_build(stale(), &a, &b), Err("boom".to_string()));
+        assert_eq!(alias_build(Ok(WasmBuild::Skipped(format!("{dir}/a.last.wasm"))), &a, &b), Ok(WasmBuild::Skipped(format!("{dir}/a.last.wasm"))));
+
+        let b_last = format!("{dir}/b.last.wasm");
+        crate::artifact::write_atomic(&b_last, wasm).unwrap();
+        assert_eq!(alias_build(stale(), &a, &b), Ok(WasmBuild::Stale { path: b_last.clone(), error: "boom".into() }));
+        assert_eq!(alias_build(Err("boom".into()), &a, &b), Ok(WasmBuild::Stale { path: b_last.clone(), error: "boom".into() }));
+        assert_eq!(alias_build(Ok(WasmBuild::Skipped(format!("{dir}/a.last.wasm"))), &a, &b), Ok(WasmBuild::Skipped(b_last)));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn empty_batch() {
+        assert!(compile_batch(&[], 4).is_empty());
+    }
+}
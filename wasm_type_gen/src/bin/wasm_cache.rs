@@ -0,0 +1,135 @@
+//! Manage the wasm output cache, `./wasmout/` by default. `--dir` can be given more than once
+//! to pick the directories instead. When pruning several directories, `--max-size-mb` is for all
+//! of them together. Dont point this at `./wasmgen/`: thats the files the modules generated, not a cache.
+//!
+//! ```text
+//! wasm_cache [--dir ./wasmout] list
+//! wasm_cache [--dir ./wasmout] stats [--reset]
+//! wasm_cache [--dir ./wasmout] prune [--max-age-days N] [--max-size-mb N]
+//! wasm_cache [--dir ./wasmout] purge <module_name>
+//! ```
+
+use std::time::{Duration, SystemTime};
+
+use wasm_type_gen::cache::{self, PruneOptions, PruneReport};
+
+const USAGE: &str = "Usage: wasm_cache [--dir <dir>]... <list | stats [--reset] | prune [--max-age-days N] [--max-size-mb N] | purge <module_name>>";
+
+const DEFAULT_DIR: &str = "./wasmout";
+
+fn human_size(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1}MB", bytes as f64 / (1024.0 * 1024.0))
+    } else if bytes >= 1024 {
+        format!("{:.1}KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{bytes}B")
+    }
+}
+
+fn print_report(report: &PruneReport) {
+    for entry in report.removed.iter() {
+        println!("removed {} ({})", entry.path, human_size(entry.size));
+    }
+    println!("freed {}, {} remaining", human_size(report.freed_bytes), human_size(report.remaining_bytes));
+}
+
+fn parse_num(flag: &str, val: Option<String>) -> Result<u64, String> {
+    let val = val.ok_or(format!("{flag} requires a value"))?;
+    val.parse().map_err(|_| format!("{flag} expected a number, got '{val}'"))
+}
+
+fn run() -> Result<(), String> {
+    let mut args = std::env::args().skip(1);
+    let mut dirs = vec![];
+    let mut command = None;
+    let mut rest = vec![];
+    while let Some(arg) = args.next() {
+        if arg == "--dir" {
+            dirs.push(args.next().ok_or("--dir requires a value")?);
+        } else if command.is_none() {
+            command = Some(arg);
+        } else {
+            rest.push(arg);
+        }
+    }
+    if dirs.is_empty() {
+        dirs.push(DEFAULT_DIR.to_string());
+    }
+    let command = command.ok_or(USAGE)?;
+    match command.as_str() {
+        "list" => {
+            let now = SystemTime::now();
+            let mut entries = vec![];
+            for dir in dirs.iter() {
+                entries.extend(cache::list_artifacts(dir)?);
+            }
+            let mut total = 0;
+            for entry in entries.iter() {
+                let age = now.duration_since(entry.last_used).unwrap_or_default().as_secs();
+                println!("{:>10}  {:>8}s ago  {}", human_size(entry.size), age, entry.path);
+                total += entry.size;
+            }
+            println!("{} entries, {} total", entries.len(), human_size(total));
+        }
+        "stats" => {
+            match rest.first().map(|s| s.as_str()) {
+                None => {}
+                Some("--reset") => {
+                    for dir in dirs.iter() {
+                        cache::reset_cache_stats(dir);
+                    }
+                    println!("stats reset");
+                    return Ok(());
+                }
+                Some(flag) => return Err(format!("Unknown stats option '{flag}'\n{USAGE}")),
+            }
+            let mut stats = cache::CacheStats::default();
+            for dir in dirs.iter() {
+                let dir_stats = cache::cache_stats(dir);
+                stats.hits += dir_stats.hits;
+                stats.misses += dir_stats.misses;
+            }
+            let total = stats.hits + stats.misses;
+            let rate = if total == 0 { 0.0 } else { stats.hits as f64 * 100.0 / total as f64 };
+            println!("hits: {}\nmisses: {}\nhit rate: {:.1}%", stats.hits, stats.misses, rate);
+        }
+        "prune" => {
+            let mut options = PruneOptions::default();
+            let mut rest = rest.into_iter();
+            while let Some(flag) = rest.next() {
+                match flag.as_str() {
+                    "--max-age-days" => {
+                        let days = parse_num(&flag, rest.next())?;
+                        options.max_age = Some(Duration::from_secs(days * 24 * 60 * 60));
+                    }
+                    "--max-size-mb" => {
+                        let mb = parse_num(&flag, rest.next())?;
+                        options.max_total_size = Some(mb * 1024 * 1024);
+                    }
+                    _ => return Err(format!("Unknown prune option '{flag}'\n{USAGE}")),
+                }
+            }
+            if options.max_age.is_none() && options.max_total_size.is_none() {
+                return Err(format!("prune requires --max-age-days and/or --max-size-mb\n{USAGE}"));
+            }
+            let dirs: Vec<&str> = dirs.iter().map(|d| d.as_str()).collect();
+            print_report(&cache::prune_dirs(&dirs, options)?);
+        }
+        "purge" => {
+            let name = rest.first().ok_or(format!("purge requires a module name\n{USAGE}"))?;
+            for dir in dirs.iter() {
+                print_report(&cache::purge_module(dir, name)?);
+            }
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
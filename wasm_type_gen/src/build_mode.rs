@@ -0,0 +1,169 @@
+//! Figure out why our proc macro / build code is being run, so we can decide
+//! whether its worth compiling wasm modules and writing files to disk.
+//!
+//! The mode is detected from the arguments of the process we are running in
+//! (for proc macros thats rustc, rustdoc, or rust-analyzer's proc macro server).
+//! It can be overridden, in order of precedence, by:
+//! - the `WASM_TYPE_GEN_BUILD_MODE` env var, eg: `WASM_TYPE_GEN_BUILD_MODE=build cargo check`
+//! - a `wasm_type_gen.toml` file next to the Cargo.toml of the crate being compiled containing
+//!   `build_mode = "build"`
+//!
+//! Valid values are `ide`, `check`, `build`, `test` and `doc`.
+//! The same file also holds per module settings, see `file_access`.
+
+use std::str::FromStr;
+
+pub const BUILD_MODE_ENV: &str = "WASM_TYPE_GEN_BUILD_MODE";
+pub const BUILD_MODE_CONFIG_FILE: &str = "wasm_type_gen.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildMode {
+    /// an IDE (rust-analyzer) is expanding macros, potentially on every keystroke
+    IdeCheck,
+    /// `cargo check`. this includes the check an IDE runs on save
+    CargoCheck,
+    /// `cargo build` / `cargo run`
+    CargoBuild,
+    /// compiling a test harness (`cargo test`)
+    Test,
+    /// `cargo doc` or doc tests
+    Doc,
+}
+
+impl BuildMode {
+    pub const ALL: [BuildMode; 5] = [
+        BuildMode::IdeCheck, BuildMode::CargoCheck, BuildMode::CargoBuild, BuildMode::Test, BuildMode::Doc,
+    ];
+
+    /// the name used by the env var and the config file
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuildMode::IdeCheck => "ide",
+            BuildMode::CargoCheck => "check",
+            BuildMode::CargoBuild => "build",
+            BuildMode::Test => "test",
+            BuildMode::Doc => "doc",
+        }
+    }
+
+    /// whether to compile wasm modules that arent cached yet. When this is false
+    /// the last successful build of a module gets used if there is one.
+    pub fn should_compile(&self) -> bool {
+        !matches!(self, BuildMode::IdeCheck)
+    }
+
+    /// whether macros should write their output files (eg: wasmgen/) to disk
+    pub fn should_write_files(&self) -> bool {
+        matches!(self, BuildMode::CargoBuild | BuildMode::Test)
+    }
+}
+
+impl FromStr for BuildMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        BuildMode::ALL.into_iter().find(|m| m.name().eq_ignore_ascii_case(s)).ok_or_else(|| {
+            let valid: Vec<&str> = BuildMode::ALL.iter().map(|m| m.name()).collect();
+            format!("Invalid build mode '{s}'. Expected one of: {}", valid.join(", "))
+        })
+    }
+}
+
+impl std::fmt::Display for BuildMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// detect the mode from the args of the current process (as in `std::env::args`)
+/// and an env var lookup. Does not consider overrides.
+pub fn detect_build_mode(args: &[String], env: &dyn Fn(&str) -> Option<String>) -> BuildMode {
+    let program = args.first().map(|p| {
+        std::path::Path::new(p).file_name().unwrap_or_default().to_string_lossy().to_string()
+    }).unwrap_or_default();
+    if env("RUST_ANALYZER_INTERNALS_DO_NOT_USE").is_some() || program.contains("rust-analyzer") {
+        return BuildMode::IdeCheck;
+    }
+    if program.starts_with("rustdoc") {
+        return BuildMode::Doc;
+    }
+    let mut emit = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--test" {
+            return BuildMode::Test;
+        }
+        if let Some(kinds) = arg.strip_prefix("--emit=") {
+            emit = Some(kinds.to_string());
+        } else if arg == "--emit" {
+            emit = iter.next().cloned();
+        }
+    }
+    match emit {
+        // cargo check only asks for metadata. rustc links by default if --emit isnt passed
+        Some(kinds) if !kinds.split(',').any(|k| k.starts_with("link")) => BuildMode::CargoCheck,
+        _ => BuildMode::CargoBuild,
+    }
+}
+
+/// reads `build_mode = "..."` out of a config file's contents
+pub fn build_mode_from_config(contents: &str) -> Result<Option<BuildMode>, String> {
+    let table: toml::Table = contents.parse().map_err(|e| format!("Failed to parse {BUILD_MODE_CONFIG_FILE}\n{e}"))?;
+    match table.get("build_mode") {
+        None => Ok(None),
+        Some(toml::Value::String(s)) => s.parse().map(Some).map_err(|e| format!("{BUILD_MODE_CONFIG_FILE}: {e}")),
+        Some(v) => Err(format!("{BUILD_MODE_CONFIG_FILE}: build_mode must be a string, found {v}")),
+    }
+}
+
+/// the build mode of the current process, with overrides applied.
+/// Errors if an override is set to something invalid.
+pub fn build_mode() -> Result<BuildMode, String> {
+    if let Ok(s) = std::env::var(BUILD_MODE_ENV) {
+        if !s.trim().is_empty() {
+            return s.parse().map_err(|e| format!("{BUILD_MODE_ENV}: {e}"));
+        }
+    }
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or(".".into());
+    if let Ok(contents) = std::fs::read_to_string(format!("{manifest_dir}/{BUILD_MODE_CONFIG_FILE}")) {
+        if let Some(mode) = build_mode_from_config(&contents)? {
+            return Ok(mode);
+        }
+    }
+    let args: Vec<String> = std::env::args().collect();
+    Ok(detect_build_mode(&args, &|key| std::env::var(key).ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(args: &[&str]) -> BuildMode {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        detect_build_mode(&args, &|_| None)
+    }
+
+    #[test]
+    fn detects_from_rustc_args() {
+        assert_eq!(detect(&["rustc", "--crate-name", "a", "--emit=dep-info,metadata", "src/lib.rs"]), BuildMode::CargoCheck);
+        assert_eq!(detect(&["rustc", "--crate-name", "a", "--emit=dep-info,metadata,link", "src/lib.rs"]), BuildMode::CargoBuild);
+        assert_eq!(detect(&["/x/rustc", "--emit", "dep-info,link", "src/lib.rs"]), BuildMode::CargoBuild);
+        assert_eq!(detect(&["rustc", "src/lib.rs"]), BuildMode::CargoBuild);
+        assert_eq!(detect(&["rustc", "--emit=dep-info,link", "--test", "src/lib.rs"]), BuildMode::Test);
+        assert_eq!(detect(&["/home/me/.rustup/toolchains/x/bin/rustdoc", "src/lib.rs"]), BuildMode::Doc);
+        assert_eq!(detect(&["/x/rust-analyzer-proc-macro-srv"]), BuildMode::IdeCheck);
+        let args = vec!["rustc".to_string(), "--emit=link".to_string()];
+        let ra = |k: &str| (k == "RUST_ANALYZER_INTERNALS_DO_NOT_USE").then(|| "1".to_string());
+        assert_eq!(detect_build_mode(&args, &ra), BuildMode::IdeCheck);
+    }
+
+    #[test]
+    fn parses_overrides() {
+        assert_eq!("Build".parse::<BuildMode>(), Ok(BuildMode::CargoBuild));
+        assert!("release".parse::<BuildMode>().unwrap_err().contains("ide, check, build, test, doc"));
+        assert_eq!(build_mode_from_config("build_mode = \"ide\""), Ok(Some(BuildMode::IdeCheck)));
+        assert_eq!(build_mode_from_config("other = 1"), Ok(None));
+        assert!(build_mode_from_config("build_mode = 1").is_err());
+    }
+}
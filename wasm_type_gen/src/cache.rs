@@ -0,0 +1,393 @@
+use std::time::{Duration, SystemTime};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::lock::{OutputLock, DIR_LOCK_FILE};
+
+/// the hit and miss counters, as "{hits} {misses}". updated under a file lock,
+/// so it stays the same size no matter how many compiles record into it.
+pub const CACHE_STATS_FILE: &str = "cache_stats.txt";
+/// older versions appended a byte per hit/miss to this file. it gets removed the next time we record
+const LEGACY_CACHE_STATS_FILE: &str = "cache_stats.log";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    /// full path to the file or directory
+    pub path: String,
+    /// the name that was passed to compile_string_to_wasm,
+    /// or the directory name for shared directories like incremental/ and target/
+    pub module_name: String,
+    pub is_dir: bool,
+    /// in bytes. for directories, this is the size of everything inside of it.
+    pub size: u64,
+    /// we update the modified time of a module every time it gets used from the cache
+    pub last_used: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PruneOptions {
+    /// remove everything that hasnt been used in this long
+    pub max_age: Option<Duration>,
+    /// after removing old entries, keep removing the least recently used
+    /// entries until the cache is at most this many bytes
+    pub max_total_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PruneReport {
+    pub removed: Vec<CacheEntry>,
+    pub freed_bytes: u64,
+    pub remaining_bytes: u64,
+}
+
+fn is_internal_file(name: &str) -> bool {
+    name.ends_with(".lock") || name.ends_with(".tmp") || name == CACHE_STATS_FILE || name == LEGACY_CACHE_STATS_FILE
+}
+
+/// `libmymod.rlib` for both `libmymod.rlib` and its `libmymod.rlib.1234.txt` hash markers.
+/// they only work together: a marker without its rlib makes the compile skip building an rlib that isnt there
+fn rlib_group(file_name: &str) -> Option<&str> {
+    let end = file_name.find(".rlib")? + ".rlib".len();
+    if file_name.starts_with("lib") {
+        Some(&file_name[..end])
+    } else {
+        None
+    }
+}
+
+/// `mymod.1234.wasm` -> `mymod`, `libmymod.rlib.1234.txt` -> `mymod`
+fn module_name_of(file_name: &str) -> String {
+    let name = file_name.split('.').next().unwrap_or_default();
+    match rlib_group(file_name) {
+        Some(_) => name.strip_prefix("lib").unwrap_or(name).to_string(),
+        None => name.to_string(),
+    }
+}
+
+fn file_name_of(entry: &CacheEntry) -> String {
+    std::path::Path::new(&entry.path).file_name().map(|f| f.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+fn dir_size(path: &std::path::Path) -> u64 {
+    let readdir = match std::fs::read_dir(path) {
+        Ok(r) => r,
+        Err(_) => return 0,
+    };
+    let mut total = 0;
+    for entry in readdir.flatten() {
+        let ftype = match entry.file_type() {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+        if ftype.is_dir() {
+            total += dir_size(&entry.path());
+        } else if let Ok(meta) = entry.metadata() {
+            total += meta.len();
+        }
+    }
+    total
+}
+
+/// list every artifact in a wasm output directory (eg: wasmout/), sorted
+/// from least recently used to most recently used.
+pub fn list_artifacts(output_dir: &str) -> Result<Vec<CacheEntry>, String> {
+    let readdir = std::fs::read_dir(output_dir)
+        .map_err(|e| format!("Failed to read cache directory {output_dir}\n{:?}", e))?;
+    let mut out = vec![];
+    for entry in readdir.flatten() {
+        let base_name = entry.file_name().to_string_lossy().to_string();
+        if is_internal_file(&base_name) {
+            continue;
+        }
+        let meta = match entry.metadata() {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        let is_dir = meta.is_dir();
+        let size = if is_dir { dir_size(&entry.path()) } else { meta.len() };
+        out.push(CacheEntry {
+            path: entry.path().to_string_lossy().to_string(),
+            module_name: if is_dir { base_name } else { module_name_of(&base_name) },
+            is_dir,
+            size,
+            last_used: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        });
+    }
+    out.sort_by(|a, b| a.last_used.cmp(&b.last_used).then(a.path.cmp(&b.path)));
+    Ok(out)
+}
+
+fn remove_entry(entry: &CacheEntry) -> bool {
+    if entry.is_dir {
+        std::fs::remove_dir_all(&entry.path).is_ok()
+    } else {
+        std::fs::remove_file(&entry.path).is_ok()
+    }
+}
+
+fn lock_for_cleanup(output_dir: &str) -> Result<OutputLock, String> {
+    match OutputLock::try_exclusive(output_dir, DIR_LOCK_FILE)? {
+        Some(l) => Ok(l),
+        None => Err(format!("Cache directory {output_dir} is currently in use by a compilation. Try again later")),
+    }
+}
+
+/// remove entries by age and/or by total size. see PruneOptions.
+/// fails if another process is currently compiling into this directory.
+pub fn prune(output_dir: &str, options: PruneOptions) -> Result<PruneReport, String> {
+    prune_dirs(&[output_dir], options)
+}
+
+/// same as `prune`, but for several directories at once. max_total_size is for all of them together,
+/// so the least recently used entries are removed first no matter which directory they are in.
+pub fn prune_dirs(output_dirs: &[&str], options: PruneOptions) -> Result<PruneReport, String> {
+    let mut _locks = vec![];
+    let mut entries = vec![];
+    for output_dir in output_dirs {
+        _locks.push(lock_for_cleanup(output_dir)?);
+        entries.extend(list_artifacts(output_dir)?);
+    }
+    entries.sort_by(|a, b| a.last_used.cmp(&b.last_used).then(a.path.cmp(&b.path)));
+    let now = SystemTime::now();
+    let mut report = PruneReport::default();
+    let mut total: u64 = entries.iter().map(|e| e.size).sum();
+    let mut removed_paths: Vec<String> = vec![];
+    // entries are sorted oldest first, so for size limits we just keep
+    // removing from the front until we are under the limit.
+    for entry in entries.iter() {
+        if removed_paths.contains(&entry.path) {
+            continue;
+        }
+        let too_old = match options.max_age {
+            Some(max_age) => now.duration_since(entry.last_used).map(|age| age > max_age).unwrap_or(false),
+            None => false,
+        };
+        let too_big = match options.max_total_size {
+            Some(max) => total > max,
+            None => false,
+        };
+        if !too_old && !too_big {
+            continue;
+        }
+        // an rlib and its hash markers are removed together
+        let file_name = file_name_of(entry);
+        let dir = std::path::Path::new(&entry.path).parent();
+        let group: Vec<&CacheEntry> = match rlib_group(&file_name) {
+            Some(rlib) if !entry.is_dir => entries.iter()
+                .filter(|e| !e.is_dir && !removed_paths.contains(&e.path) && rlib_group(&file_name_of(e)) == Some(rlib))
+                .filter(|e| std::path::Path::new(&e.path).parent() == dir)
+                .collect(),
+            _ => vec![entry],
+        };
+        for e in group {
+            if remove_entry(e) {
+                total -= e.size;
+                report.freed_bytes += e.size;
+                removed_paths.push(e.path.clone());
+                report.removed.push(e.clone());
+            }
+        }
+    }
+    report.remaining_bytes = total;
+    Ok(report)
+}
+
+/// remove every artifact that belongs to a module name, including its last.wasm.
+/// its lock file is left alone, like every other lock file, since a compile might be holding it
+pub fn purge_module(output_dir: &str, module_name: &str) -> Result<PruneReport, String> {
+    let _lock = lock_for_cleanup(output_dir)?;
+    let mut report = PruneReport::default();
+    for entry in list_artifacts(output_dir)? {
+        if entry.is_dir || entry.module_name != module_name {
+            report.remaining_bytes += entry.size;
+            continue;
+        }
+        if remove_entry(&entry) {
+            report.freed_bytes += entry.size;
+            report.removed.push(entry);
+        } else {
+            report.remaining_bytes += entry.size;
+        }
+    }
+    Ok(report)
+}
+
+fn parse_cache_stats(data: &str) -> CacheStats {
+    let mut counts = data.split_whitespace().map(|n| n.parse::<u64>().ok());
+    match (counts.next().flatten(), counts.next().flatten()) {
+        (Some(hits), Some(misses)) => CacheStats { hits, misses },
+        _ => CacheStats::default(),
+    }
+}
+
+/// best effort. called by the compile functions every time they look for a cached module.
+pub fn record_cache_event(output_dir: &str, hit: bool) {
+    let _ = std::fs::remove_file(format!("{output_dir}/{LEGACY_CACHE_STATS_FILE}"));
+    let path = format!("{output_dir}/{CACHE_STATS_FILE}");
+    let mut f = match std::fs::File::options().create(true).truncate(false).read(true).write(true).open(path) {
+        Ok(f) => f,
+        Err(_) => return,
+    };
+    // other processes record into the same file. the lock is released when f is dropped
+    if f.lock().is_err() {
+        return;
+    }
+    let mut data = String::new();
+    let _ = f.read_to_string(&mut data);
+    let mut stats = parse_cache_stats(&data);
+    if hit {
+        stats.hits += 1;
+    } else {
+        stats.misses += 1;
+    }
+    if f.set_len(0).is_ok() && f.seek(SeekFrom::Start(0)).is_ok() {
+        let _ = write!(f, "{} {}", stats.hits, stats.misses);
+    }
+}
+
+/// mark a cached artifact as used just now, so that pruning keeps it around
+pub fn touch(path: &str) {
+    if let Ok(f) = std::fs::File::options().append(true).open(path) {
+        let _ = f.set_modified(SystemTime::now());
+    }
+}
+
+pub fn cache_stats(output_dir: &str) -> CacheStats {
+    let mut f = match std::fs::File::open(format!("{output_dir}/{CACHE_STATS_FILE}")) {
+        Ok(f) => f,
+        Err(_) => return CacheStats::default(),
+    };
+    let _ = f.lock_shared();
+    let mut data = String::new();
+    let _ = f.read_to_string(&mut data);
+    parse_cache_stats(&data)
+}
+
+pub fn reset_cache_stats(output_dir: &str) {
+    let _ = std::fs::remove_file(format!("{output_dir}/{CACHE_STATS_FILE}"));
+    let _ = std::fs::remove_file(format!("{output_dir}/{LEGACY_CACHE_STATS_FILE}"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_cache_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("incremental")).unwrap();
+        let dir = dir.to_string_lossy().to_string();
+        std::fs::write(format!("{dir}/a.1.wasm"), [0; 10]).unwrap();
+        std::fs::write(format!("{dir}/a.last.wasm"), [0; 10]).unwrap();
+        std::fs::write(format!("{dir}/b.2.wasm"), [0; 20]).unwrap();
+        std::fs::write(format!("{dir}/incremental/x"), [0; 5]).unwrap();
+        std::fs::write(format!("{dir}/b.lock"), "").unwrap();
+        dir
+    }
+
+    fn set_age(path: &str, secs_ago: u64) {
+        let f = std::fs::File::options().append(true).open(path).unwrap();
+        f.set_modified(SystemTime::now() - Duration::from_secs(secs_ago)).unwrap();
+    }
+
+    #[test]
+    fn lists_artifacts_oldest_first() {
+        let dir = setup("list");
+        set_age(&format!("{dir}/b.2.wasm"), 1000);
+        let entries = list_artifacts(&dir).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].module_name, "b");
+        let incremental = entries.iter().find(|e| e.module_name == "incremental").unwrap();
+        assert!(incremental.is_dir);
+        assert_eq!(incremental.size, 5);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn prunes_by_age_and_size() {
+        let dir = setup("prune");
+        set_age(&format!("{dir}/b.2.wasm"), 1000);
+        set_age(&format!("{dir}/a.1.wasm"), 500);
+        let report = prune(&dir, PruneOptions { max_age: Some(Duration::from_secs(800)), max_total_size: None }).unwrap();
+        assert_eq!(report.removed.len(), 1);
+        assert_eq!(report.freed_bytes, 20);
+        // 10 + 10 + 5 remaining. get it below 16 by removing the oldest
+        let report = prune(&dir, PruneOptions { max_age: None, max_total_size: Some(16) }).unwrap();
+        assert_eq!(report.removed[0].path, format!("{dir}/a.1.wasm"));
+        assert!(report.remaining_bytes <= 16);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn size_limit_is_for_all_dirs_together() {
+        let first = setup("prune_dirs_1");
+        let second = setup("prune_dirs_2");
+        set_age(&format!("{second}/b.2.wasm"), 1000);
+        // 45 bytes each. the oldest entry is removed first, whichever directory its in
+        let report = prune_dirs(&[&first, &second], PruneOptions { max_age: None, max_total_size: Some(60) }).unwrap();
+        assert_eq!(report.removed[0].path, format!("{second}/b.2.wasm"));
+        assert!(report.remaining_bytes <= 60);
+        let left: u64 = [&first, &second].iter().flat_map(|d| list_artifacts(d).unwrap()).map(|e| e.size).sum();
+        assert_eq!(left, report.remaining_bytes);
+        let _ = std::fs::remove_dir_all(&first);
+        let _ = std::fs::remove_dir_all(&second);
+    }
+
+    #[test]
+    fn prunes_rlibs_with_their_hash_markers() {
+        let dir = setup("prune_rlib");
+        std::fs::write(format!("{dir}/libdep.rlib"), [0; 100]).unwrap();
+        std::fs::write(format!("{dir}/libdep.rlib.55.txt"), "").unwrap();
+        // the rlib is never touched, so its always the oldest
+        set_age(&format!("{dir}/libdep.rlib"), 1000);
+        let report = prune(&dir, PruneOptions { max_age: None, max_total_size: Some(60) }).unwrap();
+        let removed: Vec<String> = report.removed.iter().map(file_name_of).collect();
+        assert_eq!(removed, vec!["libdep.rlib", "libdep.rlib.55.txt"]);
+        assert!(std::fs::File::open(format!("{dir}/a.1.wasm")).is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rlib_module_names_dont_have_the_lib_prefix() {
+        assert_eq!(module_name_of("libmymod.rlib"), "mymod");
+        assert_eq!(module_name_of("libmymod.rlib.1234.txt"), "mymod");
+        assert_eq!(module_name_of("mymod.wasm.1234.txt"), "mymod");
+        assert_eq!(module_name_of("library.1234.wasm"), "library");
+    }
+
+    #[test]
+    fn purges_module() {
+        let dir = setup("purge");
+        std::fs::write(format!("{dir}/liba.rlib"), [0; 10]).unwrap();
+        std::fs::write(format!("{dir}/liba.rlib.55.txt"), "").unwrap();
+        std::fs::write(format!("{dir}/a.lock"), "").unwrap();
+        let report = purge_module(&dir, "a").unwrap();
+        assert_eq!(report.removed.len(), 4);
+        assert!(std::fs::File::open(format!("{dir}/b.2.wasm")).is_ok());
+        // a compile might be holding it
+        assert!(std::fs::File::open(format!("{dir}/a.lock")).is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let dir = setup("stats");
+        record_cache_event(&dir, true);
+        record_cache_event(&dir, true);
+        record_cache_event(&dir, false);
+        assert_eq!(cache_stats(&dir), CacheStats { hits: 2, misses: 1 });
+        // fixed size, no matter how many events
+        for _ in 0..100 {
+            record_cache_event(&dir, true);
+        }
+        assert_eq!(std::fs::read_to_string(format!("{dir}/{CACHE_STATS_FILE}")).unwrap(), "102 1");
+        reset_cache_stats(&dir);
+        assert_eq!(cache_stats(&dir), CacheStats::default());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,673 @@
+//! Build guests as components, and run them with wasmtime's component model API.
+//! Only available with the `component-model` feature. The guest implements the world from
+//! `wit::wit_world`, so its interface is standard, and any component host can run it.
+//!
+//! Building a guest as a component (see `compile_file_to_component`):
+//! - the guest is compiled like any other, except that instead of the entrypoint ABI's glue it gets
+//!   `component_entrypoint`, which exports `wasm-entrypoint` using the canonical ABI. guest types
+//!   implement `CanonicalAbi`, which the derive generates alongside their definitions
+//! - `encode_component` then wraps the compiled module in a component that lifts that export
+//!   with the function type from the WIT world.
+//!
+//! Running a component (see `run_component`): values are passed to the guest in the same serialized
+//! form as `run_wasm` uses, and converted to and from component values using the types of the
+//! guest's `wasm-entrypoint` export. This way callers can switch between the two backends
+//! without changing their types.
+//!
+//! Note: components must be encoded in the component binary format that this version of
+//! wasmtime supports. `encode_component` uses the same wasm-encoder as wasmtime does.
+
+use std::collections::HashMap;
+
+use wasm_encoder::{
+    Alias, CanonicalFunctionSection, CanonicalOption, ComponentAliasSection, ComponentExportKind,
+    ComponentExportSection, ComponentSectionId, ComponentTypeSection, ComponentValType, ExportKind,
+    InstanceSection, ModuleArg, PrimitiveValType, RawSection,
+};
+use wasmtime::component::{Component, Linker, Type, Val};
+use wasmtime::{Engine, Store};
+use wit_parser::abi::{AbiVariant, WasmType};
+use wit_parser::{Resolve, TypeDefKind, TypeId, UnresolvedPackage, WorldItem, WorldKey};
+
+use crate::wit::{wit_world, WIT_ENTRYPOINT};
+use crate::wasmtime_runtime::deterministic_config;
+use crate::{FromBinarySlice, ToBinarySlice, WasmIncludeString, WitType, WASM_PARSING_TRAIT_STR};
+
+/// the package and world that guests built as components implement
+pub const COMPONENT_PACKAGE: &str = "wasm-type-gen:guest";
+pub const COMPONENT_WORLD: &str = "guest";
+
+const NONE_OR_OK: [u8; 4] = [255, 255, 255, 255];
+const ERR: [u8; 4] = [255, 255, 255, 254];
+
+fn take<'a>(index: &mut usize, data: &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    let out = data.get(*index..*index + len).ok_or("Unexpected end of serialized data")?;
+    *index += len;
+    Ok(out)
+}
+
+fn take_u32(index: &mut usize, data: &[u8]) -> Result<u32, String> {
+    let b = take(index, data, 4)?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// primitives are serialized as a 4 byte length followed by their big endian bytes
+fn take_primitive<const N: usize>(index: &mut usize, data: &[u8]) -> Result<[u8; N], String> {
+    *index += 4;
+    let mut out = [0; N];
+    out.copy_from_slice(take(index, data, N)?);
+    Ok(out)
+}
+
+fn put_primitive(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u32).to_be_bytes());
+    out.extend(bytes);
+}
+
+fn err(e: wasmtime::Error) -> String {
+    format!("{:?}", e)
+}
+
+/// serialized bytes (see ToBinarySlice) -> a component value of type `ty`
+pub fn decode_val(ty: &Type, index: &mut usize, data: &[u8]) -> Result<Val, String> {
+    Ok(match ty {
+        Type::Bool => Val::Bool(take_primitive::<1>(index, data)?[0] != 0),
+        Type::S8 => Val::S8(i8::from_be_bytes(take_primitive(index, data)?)),
+        Type::U8 => Val::U8(u8::from_be_bytes(take_primitive(index, data)?)),
+        Type::S16 => Val::S16(i16::from_be_bytes(take_primitive(index, data)?)),
+        Type::U16 => Val::U16(u16::from_be_bytes(take_primitive(index, data)?)),
+        Type::S32 => Val::S32(i32::from_be_bytes(take_primitive(index, data)?)),
+        Type::U32 => Val::U32(u32::from_be_bytes(take_primitive(index, data)?)),
+        Type::S64 => Val::S64(i64::from_be_bytes(take_primitive(index, data)?)),
+        Type::U64 => Val::U64(u64::from_be_bytes(take_primitive(index, data)?)),
+        Type::Float32 => Val::Float32(f32::from_be_bytes(take_primitive(index, data)?)),
+        Type::Float64 => Val::Float64(f64::from_be_bytes(take_primitive(index, data)?)),
+        Type::Char => {
+            let c = u32::from_be_bytes(take_primitive(index, data)?);
+            Val::Char(char::from_u32(c).ok_or(format!("Invalid char {c}"))?)
+        }
+        Type::String => {
+            let len = take_u32(index, data)? as usize;
+            let s = String::from_utf8_lossy(take(index, data, len)?);
+            Val::String(s.into())
+        }
+        Type::List(list) => {
+            let len = take_u32(index, data)? as usize;
+            let elem_ty = list.ty();
+            let mut values = Vec::with_capacity(len);
+            for _ in 0..len {
+                values.push(decode_val(&elem_ty, index, data)?);
+            }
+            list.new_val(values.into()).map_err(err)?
+        }
+        // tuples only come from map entries and variant payloads, which are not length prefixed
+        Type::Tuple(tuple) => {
+            let mut values = vec![];
+            for ty in tuple.types() {
+                values.push(decode_val(&ty, index, data)?);
+            }
+            tuple.new_val(values.into()).map_err(err)?
+        }
+        Type::Record(record) => {
+            *index += 4;
+            let mut values = vec![];
+            for field in record.fields() {
+                values.push((field.name, decode_val(&field.ty, index, data)?));
+            }
+            record.new_val(values).map_err(err)?
+        }
+        Type::Enum(en) => {
+            *index += 4;
+            let variant = take_u32(index, data)? as usize;
+            let name = en.names().nth(variant).ok_or(format!("Invalid enum index {variant}"))?;
+            en.new_val(name).map_err(err)?
+        }
+        Type::Variant(variant) => {
+            *index += 4;
+            let case_index = take_u32(index, data)? as usize;
+            let case = variant.cases().nth(case_index).ok_or(format!("Invalid variant index {case_index}"))?;
+            let payload = match &case.ty {
+                Some(ty) => Some(decode_val(ty, index, data)?),
+                None => None,
+            };
+            variant.new_val(case.name, payload).map_err(err)?
+        }
+        Type::Option(opt) => {
+            if data.get(*index..*index + 4) == Some(&NONE_OR_OK) {
+                *index += 4;
+                opt.new_val(None).map_err(err)?
+            } else {
+                opt.new_val(Some(decode_val(&opt.ty(), index, data)?)).map_err(err)?
+            }
+        }
+        Type::Result(res) => {
+            let tag = take(index, data, 4)?;
+            let value = if tag == NONE_OR_OK {
+                Ok(match res.ok() {
+                    Some(ty) => Some(decode_val(&ty, index, data)?),
+                    None => None,
+                })
+            } else if tag == ERR {
+                Err(match res.err() {
+                    Some(ty) => Some(decode_val(&ty, index, data)?),
+                    None => None,
+                })
+            } else {
+                return Err("Invalid result tag".into());
+            };
+            res.new_val(value).map_err(err)?
+        }
+        Type::Union(_) | Type::Flags(_) => return Err("unions and flags are not supported".into()),
+    })
+}
+
+/// prefix `contents` with its length, the way records and variants are serialized
+fn put_prefixed(out: &mut Vec<u8>, contents: Vec<u8>) {
+    out.extend((contents.len() as u32).to_be_bytes());
+    out.extend(contents);
+}
+
+/// a component value -> serialized bytes (see FromBinarySlice)
+pub fn encode_val(val: &Val, out: &mut Vec<u8>) -> Result<(), String> {
+    match val {
+        Val::Bool(b) => put_primitive(out, &[*b as u8]),
+        Val::S8(v) => put_primitive(out, &v.to_be_bytes()),
+        Val::U8(v) => put_primitive(out, &v.to_be_bytes()),
+        Val::S16(v) => put_primitive(out, &v.to_be_bytes()),
+        Val::U16(v) => put_primitive(out, &v.to_be_bytes()),
+        Val::S32(v) => put_primitive(out, &v.to_be_bytes()),
+        Val::U32(v) => put_primitive(out, &v.to_be_bytes()),
+        Val::S64(v) => put_primitive(out, &v.to_be_bytes()),
+        Val::U64(v) => put_primitive(out, &v.to_be_bytes()),
+        Val::Float32(v) => put_primitive(out, &v.to_be_bytes()),
+        Val::Float64(v) => put_primitive(out, &v.to_be_bytes()),
+        Val::Char(c) => put_primitive(out, &(*c as u32).to_be_bytes()),
+        Val::String(s) => put_primitive(out, s.as_bytes()),
+        Val::List(list) => {
+            out.extend((list.len() as u32).to_be_bytes());
+            for v in list.iter() {
+                encode_val(v, out)?;
+            }
+        }
+        Val::Tuple(tuple) => {
+            for v in tuple.values() {
+                encode_val(v, out)?;
+            }
+        }
+        Val::Record(record) => {
+            let mut contents = vec![];
+            for (_, v) in record.fields() {
+                encode_val(v, &mut contents)?;
+            }
+            put_prefixed(out, contents);
+        }
+        Val::Enum(en) => {
+            let index = en.ty().names().position(|n| n == en.discriminant()).ok_or("Invalid enum value")?;
+            put_prefixed(out, (index as u32).to_be_bytes().to_vec());
+        }
+        Val::Variant(variant) => {
+            let index = variant.ty().cases().position(|c| c.name == variant.discriminant()).ok_or("Invalid variant value")?;
+            let mut contents = (index as u32).to_be_bytes().to_vec();
+            if let Some(payload) = variant.payload() {
+                encode_val(payload, &mut contents)?;
+            }
+            put_prefixed(out, contents);
+        }
+        Val::Option(opt) => match opt.value() {
+            Some(v) => encode_val(v, out)?,
+            None => out.extend(NONE_OR_OK),
+        },
+        Val::Result(res) => {
+            let (tag, v) = match res.value() {
+                Ok(v) => (NONE_OR_OK, v),
+                Err(v) => (ERR, v),
+            };
+            out.extend(tag);
+            if let Some(v) = v {
+                encode_val(v, out)?;
+            }
+        }
+        Val::Union(_) | Val::Flags(_) => return Err("unions and flags are not supported".into()),
+    }
+    Ok(())
+}
+
+/// Same as `run_wasm`, but for a guest component that exports `wasm-entrypoint: func(input: T) -> T`.
+/// The input and output are serialized with ToBinarySlice / FromBinarySlice.
+pub fn run_component(component_data: &[u8], serialized_data: Vec<u8>) -> Result<Vec<u8>, String> {
+    let mut config = deterministic_config();
+    config.wasm_component_model(true);
+    let engine = Engine::new(&config).map_err(err)?;
+    let component = Component::from_binary(&engine, component_data)
+        .map_err(|e| format!("failed to load component {:?}", e))?;
+    let linker: Linker<()> = Linker::new(&engine);
+    let mut store = Store::new(&engine, ());
+    let instance = linker.instantiate(&mut store, &component)
+        .map_err(|e| format!("failed to instantiate component {:?}", e))?;
+    let func = instance.get_func(&mut store, WIT_ENTRYPOINT)
+        .ok_or(format!("component does not export '{WIT_ENTRYPOINT}'"))?;
+    let params = func.params(&store);
+    let results = func.results(&store);
+    if params.len() != 1 || results.len() != 1 {
+        return Err(format!("'{WIT_ENTRYPOINT}' must take exactly 1 param and return exactly 1 result"));
+    }
+    let mut index = 0;
+    let input = decode_val(&params[0], &mut index, &serialized_data)?;
+    // placeholder, gets overwritten by call
+    let mut output = [Val::Bool(false)];
+    func.call(&mut store, &[input], &mut output).map_err(|e| format!("component trapped {:?}", e))?;
+    func.post_return(&mut store).map_err(err)?;
+    let mut out = vec![];
+    encode_val(&output[0], &mut out)?;
+    Ok(out)
+}
+
+/// same as `compile_and_run_wasm`, but for a prebuilt component
+pub fn run_component_file<T: FromBinarySlice + ToBinarySlice + WasmIncludeString>(
+    path_to_component: &str,
+    data_to_pass: &T,
+) -> Result<T, String> {
+    let component_data = std::fs::read(path_to_component)
+        .map_err(|e| format!("Failed to read {path_to_component}\n{:?}", e))?;
+    let mut serialized_data = vec![];
+    data_to_pass.add_to_slice(&mut serialized_data);
+    let out = run_component(&component_data, serialized_data)?;
+    let mut index = 0;
+    T::get_from_slice(&mut index, &out).ok_or("Failed to deserialize output from component".into())
+}
+
+/// `T`'s WIT world, parsed, along with its `wasm-entrypoint` function
+fn parse_world<T: WitType>() -> Result<(Resolve, wit_parser::Function), String> {
+    let wit = wit_world::<T>(COMPONENT_PACKAGE, COMPONENT_WORLD);
+    let mut resolve = Resolve::default();
+    let unresolved = UnresolvedPackage::parse("guest.wit".as_ref(), &wit)
+        .map_err(|e| format!("invalid WIT for {}\n{:?}\n{wit}", T::wit_type_name(), e))?;
+    let pkg = resolve.push(unresolved).map_err(|e| format!("invalid WIT for {}\n{:?}", T::wit_type_name(), e))?;
+    let world = resolve.select_world(pkg, Some(COMPONENT_WORLD)).map_err(|e| format!("{:?}", e))?;
+    let func = match resolve.worlds[world].exports.get(&WorldKey::Name(WIT_ENTRYPOINT.into())) {
+        Some(WorldItem::Function(f)) => f.clone(),
+        _ => return Err(format!("world {COMPONENT_WORLD} does not export '{WIT_ENTRYPOINT}'")),
+    };
+    Ok((resolve, func))
+}
+
+/// The guest code that exports `wasm-entrypoint` with the canonical ABI, for guests built as components.
+/// Like the entrypoint ABI's glue (see `WasmIncludeString::gen_entrypoint`), it calls the guest's
+/// `wasm_main(&mut T)` and passes T back to the host. Also exports `cabi_realloc`, which the host
+/// uses to allocate the input in the guest's memory.
+pub fn component_entrypoint<T: WitType + WasmIncludeString>() -> Result<String, String> {
+    let (resolve, func) = parse_world::<T>()?;
+    let sig = resolve.wasm_signature(AbiVariant::GuestExport, &func);
+    let mut defs = vec![];
+    T::include_defs(&mut defs);
+    let ty = defs.first().map(|(name, _)| name.clone()).ok_or("type has no definition")?;
+    // core type, and how to turn it into a flat value. (see CanonicalAbi)
+    let core_ty = |t: &WasmType| match t {
+        WasmType::I32 | WasmType::Pointer | WasmType::Length => ("i32", "as u32 as u64"),
+        WasmType::I64 | WasmType::PointerOrI64 => ("i64", "as u64"),
+        WasmType::F32 => ("f32", ".to_bits() as u64"),
+        WasmType::F64 => ("f64", ".to_bits()"),
+    };
+    let params: Vec<String> = sig.params.iter().enumerate().map(|(i, t)| format!("p{i}: {}", core_ty(t).0)).collect();
+    let lift = if sig.indirect_params {
+        format!("<{ty} as CanonicalAbi>::load(p0 as usize as *const u8)")
+    } else {
+        let flat: Vec<String> = sig.params.iter().enumerate().map(|(i, t)| format!("p{i} {}", core_ty(t).1)).collect();
+        format!("<{ty} as CanonicalAbi>::lift_flat(&[{}])", flat.join(", "))
+    };
+    let (result, lower) = match sig.results.first() {
+        _ if sig.retptr => ("i32", format!(
+            "let ret = canonical_alloc(<{ty} as CanonicalAbi>::SIZE, <{ty} as CanonicalAbi>::ALIGN);\n    \
+            input.store(ret);\n    ret as usize as i32"
+        )),
+        Some(t) => {
+            let (core, from_flat) = match core_ty(t).0 {
+                "i32" => ("i32", "flat[0] as i32"),
+                "i64" => ("i64", "flat[0] as i64"),
+                "f32" => ("f32", "f32::from_bits(flat[0] as u32)"),
+                _ => ("f64", "f64::from_bits(flat[0])"),
+            };
+            (core, format!("let mut flat = vec![];\n    input.lower_flat(&mut flat);\n    {from_flat}"))
+        }
+        None => return Err(format!("'{WIT_ENTRYPOINT}' must return {}", T::wit_type_name())),
+    };
+    Ok(format!(r#"
+#[export_name = "cabi_realloc"]
+pub unsafe extern "C" fn __wasm_type_gen_cabi_realloc(old: *mut u8, old_size: usize, align: usize, new_size: usize) -> *mut u8 {{
+    if old.is_null() || old_size == 0 {{
+        return canonical_alloc(new_size, align);
+    }}
+    ::std::alloc::realloc(old, ::std::alloc::Layout::from_size_align_unchecked(old_size, align), new_size)
+}}
+
+#[export_name = "{WIT_ENTRYPOINT}"]
+pub unsafe extern "C" fn __wasm_type_gen_component_entrypoint({params}) -> {result} {{
+    let mut input = {lift};
+    let _ = wasm_main(&mut input);
+    {lower}
+}}
+"#, params = params.join(", ")))
+}
+
+/// primitive WIT types -> component value types. everything else is defined in the type section
+fn primitive_val_type(ty: &wit_parser::Type) -> Option<PrimitiveValType> {
+    use wit_parser::Type as W;
+    Some(match ty {
+        W::Bool => PrimitiveValType::Bool,
+        W::U8 => PrimitiveValType::U8,
+        W::U16 => PrimitiveValType::U16,
+        W::U32 => PrimitiveValType::U32,
+        W::U64 => PrimitiveValType::U64,
+        W::S8 => PrimitiveValType::S8,
+        W::S16 => PrimitiveValType::S16,
+        W::S32 => PrimitiveValType::S32,
+        W::S64 => PrimitiveValType::S64,
+        W::Float32 => PrimitiveValType::Float32,
+        W::Float64 => PrimitiveValType::Float64,
+        W::Char => PrimitiveValType::Char,
+        W::String => PrimitiveValType::String,
+        W::Id(_) => return None,
+    })
+}
+
+/// adds WIT types to a component's type section. a type's dependencies are always added before it
+struct ComponentTypes<'a> {
+    resolve: &'a Resolve,
+    section: ComponentTypeSection,
+    indices: HashMap<TypeId, u32>,
+}
+
+impl ComponentTypes<'_> {
+    fn val_type(&mut self, ty: &wit_parser::Type) -> Result<ComponentValType, String> {
+        match (primitive_val_type(ty), ty) {
+            (Some(p), _) => Ok(ComponentValType::Primitive(p)),
+            (None, wit_parser::Type::Id(id)) => Ok(ComponentValType::Type(self.defined_type(*id)?)),
+            (None, _) => unreachable!("only type ids arent primitives"),
+        }
+    }
+
+    fn opt_val_type(&mut self, ty: &Option<wit_parser::Type>) -> Result<Option<ComponentValType>, String> {
+        ty.as_ref().map(|t| self.val_type(t)).transpose()
+    }
+
+    /// the index of the type in the component's type index space
+    fn defined_type(&mut self, id: TypeId) -> Result<u32, String> {
+        if let Some(index) = self.indices.get(&id) {
+            return Ok(*index);
+        }
+        let def = &self.resolve.types[id];
+        match &def.kind {
+            // `use types.{my-struct}` in the world refers to the interface's type
+            TypeDefKind::Type(ty) => {
+                let index = match self.val_type(ty)? {
+                    ComponentValType::Type(index) => index,
+                    ComponentValType::Primitive(p) => {
+                        self.section.defined_type().primitive(p);
+                        self.section.len() - 1
+                    }
+                };
+                self.indices.insert(id, index);
+                return Ok(index);
+            }
+            TypeDefKind::Record(record) => {
+                let fields = record.fields.iter().map(|f| Ok((f.name.as_str(), self.val_type(&f.ty)?))).collect::<Result<Vec<_>, String>>()?;
+                self.section.defined_type().record(fields);
+            }
+            TypeDefKind::Variant(variant) => {
+                let cases = variant.cases.iter().map(|c| Ok((c.name.as_str(), self.opt_val_type(&c.ty)?, None))).collect::<Result<Vec<_>, String>>()?;
+                self.section.defined_type().variant(cases);
+            }
+            TypeDefKind::Enum(en) => {
+                self.section.defined_type().enum_type(en.cases.iter().map(|c| c.name.as_str()));
+            }
+            TypeDefKind::Tuple(tuple) => {
+                let types = tuple.types.iter().map(|t| self.val_type(t)).collect::<Result<Vec<_>, String>>()?;
+                self.section.defined_type().tuple(types);
+            }
+            TypeDefKind::List(ty) => {
+                let ty = self.val_type(ty)?;
+                self.section.defined_type().list(ty);
+            }
+            TypeDefKind::Option(ty) => {
+                let ty = self.val_type(ty)?;
+                self.section.defined_type().option(ty);
+            }
+            TypeDefKind::Result(result) => {
+                let ok = self.opt_val_type(&result.ok)?;
+                let err = self.opt_val_type(&result.err)?;
+                self.section.defined_type().result(ok, err);
+            }
+            kind => return Err(format!("WIT {} types are not supported", kind.as_str())),
+        }
+        let index = self.section.len() - 1;
+        self.indices.insert(id, index);
+        Ok(index)
+    }
+}
+
+/// Wraps a module built with `component_entrypoint` in a component that exports `wasm-entrypoint`
+/// with `T`'s function type from `wit_world`. The module can't have any imports, which means
+/// it can't use the entrypoint ABI (its `env` imports) too.
+pub fn encode_component<T: WitType>(core_module: &[u8]) -> Result<Vec<u8>, String> {
+    for payload in wasmparser::Parser::new(0).parse_all(core_module) {
+        if let wasmparser::Payload::ImportSection(imports) = payload.map_err(|e| format!("invalid wasm module {e}"))? {
+            if let Some(import) = imports.into_iter().next() {
+                let import = import.map_err(|e| format!("invalid wasm module {e}"))?;
+                return Err(format!("guests built as components cant import anything, but this one imports {}.{}", import.module, import.name));
+            }
+        }
+    }
+    let (resolve, func) = parse_world::<T>()?;
+    let mut types = ComponentTypes { resolve: &resolve, section: ComponentTypeSection::new(), indices: HashMap::new() };
+    let params = func.params.iter().map(|(name, ty)| Ok((name.as_str(), types.val_type(ty)?))).collect::<Result<Vec<_>, String>>()?;
+    let result = match &func.results {
+        wit_parser::Results::Anon(ty) => types.val_type(ty)?,
+        wit_parser::Results::Named(_) => return Err(format!("'{WIT_ENTRYPOINT}' must return a single value")),
+    };
+    types.section.function().params(params).result(result);
+    let func_type = types.section.len() - 1;
+
+    let mut component = wasm_encoder::Component::new();
+    component.section(&RawSection { id: ComponentSectionId::CoreModule.into(), data: core_module });
+    let mut instances = InstanceSection::new();
+    instances.instantiate(0, std::iter::empty::<(&str, ModuleArg)>());
+    component.section(&instances);
+    // core memory 0, core funcs 0 (realloc) and 1 (the entrypoint)
+    let mut aliases = ComponentAliasSection::new();
+    aliases.alias(Alias::CoreInstanceExport { instance: 0, kind: ExportKind::Memory, name: "memory" });
+    aliases.alias(Alias::CoreInstanceExport { instance: 0, kind: ExportKind::Func, name: "cabi_realloc" });
+    aliases.alias(Alias::CoreInstanceExport { instance: 0, kind: ExportKind::Func, name: WIT_ENTRYPOINT });
+    component.section(&aliases);
+    component.section(&types.section);
+    let mut funcs = CanonicalFunctionSection::new();
+    funcs.lift(1, func_type, [CanonicalOption::UTF8, CanonicalOption::Memory(0), CanonicalOption::Realloc(0)]);
+    component.section(&funcs);
+    let mut exports = ComponentExportSection::new();
+    exports.export(WIT_ENTRYPOINT, "", ComponentExportKind::Func, 0, None);
+    component.section(&exports);
+    Ok(component.finish())
+}
+
+/// Compiles rust source to a component that implements `T`'s world (see `wit::wit_world`).
+/// Same as `compile_string_to_wasm`, but the guest gets `component_entrypoint` instead of the entrypoint ABI's glue.
+pub fn compile_string_to_component<T: WitType + WasmIncludeString>(
+    wasm_out_name: &str,
+    file_data: &str,
+    output_dir: Option<String>,
+) -> Result<Vec<u8>, String> {
+    let mut add_to_code = T::include_in_rs_wasm();
+    add_to_code.push_str(&component_entrypoint::<T>()?);
+    add_to_code.push_str(WASM_PARSING_TRAIT_STR);
+    let build = crate::compile_string_to_wasm(wasm_out_name, file_data, Some(add_to_code), output_dir)?;
+    let module_path = crate::runnable_path(build)?;
+    let module = std::fs::read(&module_path).map_err(|e| format!("Failed to read {module_path}\n{:?}", e))?;
+    encode_component::<T>(&module)
+}
+
+/// same as `compile_string_to_component`, for a .rs file
+pub fn compile_file_to_component<T: WitType + WasmIncludeString>(path_to_rs_file: &str) -> Result<Vec<u8>, String> {
+    let path = std::path::PathBuf::from(path_to_rs_file);
+    let file_data = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {:?} file\n{:?}", path, e))?;
+    let file_stem = path.file_stem().ok_or("Failed to get .rs file name")?.to_string_lossy().to_string();
+    compile_string_to_component::<T>(&format!("{file_stem}.component"), &file_data, None)
+}
+
+/// same as `compile_and_run_wasm`, but the guest is built as a component and run with `run_component`
+pub fn compile_and_run_component<T: FromBinarySlice + ToBinarySlice + WasmIncludeString + WitType>(
+    path_to_rs_file: &str,
+    data_to_pass: &T,
+) -> Result<T, String> {
+    let component_data = compile_file_to_component::<T>(path_to_rs_file)?;
+    let mut serialized_data = vec![];
+    data_to_pass.add_to_slice(&mut serialized_data);
+    let out = run_component(&component_data, serialized_data)?;
+    let mut index = 0;
+    T::get_from_slice(&mut index, &out).ok_or("Failed to deserialize output from component".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::*;
+    use super::*;
+
+    #[derive(WasmTypeGen, Debug, PartialEq)]
+    pub struct Leaf {
+        pub label: String,
+        pub weight: f32,
+    }
+
+    #[derive(WasmTypeGen, Debug, PartialEq)]
+    pub enum Color {
+        Red,
+        Green,
+    }
+
+    #[derive(WasmTypeGen, Debug, PartialEq)]
+    pub enum Shape {
+        Empty,
+        Circle(f64),
+        Rect { w: u32, h: u16 },
+    }
+
+    #[derive(WasmTypeGen, Debug, PartialEq)]
+    pub struct Everything {
+        pub name: String,
+        pub flag: bool,
+        pub small: i8,
+        pub big: u64,
+        pub size: usize,
+        pub letter: char,
+        pub color: Color,
+        pub shapes: Vec<Shape>,
+        pub leaves: Vec<Leaf>,
+        pub maybe: Option<Leaf>,
+        pub outcome: Result<u8, String>,
+        pub lookup: std::collections::HashMap<String, i64>,
+        pub pair: [u16; 2],
+    }
+
+    const EVERYTHING_GUEST: &str = r#"
+        use super::*;
+
+        pub fn wasm_main(obj: &mut Everything) {
+            obj.name.push_str(" from a component");
+            obj.flag = !obj.flag;
+            obj.small -= 1;
+            obj.big *= 2;
+            obj.size += 1;
+            obj.letter = 'é';
+            obj.color = Color::Green;
+            obj.shapes.push(Shape::Rect { w: obj.shapes.len() as u32, h: 7 });
+            let total: f32 = obj.leaves.iter().map(|l| l.weight).sum();
+            obj.leaves.push(Leaf { label: "total".into(), weight: total });
+            obj.maybe = None;
+            obj.outcome = Err(format!("got {:?}", obj.outcome));
+            obj.lookup.insert("added".into(), -1);
+            obj.pair.reverse();
+        }
+    "#;
+
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_component_{name}_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        dir.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn runs_guests_built_as_components() {
+        let dir = test_dir("everything");
+        let component = compile_string_to_component::<Everything>("everything", EVERYTHING_GUEST, Some(dir.clone())).unwrap();
+        let features = wasmparser::WasmFeatures { component_model: true, ..Default::default() };
+        wasmparser::Validator::new_with_features(features).validate_all(&component).unwrap();
+        let item = Everything {
+            name: "hello".into(),
+            flag: true,
+            small: -5,
+            big: u64::MAX / 4,
+            size: 41,
+            letter: 'a',
+            color: Color::Red,
+            shapes: vec![Shape::Empty, Shape::Circle(1.5), Shape::Rect { w: 3, h: 4 }],
+            leaves: vec![Leaf { label: "a".into(), weight: 0.5 }, Leaf { label: "b".into(), weight: 2.0 }],
+            maybe: Some(Leaf { label: "c".into(), weight: 1.0 }),
+            outcome: Ok(3),
+            lookup: HashMap::from([("x".to_string(), 10), ("y".to_string(), i64::MIN)]),
+            pair: [1, 2],
+        };
+        let mut serialized = vec![];
+        item.add_to_slice(&mut serialized);
+        let out = run_component(&component, serialized).unwrap();
+        let out = Everything::get_from_slice(&mut 0, &out).unwrap();
+        assert_eq!(out, Everything {
+            name: "hello from a component".into(),
+            flag: false,
+            small: -6,
+            big: u64::MAX / 4 * 2,
+            size: 42,
+            letter: 'é',
+            color: Color::Green,
+            shapes: vec![Shape::Empty, Shape::Circle(1.5), Shape::Rect { w: 3, h: 4 }, Shape::Rect { w: 3, h: 7 }],
+            leaves: vec![
+                Leaf { label: "a".into(), weight: 0.5 },
+                Leaf { label: "b".into(), weight: 2.0 },
+                Leaf { label: "total".into(), weight: 2.5 },
+            ],
+            maybe: None,
+            outcome: Err("got Ok(3)".into()),
+            lookup: HashMap::from([("x".to_string(), 10), ("y".to_string(), i64::MIN), ("added".to_string(), -1)]),
+            pair: [2, 1],
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[derive(WasmTypeGen, Debug, PartialEq)]
+    pub struct Counter {
+        pub count: u32,
+    }
+
+    #[test]
+    fn small_types_are_passed_as_flat_values() {
+        // 1 flat value, so its passed directly rather than through memory
+        let glue = component_entrypoint::<Counter>().unwrap();
+        assert!(glue.contains("(p0: i32) -> i32"), "{glue}");
+        let dir = test_dir("counter");
+        let guest = "use super::*; pub fn wasm_main(c: &mut Counter) { c.count += 1; }";
+        let component = compile_string_to_component::<Counter>("counter", guest, Some(dir.clone())).unwrap();
+        let out = run_component(&component, Counter { count: 41 }.to_binary_slice()).unwrap();
+        assert_eq!(Counter::from_binary_slice(out), Some(Counter { count: 42 }));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn entrypoint_abi_modules_arent_components() {
+        let wasm = wat::parse_str(r#"
+            (module
+                (import "env" "get_entrypoint_alloc_size" (func (result i32)))
+                (memory (export "memory") 1)
+                (func (export "wasm_entrypoint") (result i32) (i32.const 0)))
+        "#).unwrap();
+        let err = encode_component::<Counter>(&wasm).unwrap_err();
+        assert!(err.contains("env.get_entrypoint_alloc_size"), "{err}");
+    }
+}
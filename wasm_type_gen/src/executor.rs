@@ -0,0 +1,169 @@
+//! Run the same guest module over many inputs, spread across threads.
+//!
+//! The module is loaded and linked once, so each input only pays for creating an instance.
+//! With wasmtime that's cheapest with the pooling allocator: see `WasmtimeRuntime::pooling`.
+
+use std::sync::{Mutex, atomic::{AtomicUsize, Ordering}};
+
+use crate::{FromBinarySlice, ToBinarySlice};
+use crate::runtime::{run_linked, DefaultRuntime, WasmRuntime};
+
+pub struct BatchExecutor<'a, R: WasmRuntime> {
+    runtime: &'a R,
+    linked: R::Linked,
+    max_workers: usize,
+}
+
+impl<'a, R> BatchExecutor<'a, R>
+where
+    R: WasmRuntime + Sync,
+    R::Linked: Sync,
+{
+    /// load and link `wasm_data` on `runtime`. inputs will be run on at most `max_workers`
+    /// threads at a time. If `max_workers` is 0 we use the number of available cpus.
+    /// Either way, its capped at the number of instances the runtime allows (see `WasmRuntime::max_concurrency`),
+    /// otherwise the extra workers would fail to instantiate.
+    pub fn new(runtime: &'a R, wasm_data: &[u8], max_workers: usize) -> Result<Self, String> {
+        let module = runtime.load(wasm_data)?;
+        let linked = runtime.prelink(&module)?;
+        let mut max_workers = if max_workers == 0 {
+            std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+        } else {
+            max_workers
+        };
+        if let Some(limit) = runtime.max_concurrency() {
+            max_workers = max_workers.min(limit.max(1));
+        }
+        Ok(Self { runtime, linked, max_workers })
+    }
+
+    /// run the module once, on the current thread
+    pub fn run_one(&self, serialized_data: Vec<u8>) -> Result<Vec<u8>, String> {
+        run_linked(self.runtime, &self.linked, serialized_data)
+    }
+
+    /// run the module once per input. Returns one result per input, in the same order as the input.
+    /// A guest that fails (traps, or fails to deserialize its input) only fails its own item.
+    pub fn run(&self, inputs: &[Vec<u8>]) -> Vec<Result<Vec<u8>, String>> {
+        let num_workers = self.max_workers.min(inputs.len());
+        let results: Vec<Mutex<Option<_>>> = inputs.iter().map(|_| Mutex::new(None)).collect();
+        let next = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..num_workers {
+                s.spawn(|| loop {
+                    let n = next.fetch_add(1, Ordering::SeqCst);
+                    let input = match inputs.get(n) {
+                        Some(i) => i,
+                        None => break,
+                    };
+                    let res = self.run_one(input.clone());
+                    if let Ok(mut slot) = results[n].lock() {
+                        *slot = Some(res);
+                    }
+                });
+            }
+        });
+        results.into_iter().map(|m| {
+            m.into_inner().unwrap_or(None).unwrap_or_else(|| Err("Worker failed to produce a result".into()))
+        }).collect()
+    }
+
+    /// same as `run`, but serializes the inputs and deserializes the outputs
+    pub fn run_items<T: ToBinarySlice + FromBinarySlice>(&self, items: &[T]) -> Vec<Result<T, String>> {
+        let inputs: Vec<Vec<u8>> = items.iter().map(|item| {
+            let mut data = vec![];
+            item.add_to_slice(&mut data);
+            data
+        }).collect();
+        self.run(&inputs).into_iter().map(|res| {
+            let out = res?;
+            let mut index = 0;
+            T::get_from_slice(&mut index, &out).ok_or("Failed to deserialize output from wasm guest".into())
+        }).collect()
+    }
+}
+
+/// run `wasm_data` once per input on the default runtime. see `BatchExecutor::run`.
+/// Errors if the module can't be loaded.
+pub fn run_wasm_batch(
+    wasm_data: &[u8],
+    inputs: &[Vec<u8>],
+    max_workers: usize,
+) -> Result<Vec<Result<Vec<u8>, String>>, String> {
+    let runtime = DefaultRuntime::default();
+    let executor = BatchExecutor::new(&runtime, wasm_data, max_workers)?;
+    Ok(executor.run(inputs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// increments the first byte of its input. fails on empty input, and traps if the first byte is 0
+    const INCREMENT_WAT: &str = r#"
+        (module
+            (import "env" "get_entrypoint_alloc_size" (func $size (result i32)))
+            (import "env" "get_entrypoint_data" (func $get (param i32 i32)))
+            (import "env" "set_entrypoint_data" (func $set (param i32 i32)))
+            (memory (export "memory") 1)
+            (func (export "wasm_entrypoint") (result i32)
+                (local $len i32)
+                (local.set $len (call $size))
+                (if (i32.eqz (local.get $len)) (then (return (i32.const 1))))
+                (call $get (i32.const 0) (local.get $len))
+                (if (i32.eqz (i32.load8_u (i32.const 0))) (then unreachable))
+                (i32.store8 (i32.const 0) (i32.add (i32.load8_u (i32.const 0)) (i32.const 1)))
+                (call $set (i32.const 0) (local.get $len))
+                (i32.const 0))
+        )
+    "#;
+
+    fn check_executor<R: WasmRuntime + Sync>(runtime: &R) where R::Linked: Sync {
+        let wasm = wat::parse_str(INCREMENT_WAT).unwrap();
+        let executor = BatchExecutor::new(runtime, &wasm, 4).unwrap();
+        let mut inputs: Vec<Vec<u8>> = (0..500u32).map(|i| vec![(i % 200) as u8 + 1, (i % 256) as u8]).collect();
+        inputs[7] = vec![];
+        inputs[300] = vec![0, 1];
+        let results = executor.run(&inputs);
+        assert_eq!(results.len(), inputs.len());
+        for (i, (input, res)) in inputs.iter().zip(&results).enumerate() {
+            match i {
+                7 => assert!(res.as_ref().unwrap_err().contains("Failed to deserialize")),
+                300 => assert!(res.as_ref().unwrap_err().contains("trapped")),
+                _ => assert_eq!(res.as_ref().unwrap(), &vec![input[0] + 1, input[1]]),
+            }
+        }
+    }
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn runs_batches_on_wasmtime() {
+        check_executor(&crate::runtime::WasmtimeRuntime::default());
+        check_executor(&crate::runtime::WasmtimeRuntime::pooling(8, 16).unwrap());
+    }
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn workers_stay_within_the_pool() {
+        // fewer instances than workers. without the cap, the extra workers fail to instantiate
+        let runtime = crate::runtime::WasmtimeRuntime::pooling(2, 16).unwrap();
+        let wasm = wat::parse_str(INCREMENT_WAT).unwrap();
+        let executor = BatchExecutor::new(&runtime, &wasm, 16).unwrap();
+        assert_eq!(executor.max_workers, 2);
+        let inputs: Vec<Vec<u8>> = (0..200u32).map(|i| vec![(i % 200) as u8 + 1]).collect();
+        assert!(executor.run(&inputs).iter().all(|r| r.is_ok()));
+    }
+
+    #[cfg(feature = "wasmi")]
+    #[test]
+    fn runs_batches_on_wasmi() {
+        check_executor(&crate::runtime::WasmiRuntime::default());
+    }
+
+    #[test]
+    fn load_errors_fail_the_whole_batch() {
+        assert!(run_wasm_batch(b"not wasm", &[vec![1]], 0).is_err());
+        let wasm = wat::parse_str(INCREMENT_WAT).unwrap();
+        assert!(run_wasm_batch(&wasm, &[], 0).unwrap().is_empty());
+    }
+}
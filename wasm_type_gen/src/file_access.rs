@@ -0,0 +1,142 @@
+//! Capability based file reads for guests.
+//!
+//! Guests can't touch the filesystem. Instead, the crate that runs them allow-lists which files
+//! each module may read in its `wasm_type_gen.toml` (next to its Cargo.toml):
+//! ```toml
+//! [modules.mymod]
+//! # files, or directories (every file in them, recursively). relative to the crate's directory
+//! allow_read = ["schema.sql", "queries/"]
+//! ```
+//! The host reads those files before running the module and passes their contents along with
+//! the module's input. Entries that resolve to anything outside of the crate's directory
+//! (via `..`, absolute paths, or symlinks) are rejected.
+//!
+//! Note: cargo can only be told to watch files, not directories. Changing a file that a module
+//! read makes cargo re-run it, but adding a file to an allow-listed directory (or removing one) doesnt.
+//! The module only sees the new file once something else triggers a rebuild, eg: `touch wasm_type_gen.toml`.
+//! List the files individually if that matters.
+
+use std::path::{Path, PathBuf};
+
+use crate::build_mode::BUILD_MODE_CONFIG_FILE;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedFile {
+    /// relative to the crate's directory, with `/` separators. this is the path guests use to read it
+    pub path: String,
+    /// the canonical path on disk
+    pub full_path: PathBuf,
+}
+
+/// `allow_read` of `[modules.{module_name}]` in a config file's contents
+pub fn allowed_reads_from_config(contents: &str, module_name: &str) -> Result<Vec<String>, String> {
+    let table: toml::Table = contents.parse().map_err(|e| format!("Failed to parse {BUILD_MODE_CONFIG_FILE}\n{e}"))?;
+    let allow_read = table.get("modules")
+        .and_then(|m| m.get(module_name))
+        .and_then(|m| m.get("allow_read"));
+    let entries = match allow_read {
+        None => return Ok(vec![]),
+        Some(toml::Value::Array(a)) => a,
+        Some(v) => return Err(format!("{BUILD_MODE_CONFIG_FILE}: modules.{module_name}.allow_read must be an array of paths, found {v}")),
+    };
+    entries.iter().map(|e| match e {
+        toml::Value::String(s) => Ok(s.clone()),
+        v => Err(format!("{BUILD_MODE_CONFIG_FILE}: modules.{module_name}.allow_read must be an array of paths, found {v}")),
+    }).collect()
+}
+
+/// the path a guest should use for `full_path`, given the canonical `base_dir`
+fn guest_path(base_dir: &Path, full_path: &Path) -> String {
+    let relative = full_path.strip_prefix(base_dir).unwrap_or(full_path);
+    relative.components().map(|c| c.as_os_str().to_string_lossy().to_string()).collect::<Vec<_>>().join("/")
+}
+
+fn collect_files(base_dir: &Path, path: &Path, out: &mut Vec<AllowedFile>) -> Result<(), String> {
+    let full_path = path.canonicalize().map_err(|e| format!("Failed to resolve allow_read path {:?}\n{e}", path))?;
+    if !full_path.starts_with(base_dir) {
+        return Err(format!("allow_read path {:?} is outside of the crate directory {:?}", path, base_dir));
+    }
+    if full_path.is_dir() {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(&full_path)
+            .map_err(|e| format!("Failed to read directory {:?}\n{e}", full_path))?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .collect();
+        // so that guests always see files in the same order
+        entries.sort();
+        for entry in entries {
+            collect_files(base_dir, &entry, out)?;
+        }
+    } else if !out.iter().any(|f| f.full_path == full_path) {
+        out.push(AllowedFile { path: guest_path(base_dir, &full_path), full_path });
+    }
+    Ok(())
+}
+
+/// resolve allow-list entries relative to `base_dir`.
+/// Errors if an entry doesn't exist, or is outside of `base_dir`.
+pub fn resolve_allowed_reads(base_dir: &str, entries: &[String]) -> Result<Vec<AllowedFile>, String> {
+    let base = Path::new(base_dir).canonicalize().map_err(|e| format!("Failed to resolve {base_dir}\n{e}"))?;
+    let mut out = vec![];
+    for entry in entries {
+        if Path::new(entry).is_absolute() {
+            return Err(format!("allow_read paths must be relative to the crate directory, found {entry:?}"));
+        }
+        collect_files(&base, &base.join(entry), &mut out)?;
+    }
+    Ok(out)
+}
+
+/// the files `module_name` may read, according to the `wasm_type_gen.toml` of the crate being compiled
+pub fn allowed_reads(module_name: &str) -> Result<Vec<AllowedFile>, String> {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or(".".into());
+    let contents = match std::fs::read_to_string(format!("{manifest_dir}/{BUILD_MODE_CONFIG_FILE}")) {
+        Ok(c) => c,
+        Err(_) => return Ok(vec![]),
+    };
+    let entries = allowed_reads_from_config(&contents, module_name)?;
+    resolve_allowed_reads(&manifest_dir, &entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_allow_lists() {
+        let config = "build_mode = \"build\"\n[modules.a]\nallow_read = [\"x.sql\", \"dir/\"]\n[modules.b]\nallow_read = \"x\"";
+        assert_eq!(allowed_reads_from_config(config, "a").unwrap(), vec!["x.sql", "dir/"]);
+        assert!(allowed_reads_from_config(config, "b").is_err());
+        assert!(allowed_reads_from_config(config, "c").unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_are_confined_to_the_base_dir() {
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_file_access_{}", std::process::id()));
+        let base = dir.join("crate");
+        std::fs::create_dir_all(base.join("queries/nested")).unwrap();
+        std::fs::write(dir.join("secret.txt"), "no").unwrap();
+        std::fs::write(base.join("schema.sql"), "create table a;").unwrap();
+        std::fs::write(base.join("queries/b.sql"), "").unwrap();
+        std::fs::write(base.join("queries/nested/a.sql"), "").unwrap();
+        let base_str = base.to_string_lossy().to_string();
+        let resolve = |entries: &[&str]| {
+            let entries: Vec<String> = entries.iter().map(|s| s.to_string()).collect();
+            resolve_allowed_reads(&base_str, &entries)
+        };
+
+        let files = resolve(&["./schema.sql", "queries", "queries/b.sql"]).unwrap();
+        let paths: Vec<&str> = files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, vec!["schema.sql", "queries/b.sql", "queries/nested/a.sql"]);
+
+        assert!(resolve(&["../secret.txt"]).unwrap_err().contains("outside of the crate directory"));
+        assert!(resolve(&["queries/../../secret.txt"]).is_err());
+        assert!(resolve(&[&dir.join("secret.txt").to_string_lossy()]).is_err());
+        assert!(resolve(&["missing.sql"]).is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("secret.txt"), base.join("link.txt")).unwrap();
+            assert!(resolve(&["link.txt"]).is_err());
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
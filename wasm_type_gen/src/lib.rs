@@ -1,13 +1,65 @@
-use std::{path::PathBuf, process::{Command, Stdio}, io::{Write, Read}, collections::{HashSet}, format};
+use std::{path::PathBuf, process::Stdio, io::{Write, Read}, collections::{HashSet}, format};
 
 use wasm_type_gen_derive::{generate_parsing_traits};
 pub use wasm_type_gen_derive::WasmTypeGen;
 pub use wasm_type_gen_derive::{output_and_stringify, output_and_stringify_basic, output_and_stringify_basic_const};
-use wasmtime::*;
+
+pub mod source_rewrite;
+pub mod lock;
+pub mod batch;
+pub mod artifact;
+pub mod cache;
+pub mod toolchain;
+pub mod build_mode;
+pub mod optimize;
+pub mod prebuilt;
+pub mod wit;
+pub mod runtime;
+pub mod executor;
+pub mod file_access;
+#[cfg(feature = "wasmtime")]
+pub mod wasmtime_runtime;
+#[cfg(feature = "wasmi")]
+pub mod wasmi_runtime;
+#[cfg(feature = "component-model")]
+pub mod component;
+pub use source_rewrite::inject_host_code;
+pub use lock::{OutputLock, CompileLock, DIR_LOCK_FILE};
+pub use artifact::{write_atomic, copy_atomic, validate_cached_wasm};
+pub use toolchain::{preflight, Toolchain, ToolchainError, rustc_command, cargo_command, rustfmt_command};
+pub use batch::{CompileJob, CompileConfig, compile_batch};
+pub use build_mode::{BuildMode, build_mode, BUILD_MODE_ENV};
+pub use optimize::{OptimizeConfig, optimize_wasm_file, optimize_build};
+pub use prebuilt::{GuestKind, load_prebuilt_module, check_entrypoint_abi};
+pub use wit::{wit_world, wit_definitions};
+pub use runtime::{WasmRuntime, DefaultRuntime, run_wasm_with, run_module, run_linked};
+pub use executor::{BatchExecutor, run_wasm_batch};
+pub use file_access::{AllowedFile, allowed_reads};
 
 generate_parsing_traits!();
 
-pub fn compile_file_to_wasm(s: &str, add_to_code: Option<String>) -> Result<String, String> {
+/// compiles a .rs file to a .wasm file. `.wat` files get assembled instead, and `.wasm` files
+/// are used as is (`add_to_code` is ignored for both). See the prebuilt module for the ABI they must implement.
+pub fn compile_file_to_wasm(s: &str, add_to_code: Option<String>) -> Result<WasmBuild, String> {
+    match GuestKind::from_path(s) {
+        GuestKind::Rust => {}
+        GuestKind::Wasm => {
+            load_prebuilt_module(s)?;
+            return Ok(WasmBuild::Fresh(s.to_string()));
+        }
+        GuestKind::Wat => {
+            let data = load_prebuilt_module(s)?;
+            let file_stem = PathBuf::from(s).file_stem().ok_or("Failed to get .wat file name")?.to_string_lossy().to_string();
+            let hash = adler32::adler32(data.as_slice()).unwrap_or(0);
+            let wasm_out_dir = resolve_wasm_out_dir(None);
+            let module_path = format!("{wasm_out_dir}/{file_stem}.{hash}.wasm");
+            if !validate_cached_wasm(&module_path) {
+                let _ = std::fs::create_dir_all(&wasm_out_dir);
+                write_atomic(&module_path, data)?;
+            }
+            return Ok(WasmBuild::Fresh(module_path));
+        }
+    }
     let path = PathBuf::from(s);
     let file_data = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {:?} file\n{:?}", path, e))?;
 
@@ -16,11 +68,15 @@ pub fn compile_file_to_wasm(s: &str, add_to_code: Option<String>) -> Result<Stri
 }
 
 pub fn format_file_contents(data: &str) -> Result<String, String> {
-    let mut cmd = Command::new("rustfmt")
+    let mut cmd = rustfmt_command()
         .stdin(Stdio::piped())
         .stderr(Stdio::piped())
         .stdout(Stdio::piped())
-        .spawn().map_err(|e| format!("Failed to invoke rustfmt {:?}", e))?;
+        .spawn().map_err(|e| toolchain::ToolchainError::MissingTool {
+            tool: toolchain::Tool::Rustfmt,
+            path: toolchain::tool_path(toolchain::Tool::Rustfmt),
+            error: e.to_string(),
+        }.to_string())?;
     if let Some(mut stdin) = cmd.stdin.take() {
         stdin.write_all(data.as_bytes()).map_err(|e| format!("Failed to write stdin for rustc invocation\n{:?}", e))?;
     }
@@ -52,6 +108,14 @@ pub fn try_find_existing_extern_crate_file(output_dir: &str, dep_name: &str) ->
     Err(expected_file)
 }
 
+fn missing_cargo_error(e: std::io::Error) -> String {
+    toolchain::ToolchainError::MissingTool {
+        tool: toolchain::Tool::Cargo,
+        path: toolchain::tool_path(toolchain::Tool::Cargo),
+        error: e.to_string(),
+    }.to_string()
+}
+
 /// given the name of a cargo dependency, use cargo rustc
 /// to compile that to a wasm .rlib.
 /// returns final path to wasm_deps_path + compiled rlib name
@@ -77,28 +141,28 @@ pub fn compile_extern_crate(
     // sigh.. when testing i found that rustc isnt able to emit the file name and also compile it for some reason.
     // so this needs to be 2 steps :/
     // we first get the file name.
-    let cmd_resp = Command::new("cargo")
+    let cmd_resp = cargo_command()
         .args(&[
             "-q", "rustc", "--lib", "--package", dep_name, "--target", "wasm32-unknown-unknown",
             "--target-dir", target_dir,
             "--",
             "--emit=link", "--crate-type=rlib", "--print=file-names"
         ])
-        .output().map_err(|e| format!("Failed to compile dependency {}\n{:?}", dep_name, e))?;
+        .output().map_err(|e| format!("Failed to compile dependency {}\n{}", dep_name, missing_cargo_error(e)))?;
     if !cmd_resp.status.success() {
         let err_str = String::from_utf8_lossy(&cmd_resp.stderr).to_string();
         return Err(format!("Failed to compile dependency {}\n{}", dep_name, err_str));
     }
     let file_name = String::from_utf8_lossy(&cmd_resp.stdout).to_string();
     // and then we actually compile it
-    let cmd_resp = Command::new("cargo")
+    let cmd_resp = cargo_command()
         .args(&[
             "-q", "rustc", "--lib", "--package", dep_name, "--target", "wasm32-unknown-unknown",
             "--target-dir", target_dir,
             "--",
             "--emit=link", "--crate-type=rlib",
         ])
-        .output().map_err(|e| format!("Failed to compile dependency {}\n{:?}", dep_name, e))?;
+        .output().map_err(|e| format!("Failed to compile dependency {}\n{}", dep_name, missing_cargo_error(e)))?;
     if !cmd_resp.status.success() {
         let err_str = String::from_utf8_lossy(&cmd_resp.stderr).to_string();
         return Err(format!("Failed to compile dependency {}\n{}", dep_name, err_str));
@@ -109,16 +173,16 @@ pub fn compile_extern_crate(
     // changed since the last time we built it...
     let location = format!("{}/{}", wasm_deps_path, file_name.trim());
     // best effort
-    let _ = std::fs::write(cache_file_info, &location);
+    let _ = write_atomic(&cache_file_info, &location);
     Ok(location)
 }
 
 /// using `cargo metadata` we can get the output target directory
 pub fn get_target_dir() -> Result<String, String> {
     // cargo -q metadata --format-version=1
-    let cmd_resp = Command::new("cargo")
+    let cmd_resp = cargo_command()
         .args(&["-q", "metadata", "--format-version=1"])
-        .output().map_err(|e| format!("Failed to get cargo metadata\n{:?}", e))?;
+        .output().map_err(|e| format!("Failed to get cargo metadata\n{}", missing_cargo_error(e)))?;
     if !cmd_resp.status.success() {
         let err_str = String::from_utf8_lossy(&cmd_resp.stderr).to_string();
         return Err(format!("Failed to get cargo metadata\n{}", err_str));
@@ -174,14 +238,15 @@ pub fn compile_strings_to_wasm_with_extern_crates(
     }
     let last_index = len - 1;
     let mut return_string = "".to_string();
+    let lock = CompileLock::acquire(output_dir, &data[last_index].0).ok();
 
     let mut dependency_has_changed = false;
 
-    // if this is being compiled by rust analyzer, its for a keystroke, and
-    // not something we usually want to fully compile. When the user saves the file, this
-    // env var is (hopefully!) not present, and then we will run a normal compile.
-    // otherwise, if we detect this, AND we have a last.wasm file, then just return that
-    if std::env::var("RUST_ANALYZER_INTERNALS_DO_NOT_USE").is_ok() {
+    // if this is being compiled by an IDE, its for a keystroke, and
+    // not something we usually want to fully compile. When the user saves the file,
+    // a normal compile will happen. (see build_mode for how this is detected)
+    // if we have the final .wasm file, then just return that
+    if !build_mode()?.should_compile() {
         // try to see if we have the final .wasm file, if so, just return that without
         // compiling. if not, then continue and compile.
         if let Some((wasm_name, _)) = data.last() {
@@ -250,8 +315,20 @@ pub fn compile_strings_to_wasm_with_extern_crates(
         if !dependency_has_changed {
             match std::fs::File::open(&hash_file_path) {
                 // it exists, skip compilation. if its a wasm, we should return the path.
+                // but only if the wasm is actually usable, otherwise rebuild it.
+                Ok(_) if ext == "wasm" && !validate_cached_wasm(&output_path) => {
+                    dependency_has_changed = true;
+                }
+                // the marker alone isnt enough, the rlib might have been removed
+                Ok(_) if ext == "rlib" && !std::path::Path::new(&output_path).exists() => {
+                    dependency_has_changed = true;
+                }
                 Ok(_) => {
-                    if ext == "wasm" { return Ok(output_path) }
+                    if ext == "wasm" {
+                        cache::touch(&output_path);
+                        cache::record_cache_event(output_dir, true);
+                        return Ok(output_path)
+                    }
                     continue;
                 }
                 Err(_) => {
@@ -262,6 +339,10 @@ pub fn compile_strings_to_wasm_with_extern_crates(
             }
         }
         if dependency_has_changed {
+            if ext == "wasm" {
+                cache::record_cache_event(output_dir, false);
+            }
+            toolchain::preflight_cached()?;
             delete_prefixes.insert(format!("{out_prefix}{name}"));
             delete_exclusions.push(output_path.clone());
             delete_exclusions.push(hash_file_path.clone());
@@ -289,18 +370,27 @@ pub fn compile_strings_to_wasm_with_extern_crates(
         for extra in &extra_link_args {
             args.push(extra);
         }
-        // these should always be at the end:
+        // these should always be at the end.
+        // we compile to a temporary file first, and then rename it
+        // so other processes never see a partially written artifact.
+        let tmp_output_name = artifact::tmp_path_for(&output_name);
         args.push(&"-o");
-        args.push(&output_name);
+        args.push(&tmp_output_name);
         args.push(&"-");
 
-        compile_single_file(&args, output_dir, &contents)?;
+        if let Err(e) = compile_single_file(&args, output_dir, contents) {
+            let _ = std::fs::remove_file(format!("{}/{}", output_dir, tmp_output_name));
+            return Err(e);
+        }
+        artifact::rename_atomic(&format!("{}/{}", output_dir, tmp_output_name), &output_path)?;
         // save a hash file so we can avoid compilation the next time:
-        let _ = std::fs::write(hash_file_path, "");
+        let _ = write_atomic(&hash_file_path, "");
         return_string = output_path;
     }
 
-    // try to delete all past compiled files:
+    // try to delete all past compiled files. we need to give up our
+    // lock first, since cleanup needs exclusive access to the directory.
+    drop(lock);
     delete_old_artifacts(output_dir, delete_prefixes, delete_exclusions);
 
     Ok(return_string)
@@ -328,6 +418,9 @@ pub fn compile_strings_to_wasm(
 }
 
 
+/// delete every file in output_dir that starts with one of the prefixes,
+/// unless its one of the exclusions. This needs exclusive access to the output directory, so if
+/// another process is currently compiling into it, cleanup is skipped. It will happen on the next build.
 pub fn delete_old_artifacts(
     output_dir: &str,
     delete_prefixes: HashSet<String>,
@@ -336,6 +429,10 @@ pub fn delete_old_artifacts(
     if delete_exclusions.is_empty() {
         return;
     }
+    let _lock = match OutputLock::try_exclusive(output_dir, DIR_LOCK_FILE) {
+        Ok(Some(l)) => l,
+        _ => return,
+    };
 
     let readdir = match std::fs::read_dir(output_dir) {
         Ok(r) => r,
@@ -353,6 +450,12 @@ pub fn delete_old_artifacts(
             Err(_) => continue,
         };
         let base_name = entry.file_name().to_string_lossy().to_string();
+        // lock files must never be deleted, otherwise a process holding the lock and a process
+        // that recreates the file would both think they have the lock.
+        // tmp files belong to compilations in progress.
+        if base_name.ends_with(".lock") || base_name.ends_with(".tmp") {
+            continue;
+        }
         if ftype.is_file() {
             filenames.push((base_name, entry.path().to_string_lossy().to_string()));
         }
@@ -391,13 +494,16 @@ pub fn compile_single_file(
     // }
     // println!("=> {out_str}");
 
-    let mut cmd = Command::new("rustc")
+    let mut cmd = rustc_command()
         .current_dir(output_dir)
         .args(args)
         .stdin(Stdio::piped())
         .stderr(Stdio::piped())
         .stdout(Stdio::piped())
-        .spawn().map_err(|e| format!("Failed to invoke rustc {:?}", e))?;
+        .spawn().map_err(|e| match toolchain::preflight_cached() {
+            Err(preflight_err) => preflight_err.to_string(),
+            Ok(_) => format!("Failed to invoke rustc {:?}", e),
+        })?;
     if let Some(mut stdin) = cmd.stdin.take() {
         stdin.write_all(file_data.as_bytes()).map_err(|e| format!("Failed to write stdin for rustc invocation\n{:?}", e))?;
     } else {
@@ -420,81 +526,189 @@ pub fn compile_single_file(
 /// CARGO_MANIFEST_DIR/output_dir
 /// If output_dir starts with a slash, then we just output directly to:
 /// output_dir
+/// Otherwise we output to CARGO_MANIFEST_DIR/wasmout
+pub fn resolve_wasm_out_dir(output_dir: Option<String>) -> String {
+    match output_dir {
+        Some(s) => if s.starts_with('/') {
+            s
+        } else {
+            let wasm_output_base = std::env::var("CARGO_MANIFEST_DIR").unwrap_or(".".into());
+            format!("{wasm_output_base}/{s}")
+        }
+        None => {
+            let wasm_output_base = std::env::var("CARGO_MANIFEST_DIR").unwrap_or(".".into());
+            format!("{}/wasmout", wasm_output_base)
+        }
+    }
+}
+
+/// the result of compiling a guest module. Only `Fresh` reflects the code that was passed in.
+/// The other variants point at the last module that compiled successfully (`{name}.last.wasm`),
+/// which may behave like older code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WasmBuild {
+    /// the module was compiled from the given code (or was already in the cache)
+    Fresh(String),
+    /// compiling the given code failed. `error` is why, `path` is the last good build.
+    Stale { path: String, error: String },
+    /// we intentionally didnt compile (eg: a rust-analyzer keystroke check)
+    /// and the last good build was returned instead.
+    Skipped(String),
+}
+
+impl WasmBuild {
+    /// path to the .wasm file, regardless of whether its fresh or not
+    pub fn path(&self) -> &str {
+        match self {
+            WasmBuild::Fresh(p) | WasmBuild::Skipped(p) => p,
+            WasmBuild::Stale { path, .. } => path,
+        }
+    }
+
+    pub fn into_path(self) -> String {
+        match self {
+            WasmBuild::Fresh(p) | WasmBuild::Skipped(p) => p,
+            WasmBuild::Stale { path, .. } => path,
+        }
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        matches!(self, WasmBuild::Fresh(_))
+    }
+
+    /// the compile error, if we fell back to the last good build because of one
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            WasmBuild::Stale { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    /// only accept a freshly compiled module. a stale fallback becomes its compile error.
+    pub fn fresh(self) -> Result<String, String> {
+        match self {
+            WasmBuild::Fresh(p) => Ok(p),
+            WasmBuild::Stale { error, .. } => Err(error),
+            WasmBuild::Skipped(p) => Err(format!("Compilation was skipped, only the previous build {p} is available")),
+        }
+    }
+}
+
+/// where the last good build of a module is kept, ie: `{name}.last.wasm`
+pub(crate) fn last_module_destination(wasm_out_dir: &str, wasm_out_name: &str) -> String {
+    if wasm_out_name.is_empty() {
+        format!("{wasm_out_dir}/last.wasm")
+    } else {
+        format!("{wasm_out_dir}/{wasm_out_name}.last.wasm")
+    }
+}
+
+/// the last good build of a module, if there is one and its valid
+pub(crate) fn last_module_path(wasm_out_dir: &str, wasm_out_name: &str) -> Option<String> {
+    let path = last_module_destination(wasm_out_dir, wasm_out_name);
+    validate_cached_wasm(&path).then_some(path)
+}
+
+pub(crate) fn fallback_to_last(last_module_path: Option<String>, error: String) -> Result<WasmBuild, String> {
+    match last_module_path {
+        Some(path) => Ok(WasmBuild::Stale { path, error }),
+        None => Err(error),
+    }
+}
+
+/// compiles a single .rs file (as a string) to a .wasm file.
+/// See `resolve_wasm_out_dir` for where the output goes.
+/// If compilation fails but a previous build of this module exists, that
+/// build is returned as `WasmBuild::Stale` along with the compile error.
+/// If the crate's `wasm_type_gen.toml` has an `[optimize]` table, the module is
+/// also optimized, and the path is that of the optimized module. see `optimize`
 pub fn compile_string_to_wasm(
     wasm_out_name: &str,
     file_data: &str,
     add_to_code: Option<String>,
     output_dir: Option<String>,
-) -> Result<String, String> {
+) -> Result<WasmBuild, String> {
+    let build = compile_string_to_wasm_unoptimized(wasm_out_name, file_data, add_to_code, output_dir)?;
+    match optimize::crate_optimize_config()? {
+        Some(config) => optimize_build(build, &config),
+        None => Ok(build),
+    }
+}
+
+/// same as `compile_string_to_wasm`, but ignores the crate's `[optimize]` config.
+/// batch jobs use this, they have their own optimize setting
+pub(crate) fn compile_string_to_wasm_unoptimized(
+    wasm_out_name: &str,
+    file_data: &str,
+    add_to_code: Option<String>,
+    output_dir: Option<String>,
+) -> Result<WasmBuild, String> {
+    let wasm_out_dir = resolve_wasm_out_dir(output_dir);
+
+    let wasm_out_dir_incremental = format!("{}/incremental", wasm_out_dir);
+    let last_module_destination = last_module_destination(&wasm_out_dir, wasm_out_name);
+    // if we have a previously compiled module, store it so we can return this if the current compilation fails
+    let last_module_path = last_module_path(&wasm_out_dir, wasm_out_name);
+
     // to get IDE hints in our editor, our .rs file that will be turned into a .wasm file
     // must import the types that it references.
     // however, we wish to compile only a single file, and thus have no way of handling imports / linking.
-    // so our hack is thus:
-    // we remove `use super::*` and we add in the referenced code as text directly to the code before compiling.
+    // so we parse the file, put the referenced code into a `host_types` module, and point
+    // the user's imports at it (see inject_host_code for details).
     // this is why we compile via stdin rather than from a file: because we can modify the code in memory
     // rather than needing to modify the user's actual code on disk.
-    let mut file_data = file_data.replace("use super::*;", "");
-    if let Some(add) = add_to_code {
-        file_data.push('\n');
-        file_data.push_str(&add);
-    }
+    let file_data = match inject_host_code(file_data, add_to_code.as_deref().unwrap_or_default()) {
+        Ok(f) => f,
+        Err(e) => return fallback_to_last(last_module_path, e),
+    };
 
     let reader = std::io::BufReader::new(file_data.as_bytes());
     let hash = adler32::adler32(reader).unwrap_or(0);
-
-    let wasm_last_name = if wasm_out_name.is_empty() {
-        "last.wasm".to_string()
-    } else {
-        format!("{wasm_out_name}.last.wasm")
-    };
+    let module_name = wasm_out_name;
     let wasm_out_name = if wasm_out_name.is_empty() {
         format!("{hash}.wasm")
     } else {
         format!("{wasm_out_name}.{hash}.wasm")
     };
-
-    let wasm_out_dir = match output_dir {
-        Some(s) => if s.starts_with('/') {
-            s
-        } else {
-            let wasm_output_base = std::env::var("CARGO_MANIFEST_DIR").unwrap_or(".".into());
-            format!("{wasm_output_base}/{s}")
-        }
-        None => {
-            let wasm_output_base = std::env::var("CARGO_MANIFEST_DIR").unwrap_or(".".into());
-            format!("{}/wasmout", wasm_output_base)
-        }
-    };
-
-    let wasm_out_dir_incremental = format!("{}/incremental", wasm_out_dir);
     // skip compilation if file already exists
     let module_path = format!("{}/{}", wasm_out_dir, wasm_out_name);
-    let last_module_destination = format!("{}/{}", wasm_out_dir, wasm_last_name);
-    // if we have a previously compiled module, store it so we can return this if the current compilation fails
-    let last_module_path = if std::fs::File::open(&last_module_destination).is_ok() {
-        Some(last_module_destination.clone())
-    } else {
-        None
-    };
 
-    if std::fs::File::open(&module_path).is_ok() {
+    // other processes (rust-analyzer, cargo build, other batch workers) may be compiling
+    // into the same directory. this lock is best effort: if we cant get it (eg: read only dir)
+    // we still want to be able to use already compiled modules.
+    let _lock = CompileLock::acquire(&wasm_out_dir, module_name).ok();
+
+    // corrupt modules get deleted here, and then we fall through and rebuild it
+    if validate_cached_wasm(&module_path) {
         // if we are re-using an already compiled wasm file, then
         // we should set this to be the last.wasm for the next compilation
-        let _ = std::fs::copy(&module_path, &last_module_destination);
-        return Ok(module_path)
+        let _ = copy_atomic(&module_path, &last_module_destination);
+        cache::touch(&module_path);
+        cache::record_cache_event(&wasm_out_dir, true);
+        return Ok(WasmBuild::Fresh(module_path))
     }
+    cache::record_cache_event(&wasm_out_dir, false);
 
-    // if this is being compiled by rust analyzer, its for a keystroke, and
-    // not something we usually want to fully compile. When the user saves the file, this
-    // env var is (hopefully!) not present, and then we will run a normal compile.
+    // if this is being compiled by an IDE, its for a keystroke, and
+    // not something we usually want to fully compile. When the user saves the file,
+    // a normal compile will happen. (see build_mode for how this is detected)
     // otherwise, if we detect this, AND we have a last.wasm file, then just return that
-    if std::env::var("RUST_ANALYZER_INTERNALS_DO_NOT_USE").is_ok() {
+    if !build_mode()?.should_compile() {
         if let Some(last) = last_module_path {
-            return Ok(last);
+            return Ok(WasmBuild::Skipped(last));
         }
     }
 
+    // check that we actually can compile before we try to. otherwise
+    // a missing wasm target shows up as a confusing "can't find crate for std" error.
+    if let Err(e) = toolchain::preflight_cached() {
+        return fallback_to_last(last_module_path, e.into());
+    }
+
     let incremental_arg = format!("incremental={wasm_out_dir_incremental}");
+    // rustc writes to a temporary file that we rename once its done. this way
+    // nobody can read a partially written module.
+    let tmp_module_path = artifact::tmp_path_for(&module_path);
     let _ = std::fs::create_dir(wasm_out_dir);
     let _ = std::fs::create_dir(wasm_out_dir_incremental);
     // for debugging:
@@ -505,7 +719,7 @@ pub fn compile_string_to_wasm(
     //     s.push_str(&format!("{key} = {val}\n"));
     // }
     // let _ = f.write_all(s.as_bytes());
-    let cmd_resp = Command::new("rustc")
+    let cmd_resp = rustc_command()
         // .arg(s) // can compile by pointing to a file. but for out purposes we want to use stdin
         .arg("--target").arg("wasm32-unknown-unknown")
         .arg("--crate-type=cdylib")
@@ -517,7 +731,9 @@ pub fn compile_string_to_wasm(
         .arg("-C").arg("strip=symbols")
         .arg("-C").arg("lto=no")
         .arg("-C").arg(&incremental_arg)
-        .arg("-o").arg(module_path.as_str())
+        // only errors are interesting. lint warnings would bury them in the stale build warning
+        .arg("--cap-lints").arg("allow")
+        .arg("-o").arg(tmp_module_path.as_str())
         .arg("-")
         .stdin(Stdio::piped())
         .stderr(Stdio::piped())
@@ -526,52 +742,53 @@ pub fn compile_string_to_wasm(
     let mut cmd = match cmd_resp {
         Ok(c) => c,
         Err(e) => {
-            if let Some(last) = last_module_path {
-                return Ok(last)
-            }
-            return Err(format!("Failed to invoke rustc {:?}", e));
+            return fallback_to_last(last_module_path, format!("Failed to invoke rustc {:?}", e));
         }
     };
     if let Some(mut stdin) = cmd.stdin.take() {
         if let Err(e) = stdin.write_all(file_data.as_bytes()) {
-            if let Some(last) = last_module_path {
-                return Ok(last)
-            }
-            return Err(format!("Failed to write stdin for rustc invocation\n{:?}", e));
+            return fallback_to_last(last_module_path, format!("Failed to write stdin for rustc invocation\n{:?}", e));
         }
     }
 
     let output = match cmd.wait() {
         Ok(o) => o,
         Err(e) => {
-            if let Some(last) = last_module_path {
-                return Ok(last)
-            }
-            return Err(format!("Failed to compile to wasm\n{:?}", e));
+            return fallback_to_last(last_module_path, format!("Failed to compile to wasm\n{:?}", e));
         }
     };
     if !output.success() {
         let mut err = String::new();
         if let Some(mut out) = cmd.stderr.take() {
             if let Err(e) = out.read_to_string(&mut err) {
-                if let Some(last) = last_module_path {
-                    return Ok(last)
-                }
-                return Err(format!("Failed to get stderr after failure to compile wasm\n{:?}", e));
+                return fallback_to_last(last_module_path, format!("Failed to get stderr after failure to compile wasm\n{:?}", e));
             }
         }
-        if let Some(last) = last_module_path {
-            return Ok(last)
-        }
-        return Err(format!("Failed to compile wasm module\n{}", err));
+        let _ = std::fs::remove_file(&tmp_module_path);
+        return fallback_to_last(last_module_path, format!("Failed to compile wasm module\n{}", err));
+    }
+    if let Err(e) = artifact::rename_atomic(&tmp_module_path, &module_path) {
+        return fallback_to_last(last_module_path, e);
     }
 
     // copy successful path to the last path
-    let _ = std::fs::copy(&module_path, &last_module_destination);
+    let _ = copy_atomic(&module_path, &last_module_destination);
 
-    Ok(module_path)
+    Ok(WasmBuild::Fresh(module_path))
 }
 
+/// which build `compile_and_run_wasm` runs. running an older build of the file than the one
+/// on disk because the new one failed to compile is never what the caller wants.
+/// but a skipped compile (an IDE keystroke) is fine, the real build compiles it again
+pub(crate) fn runnable_path(build: WasmBuild) -> Result<String, String> {
+    match build {
+        WasmBuild::Fresh(path) | WasmBuild::Skipped(path) => Ok(path),
+        WasmBuild::Stale { error, .. } => Err(error),
+    }
+}
+
+/// compile the guest at `path_to_rs_wasm_file` (a .rs, .wat, or .wasm file), pass it
+/// `data_to_pass`, and return what the guest passed back.
 pub fn compile_and_run_wasm<T: FromBinarySlice + ToBinarySlice + WasmIncludeString>(
     path_to_rs_wasm_file: &str,
     data_to_pass: &T,
@@ -581,10 +798,16 @@ pub fn compile_and_run_wasm<T: FromBinarySlice + ToBinarySlice + WasmIncludeStri
     add_to_code.push_str(T::gen_entrypoint());
     // this got generated by generate_parsing_traits!()
     add_to_code.push_str(WASM_PARSING_TRAIT_STR);
-    let wasm_path = compile_file_to_wasm(path_to_rs_wasm_file, Some(add_to_code))?;
-    let mut wasm_f = std::fs::File::open(wasm_path).map_err(|e| format!("Failed to open wasm file {:?}", e))?;
-    let mut wasm_data = vec![];
-    wasm_f.read_to_end(&mut wasm_data).map_err(|e| format!("Failed to read wasm file {:?}", e))?;
+    let wasm_data = match GuestKind::from_path(path_to_rs_wasm_file) {
+        GuestKind::Rust => {
+            let wasm_path = runnable_path(compile_file_to_wasm(path_to_rs_wasm_file, Some(add_to_code))?)?;
+            let mut wasm_f = std::fs::File::open(wasm_path).map_err(|e| format!("Failed to open wasm file {:?}", e))?;
+            let mut wasm_data = vec![];
+            wasm_f.read_to_end(&mut wasm_data).map_err(|e| format!("Failed to read wasm file {:?}", e))?;
+            wasm_data
+        }
+        GuestKind::Wat | GuestKind::Wasm => load_prebuilt_module(path_to_rs_wasm_file)?,
+    };
 
     let mut serialized_data = vec![];
     data_to_pass.add_to_slice(&mut serialized_data);
@@ -598,60 +821,13 @@ pub fn compile_and_run_wasm<T: FromBinarySlice + ToBinarySlice + WasmIncludeStri
     }
 }
 
+/// run a guest on the default runtime (see the runtime module), passing it `serialized_data`,
+/// and return what the guest passed back.
 pub fn run_wasm(
     wasm_data: &[u8],
     serialized_data: Vec<u8>,
 ) -> Result<Vec<u8>, String> {
-    // linking (giving wasm guest access to host functions)
-    let engine = Engine::default();
-    let module = Module::from_binary(&engine, &wasm_data).map_err(|e| format!("failed to load wasm module {:?}", e))?;
-    let mut linker: Linker<_> = Linker::new(&engine);
-    linker.func_wrap("env", "get_entrypoint_alloc_size", |caller: Caller<'_, _>| -> u32 {
-        let data: &Vec<u8> = caller.data();
-        data.len() as u32
-    }).unwrap();
-    linker.func_wrap("env", "get_entrypoint_data", |mut caller: Caller<'_, _>, ptr: u32, len: u32| {
-        let ptr = ptr as usize;
-        let len = len as usize;
-        let host_data: &Vec<u8> = caller.data();
-        if host_data.len() != len {
-            return;
-        }
-        let host_data = host_data.clone();
-        if let Some(Extern::Memory(mem)) = caller.get_export("memory") {
-            let mem_data = mem.data_mut(&mut caller);
-            if let Some(data) = mem_data.get_mut(ptr..ptr+len) {
-                data.copy_from_slice(&host_data);
-            }
-        }
-    }).unwrap();
-    linker.func_wrap("env", "set_entrypoint_data", |mut caller: Caller<'_, _>, ptr: u32, len: u32| {
-        let ptr = ptr as usize;
-        let len = len as usize;
-        let output = if let Some(Extern::Memory(mem)) = caller.get_export("memory") {
-            let mem_data = mem.data_mut(&mut caller);
-            if let Some(data) = mem_data.get_mut(ptr..ptr+len) {
-                Some(data.to_vec())
-            } else {
-                None
-            }
-        } else { None };
-        if let Some(out) = output {
-            let host_data: &mut Vec<u8> = caller.data_mut();
-            *host_data = out;
-        }
-    }).unwrap();
-
-    // instantiation, setting our main data entrypoint, calling wasm entry
-    let mut store: Store<_> = Store::new(&engine, serialized_data);
-    let instance = linker.instantiate(&mut store, &module).unwrap();
-    let func = instance.get_typed_func::<(), u32>(&mut store, "wasm_entrypoint").unwrap();
-    let res = func.call(&mut store, ()).unwrap();
-    if res != 0 {
-        return Err("Failed to deserialize data from host to wasm guest".into());
-    }
-    let out_data = store.into_data();
-    Ok(out_data)
+    run_wasm_with(&DefaultRuntime::default(), wasm_data, serialized_data)
 }
 
 #[cfg(test)]
@@ -1208,6 +1384,34 @@ mod tests {
         assert_eq!(Abc::include_in_rs_wasm().match_indices("pub struct Something").collect::<Vec<_>>().len(), 1);
     }
 
+    #[test]
+    fn shared_grandchild_def_only_once() {
+        #[derive(WasmTypeGen, PartialEq, Debug)]
+        pub struct Leaf {
+            pub a: u32,
+        }
+        #[derive(WasmTypeGen, PartialEq, Debug)]
+        pub struct Left {
+            pub leaves: Vec<Leaf>,
+        }
+        #[derive(WasmTypeGen, PartialEq, Debug)]
+        pub enum Right {
+            One { leaf: Leaf },
+            Two,
+        }
+        #[derive(WasmTypeGen, PartialEq, Debug)]
+        pub struct Root {
+            pub left: Left,
+            pub right: Right,
+            pub leaf: Leaf,
+        }
+        // Leaf is reachable through 3 different fields, but should only be defined once
+        let include = Root::include_in_rs_wasm();
+        assert_eq!(include.match_indices("pub struct Leaf").count(), 1);
+        assert_eq!(include.match_indices("pub struct Left").count(), 1);
+        assert_eq!(include.match_indices("pub enum Right").count(), 1);
+    }
+
     #[test]
     fn works_for_results() {
         #[derive(WasmTypeGen, PartialEq, Debug)]
@@ -1267,4 +1471,74 @@ mod tests {
         // and ensure it only appears once!
         assert_eq!(Abc::include_in_rs_wasm().match_indices("pub struct Something").collect::<Vec<_>>().len(), 1);
     }
+
+    #[test]
+    fn failed_compile_falls_back_to_stale_build() {
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_stale_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let dir = dir.to_string_lossy().to_string();
+        // unparseable, so this fails before ever invoking rustc
+        let bad_code = "fn broken( {";
+        let err = compile_string_to_wasm("m", bad_code, None, Some(dir.clone())).unwrap_err();
+        assert!(!err.is_empty());
+
+        let last = format!("{dir}/m.last.wasm");
+        write_atomic(&last, [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00]).unwrap();
+        let build = compile_string_to_wasm("m", bad_code, None, Some(dir.clone())).unwrap();
+        assert!(!build.is_fresh());
+        assert_eq!(build.path(), last);
+        assert_eq!(build.error(), Some(err.as_str()));
+        assert_eq!(build.fresh(), Err(err));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn skipped_builds_are_runnable() {
+        assert_eq!(runnable_path(WasmBuild::Skipped("m.last.wasm".into())), Ok("m.last.wasm".into()));
+        assert_eq!(runnable_path(WasmBuild::Fresh("m.1.wasm".into())), Ok("m.1.wasm".into()));
+        let stale = WasmBuild::Stale { path: "m.last.wasm".into(), error: "bad".into() };
+        assert_eq!(runnable_path(stale), Err("bad".into()));
+    }
+
+    /// a hand written guest that implements the entrypoint ABI by echoing its input back
+    const ECHO_WAT: &str = r#"
+        (module
+            (import "env" "get_entrypoint_alloc_size" (func $size (result i32)))
+            (import "env" "get_entrypoint_data" (func $get (param i32 i32)))
+            (import "env" "set_entrypoint_data" (func $set (param i32 i32)))
+            (memory (export "memory") 1)
+            (func (export "wasm_entrypoint") (result i32)
+                (local $len i32)
+                (local.set $len (call $size))
+                ;; the input goes at offset 1024. grow the memory if it doesnt fit
+                (if (i32.gt_u (i32.add (local.get $len) (i32.const 1024)) (i32.mul (memory.size) (i32.const 65536)))
+                    (then (drop (memory.grow (i32.add (i32.shr_u (local.get $len) (i32.const 16)) (i32.const 1))))))
+                (call $get (i32.const 1024) (local.get $len))
+                (call $set (i32.const 1024) (local.get $len))
+                (i32.const 0))
+        )
+    "#;
+
+    #[test]
+    fn wat_guest_conforms_to_abi() {
+        #[derive(WasmTypeGen, Debug, PartialEq)]
+        pub struct Echo {
+            pub s: String,
+            pub v: Vec<u32>,
+            pub o: Option<u8>,
+        }
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_wat_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let wat_path = dir.join("echo.wat").to_string_lossy().to_string();
+        std::fs::write(&wat_path, ECHO_WAT).unwrap();
+        check_entrypoint_abi(&wat::parse_str(ECHO_WAT).unwrap()).unwrap();
+
+        let item = Echo { s: "hello".into(), v: (0..20000).collect(), o: Some(3) };
+        assert_eq!(compile_and_run_wasm(&wat_path, &item).unwrap(), item);
+        // prebuilt .wasm works the same way
+        let wasm_path = dir.join("echo.wasm").to_string_lossy().to_string();
+        std::fs::write(&wasm_path, wat::parse_str(ECHO_WAT).unwrap()).unwrap();
+        assert_eq!(compile_and_run_wasm(&wasm_path, &item).unwrap(), item);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
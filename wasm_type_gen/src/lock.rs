@@ -0,0 +1,96 @@
+use std::fs::{File, OpenOptions};
+
+/// name of the lock file that guards an entire output directory.
+pub const DIR_LOCK_FILE: &str = ".wasm_type_gen.lock";
+
+/// an advisory, cross-process lock backed by a file in the output directory.
+/// the lock is released when this is dropped.
+/// rust-analyzer and cargo regularly run at the same time, and both
+/// of them write to the same wasmout/ directory.
+pub struct OutputLock {
+    _file: File,
+}
+
+impl OutputLock {
+    /// block until we have exclusive access to `{output_dir}/{lock_name}`
+    pub fn exclusive(output_dir: &str, lock_name: &str) -> Result<Self, String> {
+        let file = open_lock_file(output_dir, lock_name)?;
+        file.lock().map_err(|e| format!("Failed to lock {output_dir}/{lock_name}\n{:?}", e))?;
+        Ok(Self { _file: file })
+    }
+
+    /// block until we have shared access to `{output_dir}/{lock_name}`.
+    /// many shared locks can be held at once, but not while an exclusive lock is held.
+    pub fn shared(output_dir: &str, lock_name: &str) -> Result<Self, String> {
+        let file = open_lock_file(output_dir, lock_name)?;
+        file.lock_shared().map_err(|e| format!("Failed to lock {output_dir}/{lock_name}\n{:?}", e))?;
+        Ok(Self { _file: file })
+    }
+
+    /// like `exclusive`, but returns None instead of waiting if someone else holds the lock
+    pub fn try_exclusive(output_dir: &str, lock_name: &str) -> Result<Option<Self>, String> {
+        let file = open_lock_file(output_dir, lock_name)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(Self { _file: file })),
+            Err(std::fs::TryLockError::WouldBlock) => Ok(None),
+            Err(std::fs::TryLockError::Error(e)) => Err(format!("Failed to lock {output_dir}/{lock_name}\n{:?}", e)),
+        }
+    }
+}
+
+/// Held while compiling a module: a shared lock on the whole directory
+/// (so cleanup cant run underneath us) and an exclusive lock
+/// on the module name (so two processes dont write the same artifacts).
+pub struct CompileLock {
+    _module: OutputLock,
+    _dir: OutputLock,
+}
+
+impl CompileLock {
+    pub fn acquire(output_dir: &str, module_name: &str) -> Result<Self, String> {
+        let dir = OutputLock::shared(output_dir, DIR_LOCK_FILE)?;
+        let lock_name = if module_name.is_empty() {
+            ".lock".to_string()
+        } else {
+            format!("{module_name}.lock")
+        };
+        let module = OutputLock::exclusive(output_dir, &lock_name)?;
+        Ok(Self { _module: module, _dir: dir })
+    }
+}
+
+fn open_lock_file(output_dir: &str, lock_name: &str) -> Result<File, String> {
+    let _ = std::fs::create_dir_all(output_dir);
+    let path = format!("{output_dir}/{lock_name}");
+    OpenOptions::new().create(true).truncate(false).read(true).write(true).open(&path)
+        .map_err(|e| format!("Failed to open lock file {path}\n{:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusive_lock_blocks_others() {
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_lock_{}", std::process::id()));
+        let dir = dir.to_string_lossy().to_string();
+        let held = OutputLock::exclusive(&dir, "a.lock").unwrap();
+        assert!(OutputLock::try_exclusive(&dir, "a.lock").unwrap().is_none());
+        // different name is unaffected
+        assert!(OutputLock::try_exclusive(&dir, "b.lock").unwrap().is_some());
+        drop(held);
+        assert!(OutputLock::try_exclusive(&dir, "a.lock").unwrap().is_some());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn compile_lock_blocks_dir_cleanup() {
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_lock2_{}", std::process::id()));
+        let dir = dir.to_string_lossy().to_string();
+        let held = CompileLock::acquire(&dir, "mymod").unwrap();
+        assert!(OutputLock::try_exclusive(&dir, DIR_LOCK_FILE).unwrap().is_none());
+        drop(held);
+        assert!(OutputLock::try_exclusive(&dir, DIR_LOCK_FILE).unwrap().is_some());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,346 @@
+//! Optional post-processing of compiled guest modules to make them smaller.
+//!
+//! The pipeline is:
+//! 1. validate the module
+//! 2. remove every export that the host doesnt need, and (optionally) all custom sections
+//!    (debug names, producers, etc.)
+//! 3. run binaryen's `wasm-opt` if it can be found. It is looked up via the `WASM_OPT` env var,
+//!    and then on the PATH. If its not found this step is skipped.
+//! 4. validate the result
+//!
+//! Optimized modules are cached next to the input as `{name}.{key}.opt.wasm` where the key
+//! depends on the input module, the options, and which wasm-opt (if any) was used.
+//!
+//! `compile_string_to_wasm` (and so `#[wasm_meta]`) optimizes its modules when the `wasm_type_gen.toml`
+//! of the crate being compiled has an `[optimize]` table. every key is optional:
+//! ```toml
+//! [optimize]
+//! strip_custom_sections = true
+//! # or false to never run wasm-opt
+//! wasm_opt_level = "s"
+//! keep_exports = ["wasm_entrypoint", "memory"]
+//! ```
+
+use std::process::Command;
+use std::sync::OnceLock;
+
+use crate::{artifact, cache, WasmBuild, build_mode::BUILD_MODE_CONFIG_FILE};
+
+/// the exports that `run_wasm` needs from a guest
+pub const ENTRYPOINT_EXPORTS: [&str; 2] = ["wasm_entrypoint", "memory"];
+
+/// wasm features that rustc enables by default for wasm32-unknown-unknown.
+/// wasm-opt refuses modules that use features it wasnt told about.
+const WASM_OPT_FEATURES: [&str; 6] = [
+    "--enable-bulk-memory",
+    "--enable-sign-ext",
+    "--enable-mutable-globals",
+    "--enable-nontrapping-float-to-int",
+    "--enable-multivalue",
+    "--enable-reference-types",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OptimizeConfig {
+    /// exports the host needs. every other export is removed so that
+    /// the optimizer can remove the code only they referenced.
+    pub keep_exports: Vec<String>,
+    pub strip_custom_sections: bool,
+    /// passed to wasm-opt as `-O{level}`, eg: "2", "s", "z".
+    /// None to never run wasm-opt.
+    pub wasm_opt_level: Option<String>,
+}
+
+impl Default for OptimizeConfig {
+    fn default() -> Self {
+        Self {
+            keep_exports: ENTRYPOINT_EXPORTS.iter().map(|s| s.to_string()).collect(),
+            strip_custom_sections: true,
+            wasm_opt_level: Some("s".into()),
+        }
+    }
+}
+
+/// the `[optimize]` table of a `wasm_type_gen.toml`. None if there isnt one
+pub fn optimize_config_from_toml(contents: &str) -> Result<Option<OptimizeConfig>, String> {
+    let table: toml::Table = contents.parse().map_err(|e| format!("Failed to parse {BUILD_MODE_CONFIG_FILE}\n{e}"))?;
+    let optimize = match table.get("optimize") {
+        None => return Ok(None),
+        Some(toml::Value::Table(t)) => t,
+        Some(v) => return Err(format!("{BUILD_MODE_CONFIG_FILE}: optimize must be a table, found {v}")),
+    };
+    let mut config = OptimizeConfig::default();
+    for (key, val) in optimize.iter() {
+        match (key.as_str(), val) {
+            ("strip_custom_sections", toml::Value::Boolean(b)) => config.strip_custom_sections = *b,
+            ("wasm_opt_level", toml::Value::String(level)) => config.wasm_opt_level = Some(level.clone()),
+            ("wasm_opt_level", toml::Value::Boolean(false)) => config.wasm_opt_level = None,
+            ("keep_exports", toml::Value::Array(exports)) => {
+                config.keep_exports = exports.iter().map(|e| match e {
+                    toml::Value::String(s) => Ok(s.clone()),
+                    _ => Err(format!("{BUILD_MODE_CONFIG_FILE}: optimize.keep_exports must be an array of strings, found {e}")),
+                }).collect::<Result<_, _>>()?;
+            }
+            ("strip_custom_sections" | "wasm_opt_level" | "keep_exports", v) => {
+                return Err(format!("{BUILD_MODE_CONFIG_FILE}: invalid value for optimize.{key}: {v}"));
+            }
+            _ => return Err(format!("{BUILD_MODE_CONFIG_FILE}: unknown key optimize.{key}")),
+        }
+    }
+    Ok(Some(config))
+}
+
+/// the optimize config of the crate being compiled, see the module docs
+pub fn crate_optimize_config() -> Result<Option<OptimizeConfig>, String> {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or(".".into());
+    match std::fs::read_to_string(format!("{manifest_dir}/{BUILD_MODE_CONFIG_FILE}")) {
+        Ok(contents) => optimize_config_from_toml(&contents),
+        Err(_) => Ok(None),
+    }
+}
+
+/// path to a working wasm-opt, if there is one. only looked up once per process
+pub fn find_wasm_opt() -> Option<String> {
+    static WASM_OPT: OnceLock<Option<String>> = OnceLock::new();
+    WASM_OPT.get_or_init(lookup_wasm_opt).clone()
+}
+
+fn lookup_wasm_opt() -> Option<String> {
+    let path = match std::env::var("WASM_OPT") {
+        Ok(p) if !p.is_empty() => p,
+        _ => "wasm-opt".to_string(),
+    };
+    let out = Command::new(&path).arg("--version").output().ok()?;
+    if out.status.success() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+const EXPORT_SECTION_ID: u8 = 7;
+
+fn push_leb128(out: &mut Vec<u8>, mut val: u32) {
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn push_section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    push_leb128(out, contents.len() as u32);
+    out.extend_from_slice(contents);
+}
+
+fn encode_exports(reader: wasmparser::ExportSectionReader, keep: &[String]) -> Result<Vec<u8>, String> {
+    let mut kept = vec![];
+    for export in reader {
+        let export = export.map_err(|e| format!("Failed to read export section\n{e}"))?;
+        if keep.iter().any(|k| k == export.name) {
+            kept.push(export);
+        }
+    }
+    let mut out = vec![];
+    push_leb128(&mut out, kept.len() as u32);
+    for export in kept {
+        push_leb128(&mut out, export.name.len() as u32);
+        out.extend_from_slice(export.name.as_bytes());
+        out.push(match export.kind {
+            wasmparser::ExternalKind::Func => 0,
+            wasmparser::ExternalKind::Table => 1,
+            wasmparser::ExternalKind::Memory => 2,
+            wasmparser::ExternalKind::Global => 3,
+            wasmparser::ExternalKind::Tag => 4,
+        });
+        push_leb128(&mut out, export.index);
+    }
+    Ok(out)
+}
+
+/// removes exports that arent in `config.keep_exports`, and custom sections if
+/// `config.strip_custom_sections`. every other section is copied as is.
+pub fn strip_module(data: &[u8], config: &OptimizeConfig) -> Result<Vec<u8>, String> {
+    let mut out = vec![];
+    for payload in wasmparser::Parser::new(0).parse_all(data) {
+        let payload = payload.map_err(|e| format!("Failed to parse wasm module\n{e}"))?;
+        match payload {
+            wasmparser::Payload::Version { range, .. } => out.extend_from_slice(&data[range]),
+            wasmparser::Payload::CustomSection(_) if config.strip_custom_sections => {}
+            wasmparser::Payload::ExportSection(reader) => {
+                let contents = encode_exports(reader, &config.keep_exports)?;
+                push_section(&mut out, EXPORT_SECTION_ID, &contents);
+            }
+            other => {
+                if let Some((id, range)) = other.as_section() {
+                    push_section(&mut out, id, &data[range]);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn run_wasm_opt(wasm_opt: &str, level: &str, input: &[u8], out_path: &str) -> Result<Vec<u8>, String> {
+    let in_path = artifact::tmp_path_for(out_path);
+    let opt_path = artifact::tmp_path_for(out_path);
+    std::fs::write(&in_path, input).map_err(|e| format!("Failed to write {in_path}\n{:?}", e))?;
+    let res = Command::new(wasm_opt)
+        .arg(format!("-O{level}"))
+        .args(WASM_OPT_FEATURES)
+        .arg(&in_path)
+        .arg("-o").arg(&opt_path)
+        .output();
+    let _ = std::fs::remove_file(&in_path);
+    let res = res.map_err(|e| format!("Failed to run wasm-opt ('{wasm_opt}')\n{:?}", e))?;
+    if !res.status.success() {
+        let _ = std::fs::remove_file(&opt_path);
+        return Err(format!("wasm-opt failed\n{}", String::from_utf8_lossy(&res.stderr)));
+    }
+    let data = std::fs::read(&opt_path).map_err(|e| format!("Failed to read wasm-opt output {opt_path}\n{:?}", e));
+    let _ = std::fs::remove_file(&opt_path);
+    data
+}
+
+fn optimize_bytes(data: &[u8], config: &OptimizeConfig, wasm_opt: Option<&str>, out_path: &str) -> Result<Vec<u8>, String> {
+    if !artifact::is_valid_wasm(data) {
+        return Err("Refusing to optimize an invalid wasm module".into());
+    }
+    let mut out = strip_module(data, config)?;
+    if let (Some(level), Some(wasm_opt)) = (&config.wasm_opt_level, wasm_opt) {
+        out = run_wasm_opt(wasm_opt, level, &out, out_path)?;
+    }
+    if !artifact::is_valid_wasm(&out) {
+        return Err("Optimizing produced an invalid wasm module".into());
+    }
+    Ok(out)
+}
+
+/// where the optimized version of `module_path` gets cached
+fn optimized_path(module_path: &str, data: &[u8], config: &OptimizeConfig, wasm_opt: Option<&str>) -> String {
+    let path = std::path::Path::new(module_path);
+    let dir = path.parent().map(|p| p.to_string_lossy().to_string()).unwrap_or(".".into());
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let module_name = file_name.split('.').next().unwrap_or_default();
+    let mut key_data = data.to_vec();
+    key_data.extend_from_slice(format!("{:?}{:?}", config, wasm_opt).as_bytes());
+    let key = adler32::adler32(key_data.as_slice()).unwrap_or(0);
+    if module_name.is_empty() {
+        format!("{dir}/{key}.opt.wasm")
+    } else {
+        format!("{dir}/{module_name}.{key}.opt.wasm")
+    }
+}
+
+/// optimize a compiled .wasm file (see the module docs), returning the path of the optimized module.
+/// Results are cached, so calling this again with the same module and config is cheap.
+pub fn optimize_wasm_file(module_path: &str, config: &OptimizeConfig) -> Result<String, String> {
+    let data = std::fs::read(module_path).map_err(|e| format!("Failed to read {module_path}\n{:?}", e))?;
+    let wasm_opt = match config.wasm_opt_level {
+        Some(_) => find_wasm_opt(),
+        None => None,
+    };
+    let out_path = optimized_path(module_path, &data, config, wasm_opt.as_deref());
+    if artifact::validate_cached_wasm(&out_path) {
+        cache::touch(&out_path);
+        return Ok(out_path);
+    }
+    let optimized = optimize_bytes(&data, config, wasm_opt.as_deref(), &out_path)?;
+    artifact::write_atomic(&out_path, optimized)?;
+    Ok(out_path)
+}
+
+/// same as `optimize_wasm_file`, but keeps track of whether the build was stale
+pub fn optimize_build(build: WasmBuild, config: &OptimizeConfig) -> Result<WasmBuild, String> {
+    let path = optimize_wasm_file(build.path(), config)?;
+    Ok(match build {
+        WasmBuild::Fresh(_) => WasmBuild::Fresh(path),
+        WasmBuild::Stale { error, .. } => WasmBuild::Stale { path, error },
+        WasmBuild::Skipped(_) => WasmBuild::Skipped(path),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULE: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (global $g (export "__heap_base") i32 (i32.const 1024))
+            (func $helper (export "helper") (result i32) (i32.const 2))
+            (func (export "wasm_entrypoint") (result i32) (call $helper))
+            (@custom "producers" "whatever")
+        )
+    "#;
+
+    fn export_names(data: &[u8]) -> Vec<String> {
+        let mut names = vec![];
+        for payload in wasmparser::Parser::new(0).parse_all(data) {
+            if let Ok(wasmparser::Payload::ExportSection(reader)) = payload {
+                names.extend(reader.into_iter().map(|e| e.unwrap().name.to_string()));
+            }
+        }
+        names
+    }
+
+    #[test]
+    fn strips_exports_and_custom_sections() {
+        let data = wat::parse_str(MODULE).unwrap();
+        let stripped = strip_module(&data, &OptimizeConfig::default()).unwrap();
+        assert!(artifact::is_valid_wasm(&stripped));
+        assert!(stripped.len() < data.len());
+        assert_eq!(export_names(&stripped), vec!["memory", "wasm_entrypoint"]);
+        let has_custom = wasmparser::Parser::new(0).parse_all(&stripped)
+            .any(|p| matches!(p, Ok(wasmparser::Payload::CustomSection(_))));
+        assert!(!has_custom);
+    }
+
+    #[test]
+    fn parses_optimize_config() {
+        assert_eq!(optimize_config_from_toml("build_mode = \"build\"").unwrap(), None);
+        assert_eq!(optimize_config_from_toml("[optimize]").unwrap(), Some(OptimizeConfig::default()));
+        let config = optimize_config_from_toml("[optimize]\nwasm_opt_level = false\nkeep_exports = [\"memory\"]").unwrap().unwrap();
+        assert_eq!(config.wasm_opt_level, None);
+        assert_eq!(config.keep_exports, vec!["memory"]);
+        assert!(optimize_config_from_toml("[optimize]\nwasm_opt_level = 2").unwrap_err().contains("optimize.wasm_opt_level"));
+        assert!(optimize_config_from_toml("[optimize]\nlevel = \"s\"").unwrap_err().contains("unknown key optimize.level"));
+        assert!(optimize_config_from_toml("optimize = true").is_err());
+    }
+
+    // run with `cargo test -- --ignored` where binaryen is installed
+    #[test]
+    #[ignore = "needs wasm-opt (binaryen) on the PATH"]
+    fn runs_wasm_opt() {
+        let wasm_opt = find_wasm_opt().expect("wasm-opt not found. install binaryen, or set WASM_OPT to its path");
+        let data = wat::parse_str(MODULE).unwrap();
+        let config = OptimizeConfig { wasm_opt_level: Some("z".into()), ..Default::default() };
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_wasm_opt_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let out_path = dir.join("m.opt.wasm").to_string_lossy().to_string();
+        let optimized = optimize_bytes(&data, &config, Some(&wasm_opt), &out_path).unwrap();
+        assert!(artifact::is_valid_wasm(&optimized));
+        assert_eq!(export_names(&optimized), vec!["memory", "wasm_entrypoint"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn optimized_modules_are_cached() {
+        let dir = std::env::temp_dir().join(format!("wasm_type_gen_optimize_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let path = dir.join("m.1.wasm").to_string_lossy().to_string();
+        std::fs::write(&path, wat::parse_str(MODULE).unwrap()).unwrap();
+        let config = OptimizeConfig { wasm_opt_level: None, ..Default::default() };
+        let out = optimize_wasm_file(&path, &config).unwrap();
+        assert!(out.ends_with(".opt.wasm"));
+        assert_eq!(optimize_wasm_file(&path, &config).unwrap(), out);
+        let other = OptimizeConfig { strip_custom_sections: false, ..config };
+        assert_ne!(optimize_wasm_file(&path, &other).unwrap(), out);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
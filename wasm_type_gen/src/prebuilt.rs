@@ -0,0 +1,182 @@
+//! Guest modules dont have to be written in rust. Any module that implements the
+//! entrypoint ABI can be used, so it can be written by hand in WAT, or built from
+//! AssemblyScript, Zig, C, etc.
+//!
+//! The ABI, from the guest's point of view:
+//! - export a memory named `memory`
+//! - export `wasm_entrypoint: () -> i32`. it returns 0 on success
+//! - it may import the following from `env`:
+//!   - `get_entrypoint_alloc_size: () -> i32` the size of the serialized input
+//!   - `get_entrypoint_data: (ptr: i32, len: i32)` copies the input into memory at `ptr`.
+//!     `len` must be the size returned by get_entrypoint_alloc_size.
+//!   - `set_entrypoint_data: (ptr: i32, len: i32)` sets the output to `len` bytes of memory at `ptr`
+//!
+//! The input and output are in the format of `ToBinarySlice` / `FromBinarySlice`.
+
+use wasmparser::{ExternalKind, FuncType, Payload, TypeRef, ValType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestKind {
+    /// a .rs file that we compile to wasm
+    Rust,
+    /// a .wat file, which gets assembled to wasm
+    Wat,
+    /// an already compiled .wasm file
+    Wasm,
+}
+
+impl GuestKind {
+    /// decided by file extension. anything that isnt .wat or .wasm is assumed to be rust
+    pub fn from_path(path: &str) -> Self {
+        let ext = std::path::Path::new(path).extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "wat" => GuestKind::Wat,
+            "wasm" => GuestKind::Wasm,
+            _ => GuestKind::Rust,
+        }
+    }
+}
+
+/// (name, params, results) of every host function a guest may import from `env`
+const ENTRYPOINT_IMPORTS: [(&str, &[ValType], &[ValType]); 3] = [
+    ("get_entrypoint_alloc_size", &[], &[ValType::I32]),
+    ("get_entrypoint_data", &[ValType::I32, ValType::I32], &[]),
+    ("set_entrypoint_data", &[ValType::I32, ValType::I32], &[]),
+];
+
+fn signature_matches(ty: &FuncType, params: &[ValType], results: &[ValType]) -> bool {
+    ty.params() == params && ty.results() == results
+}
+
+/// the parts of a module we need to check its imports and exports
+#[derive(Default)]
+struct ModuleInterface<'a> {
+    types: Vec<FuncType>,
+    /// type index of every function, imported ones first
+    funcs: Vec<u32>,
+    imports: Vec<(&'a str, &'a str, TypeRef)>,
+    exports: Vec<(&'a str, ExternalKind, u32)>,
+}
+
+fn read_interface(wasm_data: &[u8]) -> Result<ModuleInterface<'_>, wasmparser::BinaryReaderError> {
+    let mut out = ModuleInterface::default();
+    for payload in wasmparser::Parser::new(0).parse_all(wasm_data) {
+        match payload? {
+            Payload::TypeSection(reader) => {
+                for ty in reader {
+                    let wasmparser::Type::Func(f) = ty?;
+                    out.types.push(f);
+                }
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import?;
+                    if let TypeRef::Func(ty) = import.ty {
+                        out.funcs.push(ty);
+                    }
+                    out.imports.push((import.module, import.name, import.ty));
+                }
+            }
+            Payload::FunctionSection(reader) => {
+                for ty in reader {
+                    out.funcs.push(ty?);
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    out.exports.push((export.name, export.kind, export.index));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(out)
+}
+
+/// check that a module implements the entrypoint ABI (see the module docs).
+/// returns every problem we found, not just the first one.
+pub fn check_entrypoint_abi(wasm_data: &[u8]) -> Result<(), String> {
+    if let Err(e) = wasmparser::Validator::new().validate_all(wasm_data) {
+        return Err(format!("Invalid wasm module\n{e}"));
+    }
+    let interface = read_interface(wasm_data).map_err(|e| format!("Invalid wasm module\n{e}"))?;
+    let func_type = |type_index: u32| interface.types.get(type_index as usize);
+    let export = |name: &str| interface.exports.iter().find(|(n, _, _)| *n == name).map(|(_, kind, index)| (*kind, *index));
+
+    let mut problems = vec![];
+    match export("wasm_entrypoint") {
+        Some((ExternalKind::Func, index)) if interface.funcs.get(index as usize)
+            .and_then(|ty| func_type(*ty))
+            .map(|f| signature_matches(f, &[], &[ValType::I32]))
+            .unwrap_or(false) => {}
+        Some(_) => problems.push("export 'wasm_entrypoint' must be a function with the signature () -> i32".to_string()),
+        None => problems.push("missing export 'wasm_entrypoint'".to_string()),
+    }
+    match export("memory") {
+        Some((ExternalKind::Memory, _)) => {}
+        Some(_) => problems.push("export 'memory' must be a memory".to_string()),
+        None => problems.push("missing export 'memory'".to_string()),
+    }
+    for (module, name, ty) in &interface.imports {
+        let expected = ENTRYPOINT_IMPORTS.iter().find(|(n, _, _)| *module == "env" && name == n);
+        let (name, params, results) = match expected {
+            Some(e) => e,
+            None => {
+                problems.push(format!("unknown import '{module}::{name}'"));
+                continue;
+            }
+        };
+        match ty {
+            TypeRef::Func(ty) if func_type(*ty).map(|f| signature_matches(f, params, results)).unwrap_or(false) => {}
+            _ => problems.push(format!("import 'env::{name}' must be a function with the signature {:?} -> {:?}", params, results)),
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Module does not implement the wasm_entrypoint ABI:\n- {}", problems.join("\n- ")))
+    }
+}
+
+/// read a .wasm file, or assemble a .wat file, and check that it implements the entrypoint ABI
+pub fn load_prebuilt_module(path: &str) -> Result<Vec<u8>, String> {
+    let data = match GuestKind::from_path(path) {
+        GuestKind::Wat => wat::parse_file(path).map_err(|e| format!("Failed to assemble {path}\n{e}"))?,
+        GuestKind::Wasm => std::fs::read(path).map_err(|e| format!("Failed to read {path}\n{:?}", e))?,
+        GuestKind::Rust => return Err(format!("{path} is not a .wat or .wasm file")),
+    };
+    check_entrypoint_abi(&data).map_err(|e| format!("{path}: {e}"))?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_kind_from_extension() {
+        assert_eq!(GuestKind::from_path("a/b.wat"), GuestKind::Wat);
+        assert_eq!(GuestKind::from_path("b.WASM"), GuestKind::Wasm);
+        assert_eq!(GuestKind::from_path("b.rs"), GuestKind::Rust);
+        assert_eq!(GuestKind::from_path("b"), GuestKind::Rust);
+    }
+
+    #[test]
+    fn reports_abi_problems() {
+        let data = wat::parse_str(r#"
+            (module
+                (import "env" "get_entrypoint_data" (func (param i32)))
+                (import "env" "print" (func))
+                (func (export "wasm_entrypoint"))
+            )
+        "#).unwrap();
+        let err = check_entrypoint_abi(&data).unwrap_err();
+        assert!(err.contains("'wasm_entrypoint' must be a function"));
+        assert!(err.contains("missing export 'memory'"));
+        assert!(err.contains("unknown import 'env::print'"));
+        assert!(err.contains("import 'env::get_entrypoint_data'"));
+    }
+}
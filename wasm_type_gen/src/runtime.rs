@@ -0,0 +1,260 @@
+//! The runtime that guests get run on is pluggable, and picked with cargo features:
+//! - `wasmtime` (default): compiles guests to native code with cranelift. Fast to run,
+//!   but a heavy dependency, which hurts the build times of proc macro crates.
+//! - `wasmi`: a pure interpreter. Much faster to build and smaller, and it works
+//!   on hosts where JIT compilation isn't allowed. Guests run slower.
+//!
+//! At least one of them must be enabled. `run_wasm` uses `DefaultRuntime`, which is wasmtime
+//! when both are enabled. Use `run_wasm_with` to pick a runtime explicitly, eg: a proc macro crate
+//! can depend on `wasm_type_gen = { default-features = false, features = ["wasmi"] }`
+//! and call `run_wasm_with(&WasmiRuntime::default(), ..)`.
+//!
+//! Guests usually run during compilation, so their output ends up in generated code and in files
+//! under `wasmgen/`. The default configuration of both runtimes is deterministic so that builds
+//! are reproducible. See `wasmtime_runtime::deterministic_config` for the details.
+//! The one exception is wasmi and NaNs: wasmi can't canonicalize them, so the bits of a NaN
+//! a guest computes can differ between cpu architectures. Every other float result is the same
+//! everywhere, since wasm float math is IEEE 754 with no fused or approximate operations.
+
+#[cfg(feature = "wasmtime")]
+pub use crate::wasmtime_runtime::WasmtimeRuntime;
+#[cfg(feature = "wasmi")]
+pub use crate::wasmi_runtime::WasmiRuntime;
+
+#[cfg(feature = "wasmtime")]
+pub type DefaultRuntime = WasmtimeRuntime;
+#[cfg(all(feature = "wasmi", not(feature = "wasmtime")))]
+pub type DefaultRuntime = WasmiRuntime;
+#[cfg(not(any(feature = "wasmtime", feature = "wasmi")))]
+compile_error!("wasm_type_gen needs a runtime to run guests. Enable the `wasmtime` or `wasmi` feature");
+
+/// The operations the host needs to run a guest that implements the entrypoint ABI
+/// (see the prebuilt module for what that is).
+pub trait WasmRuntime {
+    /// a loaded (validated / compiled) module. it can be linked any number of times
+    type Module;
+    /// a module with the `env` functions of the entrypoint ABI already linked.
+    /// instantiating this is cheaper than linking the module again
+    type Linked;
+    /// an instantiated module along with its host data
+    type Instance;
+
+    fn load(&self, wasm_data: &[u8]) -> Result<Self::Module, String>;
+    /// link the `env` functions of the entrypoint ABI, without instantiating anything yet
+    fn prelink(&self, module: &Self::Module) -> Result<Self::Linked, String>;
+    /// create a new instance of a linked module.
+    /// `host_data` is what the guest gets from `get_entrypoint_data`.
+    fn instantiate(&self, linked: &Self::Linked, host_data: Vec<u8>) -> Result<Self::Instance, String>;
+    /// instantiate `module`, linking the `env` functions of the entrypoint ABI.
+    fn link_env_imports(&self, module: &Self::Module, host_data: Vec<u8>) -> Result<Self::Instance, String> {
+        let linked = self.prelink(module)?;
+        self.instantiate(&linked, host_data)
+    }
+    /// call `wasm_entrypoint`, returning its status code
+    fn call_entrypoint(&self, instance: &mut Self::Instance) -> Result<u32, String>;
+    fn read_memory(&self, instance: &mut Self::Instance, ptr: usize, len: usize) -> Result<Vec<u8>, String>;
+    fn write_memory(&self, instance: &mut Self::Instance, ptr: usize, data: &[u8]) -> Result<(), String>;
+    /// the host data of an instance. after the guest calls `set_entrypoint_data` this is its output
+    fn take_host_data(&self, instance: Self::Instance) -> Vec<u8>;
+    /// how many instances can exist at once, if the runtime has a limit. `BatchExecutor` never uses more workers than this
+    fn max_concurrency(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// what `get_entrypoint_data` does, for every runtime: copy the host data into guest memory.
+/// does nothing if the guest asked for the wrong length, or the memory range is out of bounds.
+pub(crate) fn copy_host_data_to_guest(host_data: &[u8], memory: &mut [u8], ptr: u32, len: u32) {
+    let ptr = ptr as usize;
+    let len = len as usize;
+    if host_data.len() != len {
+        return;
+    }
+    if let Some(data) = memory.get_mut(ptr..ptr + len) {
+        data.copy_from_slice(host_data);
+    }
+}
+
+/// what `set_entrypoint_data` does, for every runtime: replace the host data with the guest's output.
+/// does nothing if the memory range is out of bounds.
+pub(crate) fn copy_guest_data_to_host(memory: &[u8], host_data: &mut Vec<u8>, ptr: u32, len: u32) {
+    let ptr = ptr as usize;
+    let len = len as usize;
+    if let Some(data) = memory.get(ptr..ptr + len) {
+        *host_data = data.to_vec();
+    }
+}
+
+/// run a module that was already loaded by `runtime`. see `run_wasm_with`
+pub fn run_module<R: WasmRuntime>(
+    runtime: &R,
+    module: &R::Module,
+    serialized_data: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    let linked = runtime.prelink(module)?;
+    run_linked(runtime, &linked, serialized_data)
+}
+
+/// run a module that was already linked by `runtime`. see `run_wasm_with`
+pub fn run_linked<R: WasmRuntime>(
+    runtime: &R,
+    linked: &R::Linked,
+    serialized_data: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    let mut instance = runtime.instantiate(linked, serialized_data)?;
+    let res = runtime.call_entrypoint(&mut instance)?;
+    if res != 0 {
+        return Err("Failed to deserialize data from host to wasm guest".into());
+    }
+    Ok(runtime.take_host_data(instance))
+}
+
+/// same as `run_wasm`, but on a specific runtime
+pub fn run_wasm_with<R: WasmRuntime>(
+    runtime: &R,
+    wasm_data: &[u8],
+    serialized_data: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    let module = runtime.load(wasm_data)?;
+    run_module(runtime, &module, serialized_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// reverses its input
+    const REVERSE_WAT: &str = r#"
+        (module
+            (import "env" "get_entrypoint_alloc_size" (func $size (result i32)))
+            (import "env" "get_entrypoint_data" (func $get (param i32 i32)))
+            (import "env" "set_entrypoint_data" (func $set (param i32 i32)))
+            (memory (export "memory") 1)
+            (func (export "wasm_entrypoint") (result i32)
+                (local $len i32) (local $i i32)
+                (local.set $len (call $size))
+                (call $get (i32.const 0) (local.get $len))
+                (block $done
+                    (loop $copy
+                        (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+                        (i32.store8
+                            (i32.add (i32.const 32768) (local.get $i))
+                            (i32.load8_u (i32.sub (i32.sub (local.get $len) (i32.const 1)) (local.get $i))))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br $copy)))
+                (call $set (i32.const 32768) (local.get $len))
+                (i32.const 0))
+        )
+    "#;
+
+    fn check_runtime<R: WasmRuntime>(runtime: &R) {
+        let wasm = wat::parse_str(REVERSE_WAT).unwrap();
+        assert_eq!(run_wasm_with(runtime, &wasm, vec![1, 2, 3]).unwrap(), vec![3, 2, 1]);
+
+        let module = runtime.load(&wasm).unwrap();
+        let mut instance = runtime.link_env_imports(&module, vec![]).unwrap();
+        runtime.write_memory(&mut instance, 100, b"hello").unwrap();
+        assert_eq!(runtime.read_memory(&mut instance, 100, 5).unwrap(), b"hello");
+        assert!(runtime.read_memory(&mut instance, 65535, 2).is_err());
+        assert!(runtime.write_memory(&mut instance, 65536, b"x").is_err());
+
+        assert!(runtime.load(b"not wasm").is_err());
+    }
+
+    /// does a bunch of float math, storing 512 f64 results (some of which are NaN) as its output
+    const FLOAT_WAT: &str = r#"
+        (module
+            (import "env" "set_entrypoint_data" (func $set (param i32 i32)))
+            (memory (export "memory") 1)
+            (func (export "wasm_entrypoint") (result i32)
+                (local $i i32) (local $x f64)
+                (local.set $x (f64.const 1.5))
+                (block $done
+                    (loop $next
+                        (br_if $done (i32.ge_u (local.get $i) (i32.const 512)))
+                        (local.set $x (f64.div
+                            (f64.sqrt (f64.add (f64.mul (local.get $x) (f64.const 1.0001)) (f64.convert_i32_u (local.get $i))))
+                            (f64.const 3.7)))
+                        (local.set $x (f64.promote_f32 (f32.demote_f64 (f64.mul (local.get $x) (f64.const 1234.5678)))))
+                        (f64.store (i32.mul (local.get $i) (i32.const 8))
+                            (if (result f64) (i32.eqz (i32.rem_u (local.get $i) (i32.const 7)))
+                                (then (f64.div (f64.sub (local.get $x) (local.get $x)) (f64.const 0)))
+                                (else (if (result f64) (i32.eqz (i32.rem_u (local.get $i) (i32.const 11)))
+                                    (then (f64.promote_f32 (f32.sqrt (f32.neg (f32.demote_f64 (local.get $x))))))
+                                    (else (local.get $x))))))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br $next)))
+                (call $set (i32.const 0) (i32.const 4096))
+                (i32.const 0))
+        )
+    "#;
+
+    /// adler32 of FLOAT_WAT's output with canonical NaNs. the same on every platform
+    const FLOAT_WAT_CHECKSUM: u32 = 0xca72675a;
+
+    fn f64_at(out: &[u8], index: usize) -> u64 {
+        u64::from_le_bytes(out[index * 8..index * 8 + 8].try_into().unwrap())
+    }
+
+    /// replace the bits of every NaN in FLOAT_WAT's output with the canonical NaN
+    fn canonicalize_nans(out: &[u8]) -> Vec<u8> {
+        out.chunks(8).flat_map(|c| {
+            let bits = u64::from_le_bytes(c.try_into().unwrap());
+            let bits = if f64::from_bits(bits).is_nan() { 0x7ff8_0000_0000_0000 } else { bits };
+            bits.to_le_bytes()
+        }).collect()
+    }
+
+    #[test]
+    fn float_heavy_guests_are_reproducible() {
+        let wasm = wat::parse_str(FLOAT_WAT).unwrap();
+        let first = crate::run_wasm(&wasm, vec![]).unwrap();
+        let second = crate::run_wasm(&wasm, vec![]).unwrap();
+        assert_eq!(first.len(), 4096);
+        assert!(first == second);
+        // not just the same on every run, but the same bits on every machine
+        assert_eq!(f64_at(&first, 1), 0x40ba61eb40000000);
+        assert_eq!(adler32::adler32(canonicalize_nans(&first).as_slice()).unwrap(), FLOAT_WAT_CHECKSUM);
+        // the default runtime canonicalizes NaNs itself
+        #[cfg(feature = "wasmtime")]
+        assert_eq!(adler32::adler32(first.as_slice()).unwrap(), FLOAT_WAT_CHECKSUM);
+    }
+
+    /// wasmi cant canonicalize NaNs. everything but their bits is still fixed
+    #[cfg(feature = "wasmi")]
+    #[test]
+    fn wasmi_floats_only_differ_in_nan_bits() {
+        let wasm = wat::parse_str(FLOAT_WAT).unwrap();
+        let out = run_wasm_with(&WasmiRuntime::default(), &wasm, vec![]).unwrap();
+        assert_eq!(f64_at(&out, 1), 0x40ba61eb40000000);
+        assert!(f64::from_bits(f64_at(&out, 0)).is_nan());
+        assert_eq!(adler32::adler32(canonicalize_nans(&out).as_slice()).unwrap(), FLOAT_WAT_CHECKSUM);
+    }
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn wasmtime_canonicalizes_nans() {
+        let wasm = wat::parse_str(FLOAT_WAT).unwrap();
+        let out = run_wasm_with(&WasmtimeRuntime::default(), &wasm, vec![]).unwrap();
+        let canonical_f64 = f64::from_bits(0x7ff8_0000_0000_0000).to_le_bytes();
+        let canonical_f32 = (f32::from_bits(0x7fc0_0000) as f64).to_le_bytes();
+        // 0 / 0
+        assert_eq!(out[0..8], canonical_f64);
+        // sqrt of a negative f32
+        assert_eq!(out[11 * 8..12 * 8], canonical_f32);
+        let pooled = run_wasm_with(&WasmtimeRuntime::pooling(1, 1).unwrap(), &wasm, vec![]).unwrap();
+        assert!(out == pooled);
+    }
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn wasmtime_runtime_works() {
+        check_runtime(&WasmtimeRuntime::default());
+    }
+
+    #[cfg(feature = "wasmi")]
+    #[test]
+    fn wasmi_runtime_works() {
+        check_runtime(&WasmiRuntime::default());
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::HashSet;
+
+use proc_macro2::{TokenStream, TokenTree};
+use quote::{quote, format_ident};
+use syn::{
+    visit::Visit,
+    File,
+    Item,
+    ItemUse,
+    UseTree,
+    Visibility,
+};
+
+/// name of the module that host provided code gets placed in
+/// before we compile a guest file.
+pub const HOST_TYPES_MODULE: &str = "host_types";
+
+/// to get IDE hints in our editor, our .rs file that will be turned into a .wasm file
+/// must import the types that it references, usually via `use super::*;`.
+/// however, we compile only a single file, so those imports dont point anywhere.
+/// This function takes the guest's source code, and the code the host wants to add to it,
+/// and returns a single file where:
+/// - the host code lives in a `host_types` module
+/// - any top level `use super::..` / `use crate::..` that refers to a host item is rewritten
+///   to point to `crate::host_types`
+/// - every host item that the guest references (without importing it) gets imported
+/// - every other import the user wrote is kept as is.
+pub fn inject_host_code(guest_source: &str, host_code: &str) -> Result<String, String> {
+    let mut guest = syn::parse_file(guest_source)
+        .map_err(|e| format!("Failed to parse wasm source file\n{}", e))?;
+    let mut host = syn::parse_file(host_code)
+        .map_err(|e| format!("Failed to parse code provided by the host\n{}", e))?;
+
+    let mut host_names = HashSet::new();
+    let mut host_traits = vec![];
+    for item in host.items.iter_mut() {
+        let (ident, vis) = match item {
+            Item::Struct(x) => {
+                for field in x.fields.iter_mut() {
+                    make_crate_visible(&mut field.vis);
+                }
+                (&x.ident, &mut x.vis)
+            }
+            Item::Impl(x) => {
+                // methods that were private at the crate root
+                // must still be callable by the guest.
+                if x.trait_.is_none() {
+                    for impl_item in x.items.iter_mut() {
+                        match impl_item {
+                            syn::ImplItem::Fn(f) => make_crate_visible(&mut f.vis),
+                            syn::ImplItem::Const(c) => make_crate_visible(&mut c.vis),
+                            syn::ImplItem::Type(t) => make_crate_visible(&mut t.vis),
+                            _ => {}
+                        }
+                    }
+                }
+                continue;
+            }
+            Item::Enum(x) => (&x.ident, &mut x.vis),
+            Item::Union(x) => (&x.ident, &mut x.vis),
+            Item::Fn(x) => (&x.sig.ident, &mut x.vis),
+            Item::Const(x) => (&x.ident, &mut x.vis),
+            Item::Static(x) => (&x.ident, &mut x.vis),
+            Item::Type(x) => (&x.ident, &mut x.vis),
+            Item::Mod(x) => (&x.ident, &mut x.vis),
+            Item::Trait(x) => {
+                host_traits.push(x.ident.to_string());
+                (&x.ident, &mut x.vis)
+            }
+            _ => continue,
+        };
+        host_names.insert(ident.to_string());
+        // the guest imports these from the parent of host_types, so
+        // private items need to be at least visible within the crate.
+        make_crate_visible(vis);
+    }
+
+    let mut guest_defined = HashSet::new();
+    for item in guest.items.iter() {
+        let ident = match item {
+            Item::Struct(x) => &x.ident,
+            Item::Enum(x) => &x.ident,
+            Item::Union(x) => &x.ident,
+            Item::Fn(x) => &x.sig.ident,
+            Item::Const(x) => &x.ident,
+            Item::Static(x) => &x.ident,
+            Item::Type(x) => &x.ident,
+            Item::Mod(x) => &x.ident,
+            Item::Trait(x) => &x.ident,
+            _ => continue,
+        };
+        guest_defined.insert(ident.to_string());
+    }
+
+    // rewrite the top level imports. nested modules are left alone: their `super`
+    // points to the crate root which will have everything it needs imported.
+    let mut has_host_glob = false;
+    let mut imported_names = HashSet::new();
+    let mut items = Vec::with_capacity(guest.items.len());
+    for item in guest.items.drain(..) {
+        match item {
+            Item::Use(use_item) => {
+                for rewritten in rewrite_use(use_item, &host_names) {
+                    collect_bound_names(&rewritten.tree, &mut imported_names, &mut has_host_glob);
+                    items.push(Item::Use(rewritten));
+                }
+            }
+            item => items.push(item),
+        }
+    }
+    guest.items = items;
+
+    let mut referenced = ReferencedIdents::default();
+    referenced.visit_file(&guest);
+
+    let host_mod = format_ident!("{}", HOST_TYPES_MODULE);
+    let mut add_imports = vec![];
+    if !has_host_glob {
+        let mut names: Vec<_> = host_names.iter()
+            .filter(|n| referenced.0.contains(*n))
+            .filter(|n| !guest_defined.contains(*n))
+            .filter(|n| !imported_names.contains(*n))
+            .collect();
+        names.sort();
+        for name in names {
+            let ident = format_ident!("{}", name);
+            add_imports.push(quote! { #[allow(unused_imports)] use crate::#host_mod::#ident; });
+        }
+        // trait methods need the trait to be in scope, but the guest
+        // doesnt necessarily name the trait itself.
+        for name in host_traits.iter() {
+            if referenced.0.contains(name) {
+                continue;
+            }
+            let ident = format_ident!("{}", name);
+            add_imports.push(quote! { #[allow(unused_imports)] use crate::#host_mod::#ident as _; });
+        }
+    }
+
+    let host_items = &host.items;
+    let out = quote! {
+        #guest
+        #(#add_imports)*
+
+        #[allow(dead_code)]
+        pub mod #host_mod {
+            #[allow(unused_imports)]
+            use super::*;
+            #(#host_items)*
+        }
+    };
+    let out_file: File = syn::parse2(out)
+        .map_err(|e| format!("Failed to combine wasm source with host code\n{}", e))?;
+    Ok(prettyplease::unparse(&out_file))
+}
+
+fn make_crate_visible(vis: &mut Visibility) {
+    if let Visibility::Inherited = vis {
+        *vis = syn::parse_quote!(pub(crate));
+    }
+}
+
+/// returns the use items that should replace this one. Most of the time
+/// its just the same item. But if its something like `use super::{Thing, other::Stuff};`
+/// then it gets split such that host items are imported from host_types and the rest is kept as is.
+fn rewrite_use(use_item: ItemUse, host_names: &HashSet<String>) -> Vec<ItemUse> {
+    let (prefix, rest) = match &use_item.tree {
+        UseTree::Path(p) if p.ident == "super" || p.ident == "crate" => (p.ident.clone(), (*p.tree).clone()),
+        _ => return vec![use_item],
+    };
+    let host_mod = format_ident!("{}", HOST_TYPES_MODULE);
+    let is_host = |tree: &UseTree| match tree {
+        UseTree::Name(n) => host_names.contains(&n.ident.to_string()),
+        UseTree::Rename(r) => host_names.contains(&r.ident.to_string()),
+        UseTree::Path(p) => host_names.contains(&p.ident.to_string()),
+        UseTree::Glob(_) => true,
+        UseTree::Group(_) => false,
+    };
+    let mut host_trees = vec![];
+    let mut other_trees = vec![];
+    match rest {
+        UseTree::Group(g) => {
+            for tree in g.items {
+                if is_host(&tree) {
+                    host_trees.push(tree);
+                } else {
+                    other_trees.push(tree);
+                }
+            }
+        }
+        tree => if is_host(&tree) {
+            host_trees.push(tree);
+        } else {
+            other_trees.push(tree);
+        }
+    }
+
+    if host_trees.is_empty() {
+        return vec![use_item];
+    }
+    let mut out = vec![];
+    let attrs = &use_item.attrs;
+    let vis = &use_item.vis;
+    let host_tree = group_trees(host_trees);
+    out.push(syn::parse_quote! {
+        #(#attrs)* #vis use crate::#host_mod::#host_tree;
+    });
+    if !other_trees.is_empty() {
+        let other_tree = group_trees(other_trees);
+        out.push(syn::parse_quote! {
+            #(#attrs)* #vis use #prefix::#other_tree;
+        });
+    }
+    out
+}
+
+fn group_trees(mut trees: Vec<UseTree>) -> TokenStream {
+    if trees.len() == 1 {
+        let tree = trees.remove(0);
+        quote! { #tree }
+    } else {
+        quote! { {#(#trees),*} }
+    }
+}
+
+fn collect_bound_names(tree: &UseTree, names: &mut HashSet<String>, has_host_glob: &mut bool) {
+    match tree {
+        UseTree::Path(p) => {
+            if p.ident == HOST_TYPES_MODULE {
+                if let UseTree::Group(g) = &*p.tree {
+                    if g.items.iter().any(|t| matches!(t, UseTree::Glob(_))) {
+                        *has_host_glob = true;
+                    }
+                }
+                if let UseTree::Glob(_) = &*p.tree {
+                    *has_host_glob = true;
+                }
+            }
+            collect_bound_names(&p.tree, names, has_host_glob);
+        }
+        UseTree::Name(n) => {
+            names.insert(n.ident.to_string());
+        }
+        UseTree::Rename(r) => {
+            names.insert(r.rename.to_string());
+        }
+        UseTree::Glob(_) => {}
+        UseTree::Group(g) => {
+            for tree in g.items.iter() {
+                collect_bound_names(tree, names, has_host_glob);
+            }
+        }
+    }
+}
+
+/// every identifier that appears in the guest file, including
+/// the ones inside of macro invocations since those arent parsed by syn.
+#[derive(Default)]
+struct ReferencedIdents(HashSet<String>);
+
+impl ReferencedIdents {
+    fn add_tokens(&mut self, tokens: TokenStream) {
+        for token in tokens {
+            match token {
+                TokenTree::Ident(i) => {
+                    self.0.insert(i.to_string());
+                }
+                TokenTree::Group(g) => self.add_tokens(g.stream()),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for ReferencedIdents {
+    fn visit_ident(&mut self, i: &'ast proc_macro2::Ident) {
+        self.0.insert(i.to_string());
+    }
+    fn visit_macro(&mut self, m: &'ast syn::Macro) {
+        self.add_tokens(m.tokens.clone());
+        syn::visit::visit_macro(self, m);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "pub struct Thing { pub a: u32, b: u32 } impl Thing { fn private_method(&self) {} } struct Private; pub trait Helper { fn help(&self); }";
+
+    #[test]
+    fn rewrites_super_glob() {
+        let out = inject_host_code("use super::*;\nfn wasm_main(t: &mut Thing) {}", HOST).unwrap();
+        assert!(out.starts_with("use crate::host_types::*;"));
+    }
+
+    #[test]
+    fn rewrites_named_imports() {
+        let guest = "use super::{Thing};\nuse crate::Private as P;\nuse std::collections::HashMap;\nfn wasm_main(t: &mut Thing, p: P, m: HashMap<u32, u32>) {}";
+        let out = inject_host_code(guest, HOST).unwrap();
+        assert!(out.contains("use crate::host_types::Thing;"));
+        assert!(out.contains("use crate::host_types::Private as P;"));
+        assert!(out.contains("use std::collections::HashMap;"));
+        // Private gets bumped to pub(crate) so the guest can import it
+        assert!(out.contains("pub(crate) struct Private"));
+        // as well as private fields and methods
+        assert!(out.contains("pub(crate) b: u32"));
+        assert!(out.contains("pub(crate) fn private_method"));
+    }
+
+    #[test]
+    fn handles_odd_formatting_and_string_literals() {
+        let guest = "use super :: * ;\nfn wasm_main(t: &mut Thing) { let s = \"use super::*;\"; }";
+        let out = inject_host_code(guest, HOST).unwrap();
+        assert!(out.contains("\"use super::*;\""));
+        assert!(out.starts_with("use crate::host_types::*;"));
+    }
+
+    #[test]
+    fn imports_referenced_items_without_use() {
+        let guest = "fn wasm_main(t: &mut Thing) { let v = vec![Thing { a: 1 }]; }";
+        let out = inject_host_code(guest, HOST).unwrap();
+        assert!(out.contains("use crate::host_types::Thing;"));
+        assert!(!out.contains("use crate::host_types::Private;"));
+        // traits are always brought into scope for method calls
+        assert!(out.contains("use crate::host_types::Helper as _;"));
+    }
+
+    #[test]
+    fn keeps_crate_imports_of_guest_items() {
+        let guest = "mod inner { pub struct Mine; }\nuse crate::inner::Mine;\nfn wasm_main(t: &mut Thing, m: Mine) {}";
+        let out = inject_host_code(guest, HOST).unwrap();
+        assert!(out.contains("use crate::inner::Mine;"));
+    }
+}
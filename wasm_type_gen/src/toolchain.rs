@@ -0,0 +1,216 @@
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// the target every guest module gets compiled to
+pub const WASM_TARGET: &str = "wasm32-unknown-unknown";
+
+/// a Command for rustc. respects the `RUSTC` env var.
+/// `RUSTUP_TOOLCHAIN` is inherited by the child process, so rustup's proxy picks it up.
+pub fn rustc_command() -> Command {
+    Command::new(tool_path(Tool::Rustc))
+}
+
+/// a Command for cargo. respects the `CARGO` env var (which cargo sets itself when it runs us)
+pub fn cargo_command() -> Command {
+    Command::new(tool_path(Tool::Cargo))
+}
+
+/// a Command for rustfmt. respects the `RUSTFMT` env var.
+pub fn rustfmt_command() -> Command {
+    Command::new(tool_path(Tool::Rustfmt))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    Rustc,
+    Cargo,
+    Rustfmt,
+}
+
+impl Tool {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tool::Rustc => "rustc",
+            Tool::Cargo => "cargo",
+            Tool::Rustfmt => "rustfmt",
+        }
+    }
+    pub fn env_var(&self) -> &'static str {
+        match self {
+            Tool::Rustc => "RUSTC",
+            Tool::Cargo => "CARGO",
+            Tool::Rustfmt => "RUSTFMT",
+        }
+    }
+}
+
+pub fn tool_path(tool: Tool) -> String {
+    match std::env::var(tool.env_var()) {
+        Ok(p) if !p.is_empty() => p,
+        _ => tool.name().to_string(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Toolchain {
+    /// path or name of the rustc we invoke
+    pub rustc: String,
+    /// output of `rustc --version`
+    pub rustc_version: String,
+    /// output of `rustc --print sysroot`
+    pub sysroot: String,
+    /// value of RUSTUP_TOOLCHAIN, if set
+    pub toolchain: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolchainError {
+    /// failed to run the tool at all. usually its not on PATH.
+    MissingTool { tool: Tool, path: String, error: String },
+    /// the tool ran, but exited with an error
+    ToolFailed { tool: Tool, path: String, stderr: String },
+    /// rustc works, but the sysroot doesnt have the std library for the wasm target
+    MissingTarget { target: String, sysroot: String, toolchain: Option<String> },
+}
+
+impl ToolchainError {
+    /// the exact thing a user should run/do to fix this
+    pub fn fix(&self) -> String {
+        match self {
+            ToolchainError::MissingTool { tool, .. } => format!(
+                "Install Rust via https://rustup.rs (which provides {}), or set the {} env var to the full path of {}",
+                tool.name(), tool.env_var(), tool.name(),
+            ),
+            ToolchainError::ToolFailed { tool, .. } => format!(
+                "Check that `{} --version` works in this environment, or set the {} env var to a working {}",
+                tool.name(), tool.env_var(), tool.name(),
+            ),
+            ToolchainError::MissingTarget { target, toolchain, .. } => match toolchain {
+                Some(t) => format!("rustup target add {target} --toolchain {t}"),
+                None => format!("rustup target add {target}"),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for ToolchainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ToolchainError::MissingTool { tool, path, error } => {
+                write!(f, "Failed to run {} ('{}'): {}", tool.name(), path, error)?;
+            }
+            ToolchainError::ToolFailed { tool, path, stderr } => {
+                write!(f, "{} ('{}') failed:\n{}", tool.name(), path, stderr)?;
+            }
+            ToolchainError::MissingTarget { target, sysroot, .. } => {
+                write!(f, "The {target} target is not installed for the toolchain at {sysroot}")?;
+            }
+        }
+        write!(f, "\nTo fix this: {}", self.fix())
+    }
+}
+
+impl From<ToolchainError> for String {
+    fn from(value: ToolchainError) -> Self {
+        value.to_string()
+    }
+}
+
+fn run_tool(tool: Tool, path: &str, args: &[&str]) -> Result<String, ToolchainError> {
+    let out = Command::new(path).args(args).output().map_err(|e| ToolchainError::MissingTool {
+        tool, path: path.to_string(), error: e.to_string(),
+    })?;
+    if !out.status.success() {
+        return Err(ToolchainError::ToolFailed {
+            tool, path: path.to_string(), stderr: String::from_utf8_lossy(&out.stderr).to_string(),
+        });
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+/// directory that must exist in the sysroot for us to be able to compile to the target
+pub fn target_lib_dir(sysroot: &str, target: &str) -> String {
+    format!("{sysroot}/lib/rustlib/{target}/lib")
+}
+
+fn check_rustc(rustc: &str, toolchain: Option<String>) -> Result<Toolchain, ToolchainError> {
+    let rustc_version = run_tool(Tool::Rustc, rustc, &["--version"])?;
+    let sysroot = run_tool(Tool::Rustc, rustc, &["--print", "sysroot"])?;
+    if !std::path::Path::new(&target_lib_dir(&sysroot, WASM_TARGET)).is_dir() {
+        return Err(ToolchainError::MissingTarget { target: WASM_TARGET.to_string(), sysroot, toolchain });
+    }
+    Ok(Toolchain { rustc: rustc.to_string(), rustc_version, sysroot, toolchain })
+}
+
+/// Verify that we can compile guest modules: rustc runs, and
+/// the wasm target is installed for the active toolchain.
+pub fn preflight() -> Result<Toolchain, ToolchainError> {
+    let toolchain = std::env::var("RUSTUP_TOOLCHAIN").ok().filter(|t| !t.is_empty());
+    check_rustc(&tool_path(Tool::Rustc), toolchain)
+}
+
+/// only a successful check is kept. an error is checked again next time,
+/// so installing the missing target fixes a long running process (ie: rust-analyzer)
+fn cached_check(
+    cache: &OnceLock<Toolchain>,
+    check: impl FnOnce() -> Result<Toolchain, ToolchainError>,
+) -> Result<Toolchain, ToolchainError> {
+    if let Some(toolchain) = cache.get() {
+        return Ok(toolchain.clone());
+    }
+    let toolchain = check()?;
+    Ok(cache.get_or_init(|| toolchain).clone())
+}
+
+/// same as `preflight` but once the checks pass they are not run again in this process.
+/// this is what the compile functions use before invoking rustc.
+pub fn preflight_cached() -> Result<Toolchain, ToolchainError> {
+    static PREFLIGHT: OnceLock<Toolchain> = OnceLock::new();
+    cached_check(&PREFLIGHT, preflight)
+}
+
+/// checks that an auxiliary tool (cargo or rustfmt) can be run. returns its version string.
+pub fn check_tool(tool: Tool) -> Result<String, ToolchainError> {
+    run_tool(tool, &tool_path(tool), &["--version"])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_rustc_is_reported() {
+        let err = check_rustc("/this/rustc/does/not/exist", None).unwrap_err();
+        assert!(matches!(err, ToolchainError::MissingTool { tool: Tool::Rustc, .. }));
+        assert!(err.to_string().contains("RUSTC env var"));
+    }
+
+    #[test]
+    fn only_successful_checks_are_cached() {
+        let cache = OnceLock::new();
+        let err = cached_check(&cache, || check_rustc("/this/rustc/does/not/exist", None));
+        assert!(err.is_err());
+        let toolchain = Toolchain {
+            rustc: "rustc".into(),
+            rustc_version: "rustc 1.0.0".into(),
+            sysroot: "/x".into(),
+            toolchain: None,
+        };
+        let ok = cached_check(&cache, || Ok(toolchain.clone())).unwrap();
+        assert_eq!(ok.rustc_version, "rustc 1.0.0");
+        let cached = cached_check(&cache, || panic!("should use the cached toolchain")).unwrap();
+        assert_eq!(cached.rustc_version, "rustc 1.0.0");
+    }
+
+    #[test]
+    fn missing_target_fix_uses_toolchain() {
+        let err = ToolchainError::MissingTarget {
+            target: WASM_TARGET.into(),
+            sysroot: "/x".into(),
+            toolchain: Some("nightly".into()),
+        };
+        assert_eq!(err.fix(), "rustup target add wasm32-unknown-unknown --toolchain nightly");
+        let err = ToolchainError::MissingTarget { target: WASM_TARGET.into(), sysroot: "/x".into(), toolchain: None };
+        assert!(err.to_string().ends_with("To fix this: rustup target add wasm32-unknown-unknown"));
+    }
+}
@@ -0,0 +1,99 @@
+//! `WasmRuntime` implemented with the wasmi interpreter. Enabled by the `wasmi` feature.
+
+use std::sync::Arc;
+
+use wasmi::{Caller, Engine, Extern, Instance, Linker, Module, Store};
+
+use crate::runtime::{copy_guest_data_to_host, copy_host_data_to_guest, WasmRuntime};
+
+/// wasmi doesn't support threads or SIMD, so guests run deterministically on a given platform.
+/// Unlike `WasmtimeRuntime` it can't canonicalize NaNs though, so the bits of a NaN can
+/// differ between cpu architectures. All other float results are the same on every platform.
+/// A guest whose output must be reproducible everywhere shouldn't write out the bits of a NaN
+/// it computed (eg: check `is_nan()` and write a fixed value instead).
+#[derive(Default)]
+pub struct WasmiRuntime {
+    pub engine: Engine,
+}
+
+/// wasmi can't pre instantiate a module outside of a store, so we keep the linker around instead
+pub struct WasmiLinked {
+    linker: Linker<Vec<u8>>,
+    module: Arc<Module>,
+}
+
+pub struct WasmiInstance {
+    store: Store<Vec<u8>>,
+    instance: Instance,
+}
+
+impl WasmiInstance {
+    fn memory(&self) -> Result<wasmi::Memory, String> {
+        self.instance.get_memory(&self.store, "memory").ok_or("wasm module has no exported memory".into())
+    }
+}
+
+fn exported_memory(caller: &Caller<'_, Vec<u8>>) -> Option<wasmi::Memory> {
+    caller.get_export("memory").and_then(Extern::into_memory)
+}
+
+impl WasmRuntime for WasmiRuntime {
+    type Module = Arc<Module>;
+    type Linked = WasmiLinked;
+    type Instance = WasmiInstance;
+
+    fn load(&self, wasm_data: &[u8]) -> Result<Arc<Module>, String> {
+        Module::new(&self.engine, wasm_data).map(Arc::new).map_err(|e| format!("failed to load wasm module {e}"))
+    }
+
+    fn prelink(&self, module: &Arc<Module>) -> Result<WasmiLinked, String> {
+        let mut linker: Linker<Vec<u8>> = Linker::new(&self.engine);
+        let link_err = |e| format!("failed to link host functions {e}");
+        linker.func_wrap("env", "get_entrypoint_alloc_size", |caller: Caller<'_, Vec<u8>>| -> u32 {
+            caller.data().len() as u32
+        }).map_err(link_err)?;
+        linker.func_wrap("env", "get_entrypoint_data", |mut caller: Caller<'_, Vec<u8>>, ptr: u32, len: u32| {
+            if let Some(mem) = exported_memory(&caller) {
+                let (memory, host_data) = mem.data_and_store_mut(&mut caller);
+                copy_host_data_to_guest(host_data, memory, ptr, len);
+            }
+        }).map_err(link_err)?;
+        linker.func_wrap("env", "set_entrypoint_data", |mut caller: Caller<'_, Vec<u8>>, ptr: u32, len: u32| {
+            if let Some(mem) = exported_memory(&caller) {
+                let (memory, host_data) = mem.data_and_store_mut(&mut caller);
+                copy_guest_data_to_host(memory, host_data, ptr, len);
+            }
+        }).map_err(link_err)?;
+        Ok(WasmiLinked { linker, module: module.clone() })
+    }
+
+    fn instantiate(&self, linked: &WasmiLinked, host_data: Vec<u8>) -> Result<WasmiInstance, String> {
+        let mut store = Store::new(&self.engine, host_data);
+        let instance = linked.linker.instantiate(&mut store, &linked.module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|e| format!("failed to instantiate wasm module {e}"))?;
+        Ok(WasmiInstance { store, instance })
+    }
+
+    fn call_entrypoint(&self, instance: &mut WasmiInstance) -> Result<u32, String> {
+        let func = instance.instance.get_typed_func::<(), u32>(&instance.store, "wasm_entrypoint")
+            .map_err(|e| format!("wasm module has no valid wasm_entrypoint {e}"))?;
+        func.call(&mut instance.store, ()).map_err(|e| format!("wasm module trapped {e}"))
+    }
+
+    fn read_memory(&self, instance: &mut WasmiInstance, ptr: usize, len: usize) -> Result<Vec<u8>, String> {
+        let mem = instance.memory()?;
+        mem.data(&instance.store).get(ptr..ptr + len).map(|d| d.to_vec())
+            .ok_or(format!("memory range {ptr}..{} is out of bounds", ptr + len))
+    }
+
+    fn write_memory(&self, instance: &mut WasmiInstance, ptr: usize, data: &[u8]) -> Result<(), String> {
+        let mem = instance.memory()?;
+        mem.write(&mut instance.store, ptr, data)
+            .map_err(|_| format!("memory range {ptr}..{} is out of bounds", ptr + data.len()))
+    }
+
+    fn take_host_data(&self, instance: WasmiInstance) -> Vec<u8> {
+        instance.store.into_data()
+    }
+}
@@ -0,0 +1,142 @@
+//! `WasmRuntime` implemented with wasmtime. Enabled by the `wasmtime` feature.
+
+use wasmtime::{
+    Caller, Config, Engine, Extern, Instance, InstanceAllocationStrategy, InstancePre, Linker, Module,
+    PoolingAllocationConfig, Store,
+};
+
+use crate::runtime::{copy_guest_data_to_host, copy_host_data_to_guest, WasmRuntime};
+
+/// A config where running the same module on the same input always produces the same output,
+/// so that guests run during compilation don't make builds unreproducible:
+/// - NaNs are canonicalized. Otherwise the bits of a NaN depend on the cpu
+/// - no threads, so no races. (relaxed SIMD, whose results depend on the cpu, isn't supported by this wasmtime at all)
+///
+/// Guests can't get at time or randomness either, since the only imports we provide are the entrypoint ABI's.
+///
+/// This only covers `WasmtimeRuntime`. `WasmiRuntime` can't canonicalize NaNs, so guests run on it
+/// (eg: example2_derive's modules, unless its `wasmtime` feature is on) can produce NaNs whose bits
+/// differ between cpu architectures. Every other float result is still the same everywhere.
+pub fn deterministic_config() -> Config {
+    let mut config = Config::new();
+    config.cranelift_nan_canonicalization(true);
+    config.wasm_threads(false);
+    config
+}
+
+/// The default runtime uses `deterministic_config`. Use `WasmtimeRuntime::new` for anything else.
+pub struct WasmtimeRuntime {
+    pub engine: Engine,
+    /// the pooling allocator's instance limit, see `pooling`
+    max_instances: Option<usize>,
+}
+
+impl Default for WasmtimeRuntime {
+    fn default() -> Self {
+        let engine = Engine::new(&deterministic_config()).expect("deterministic wasmtime config is always valid");
+        Self { engine, max_instances: None }
+    }
+}
+
+impl WasmtimeRuntime {
+    /// note: if `config` uses the pooling allocator, we dont know its instance limit.
+    /// use `pooling` instead, so that `BatchExecutor` stays within it.
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let engine = Engine::new(config).map_err(|e| format!("Invalid wasmtime config {:?}", e))?;
+        Ok(Self { engine, max_instances: None })
+    }
+
+    /// a runtime that uses wasmtime's pooling allocator, which makes creating instances much cheaper
+    /// when running a module many times (see `BatchExecutor`). At most `max_instances` can exist at once,
+    /// and each of them can use at most `max_memory_pages` pages (64KiB each) of memory.
+    pub fn pooling(max_instances: u32, max_memory_pages: u64) -> Result<Self, String> {
+        let mut pooling = PoolingAllocationConfig::default();
+        pooling.instance_count(max_instances).instance_memory_pages(max_memory_pages);
+        let mut config = deterministic_config();
+        config.allocation_strategy(InstanceAllocationStrategy::Pooling(pooling));
+        let mut runtime = Self::new(&config)?;
+        runtime.max_instances = Some(max_instances as usize);
+        Ok(runtime)
+    }
+}
+
+pub struct WasmtimeInstance {
+    store: Store<Vec<u8>>,
+    instance: Instance,
+}
+
+impl WasmtimeInstance {
+    fn memory(&mut self) -> Result<wasmtime::Memory, String> {
+        self.instance.get_memory(&mut self.store, "memory").ok_or("wasm module has no exported memory".into())
+    }
+}
+
+fn exported_memory(caller: &mut Caller<'_, Vec<u8>>) -> Option<wasmtime::Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(mem)) => Some(mem),
+        _ => None,
+    }
+}
+
+impl WasmRuntime for WasmtimeRuntime {
+    type Module = Module;
+    type Linked = InstancePre<Vec<u8>>;
+    type Instance = WasmtimeInstance;
+
+    fn load(&self, wasm_data: &[u8]) -> Result<Module, String> {
+        Module::from_binary(&self.engine, wasm_data).map_err(|e| format!("failed to load wasm module {:?}", e))
+    }
+
+    fn prelink(&self, module: &Module) -> Result<InstancePre<Vec<u8>>, String> {
+        let mut linker: Linker<Vec<u8>> = Linker::new(&self.engine);
+        let link_err = |e| format!("failed to link host functions {:?}", e);
+        linker.func_wrap("env", "get_entrypoint_alloc_size", |caller: Caller<'_, Vec<u8>>| -> u32 {
+            caller.data().len() as u32
+        }).map_err(link_err)?;
+        linker.func_wrap("env", "get_entrypoint_data", |mut caller: Caller<'_, Vec<u8>>, ptr: u32, len: u32| {
+            if let Some(mem) = exported_memory(&mut caller) {
+                let (memory, host_data) = mem.data_and_store_mut(&mut caller);
+                copy_host_data_to_guest(host_data, memory, ptr, len);
+            }
+        }).map_err(link_err)?;
+        linker.func_wrap("env", "set_entrypoint_data", |mut caller: Caller<'_, Vec<u8>>, ptr: u32, len: u32| {
+            if let Some(mem) = exported_memory(&mut caller) {
+                let (memory, host_data) = mem.data_and_store_mut(&mut caller);
+                copy_guest_data_to_host(memory, host_data, ptr, len);
+            }
+        }).map_err(link_err)?;
+        linker.instantiate_pre(module).map_err(|e| format!("failed to link wasm module {:?}", e))
+    }
+
+    fn instantiate(&self, linked: &InstancePre<Vec<u8>>, host_data: Vec<u8>) -> Result<WasmtimeInstance, String> {
+        let mut store = Store::new(&self.engine, host_data);
+        let instance = linked.instantiate(&mut store).map_err(|e| format!("failed to instantiate wasm module {:?}", e))?;
+        Ok(WasmtimeInstance { store, instance })
+    }
+
+    fn call_entrypoint(&self, instance: &mut WasmtimeInstance) -> Result<u32, String> {
+        let func = instance.instance.get_typed_func::<(), u32>(&mut instance.store, "wasm_entrypoint")
+            .map_err(|e| format!("wasm module has no valid wasm_entrypoint {:?}", e))?;
+        func.call(&mut instance.store, ()).map_err(|e| format!("wasm module trapped {:?}", e))
+    }
+
+    fn read_memory(&self, instance: &mut WasmtimeInstance, ptr: usize, len: usize) -> Result<Vec<u8>, String> {
+        let mem = instance.memory()?;
+        mem.data(&instance.store).get(ptr..ptr + len).map(|d| d.to_vec())
+            .ok_or(format!("memory range {ptr}..{} is out of bounds", ptr + len))
+    }
+
+    fn write_memory(&self, instance: &mut WasmtimeInstance, ptr: usize, data: &[u8]) -> Result<(), String> {
+        let mem = instance.memory()?;
+        mem.write(&mut instance.store, ptr, data)
+            .map_err(|_| format!("memory range {ptr}..{} is out of bounds", ptr + data.len()))
+    }
+
+    fn take_host_data(&self, instance: WasmtimeInstance) -> Vec<u8> {
+        instance.store.into_data()
+    }
+
+    fn max_concurrency(&self) -> Option<usize> {
+        self.max_instances
+    }
+}
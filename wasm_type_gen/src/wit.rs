@@ -0,0 +1,149 @@
+//! Describe guest interfaces with WIT (WebAssembly Interface Types) so that guests can be
+//! built as components, and used by any component host rather than just this crate.
+//! With the `component-model` feature, the component module builds guests that implement
+//! `wit_world`, and runs them with wasmtime's component model API. The entrypoint ABI stays
+//! the default, see `compile_and_run_wasm`.
+//!
+//! Every `#[derive(WasmTypeGen)]` type implements `WitType`:
+//! - structs become records. tuple structs become records with fields named `f0`, `f1`, ...
+//! - enums whose variants have no data become enums. other enums become variants,
+//!   where a case with more than one field gets a tuple payload
+//! - `Vec<T>` and `[T; N]` become `list<T>`, `HashMap<K, V>` becomes `list<tuple<K, V>>`
+//! - `Option<T>` and `Result<T, E>` become `option<T>` and `result<T, E>`
+//! - `isize`/`usize` become `s64`/`u64`. `i128`/`u128` have no WIT equivalent, so types
+//!   that contain them dont implement `WitType`
+//!
+//! Rust names are converted to kebab-case, eg: `MyStruct { some_field }` -> `record my-struct { some-field }`.
+
+use crate::WitType;
+
+/// name of the function every guest world exports. it takes the input type and returns it (modified)
+pub const WIT_ENTRYPOINT: &str = "wasm-entrypoint";
+
+/// the WIT definitions of `T` and of every type it contains
+pub fn wit_definitions<T: WitType>() -> Vec<String> {
+    let mut defs = vec![];
+    T::add_wit_definitions(&mut defs);
+    defs
+}
+
+/// A complete WIT package for a guest that takes a `T` and returns it:
+/// ```text
+/// package {package};
+///
+/// interface types {
+///     record my-struct { ... }
+/// }
+///
+/// world {world} {
+///     use types.{my-struct};
+///     export wasm-entrypoint: func(input: my-struct) -> my-struct;
+/// }
+/// ```
+/// `package` is of the form `namespace:name`, and `world` must be a valid WIT identifier.
+pub fn wit_world<T: WitType>(package: &str, world: &str) -> String {
+    let ty = T::wit_type_name();
+    let mut out = format!("package {package};\n\ninterface types {{\n");
+    for def in wit_definitions::<T>() {
+        for line in def.lines() {
+            out.push_str("    ");
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out.push_str("}\n\n");
+    out.push_str(&format!("world {world} {{\n"));
+    out.push_str(&format!("    use types.{{{ty}}};\n"));
+    out.push_str(&format!("    export {WIT_ENTRYPOINT}: func(input: {ty}) -> {ty};\n"));
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use super::*;
+
+    #[derive(WasmTypeGen, Debug)]
+    pub struct Inner {
+        pub name_value: String,
+        pub scores: Vec<f32>,
+    }
+
+    #[derive(WasmTypeGen, Debug)]
+    pub enum Kind {
+        Plain,
+        Fancy,
+    }
+
+    #[derive(WasmTypeGen, Debug)]
+    pub enum Shape {
+        Empty,
+        Circle(f64),
+        Rect { w: u32, h: u32 },
+    }
+
+    #[derive(WasmTypeGen, Debug)]
+    pub struct Pair(u8, Option<Inner>);
+
+    #[derive(WasmTypeGen, Debug)]
+    pub struct MyStruct {
+        pub inner: Inner,
+        pub r#type: Kind,
+        pub shapes: Vec<Shape>,
+        pub pair: Pair,
+        pub lookup: std::collections::HashMap<String, Result<u64, String>>,
+        pub also_inner: Option<Inner>,
+    }
+
+    #[test]
+    fn derives_wit_definitions() {
+        assert_eq!(MyStruct::wit_type_name(), "my-struct");
+        let defs = wit_definitions::<MyStruct>();
+        assert_eq!(defs.len(), 5);
+        assert_eq!(defs[0], "record my-struct {\n    inner: inner,\n    %type: kind,\n    shapes: list<shape>,\n    pair: pair,\n    lookup: list<tuple<string, result<u64, string>>>,\n    also-inner: option<inner>,\n}");
+        assert!(defs.contains(&"record inner {\n    name-value: string,\n    scores: list<f32>,\n}".to_string()));
+        assert!(defs.contains(&"enum kind {\n    plain,\n    fancy,\n}".to_string()));
+        assert!(defs.contains(&"variant shape {\n    empty,\n    circle(f64),\n    rect(tuple<u32, u32>),\n}".to_string()));
+        assert!(defs.contains(&"record pair {\n    f0: u8,\n    f1: option<inner>,\n}".to_string()));
+    }
+
+    #[derive(WasmTypeGen, Debug)]
+    pub struct Big {
+        pub value: i128,
+    }
+
+    #[derive(WasmTypeGen, Debug)]
+    pub struct HasBig {
+        pub big: Big,
+        pub small: u8,
+    }
+
+    /// `Probe::<T>::is_wit()` is the inherent method if T is a WitType, and the trait's otherwise
+    struct Probe<T>(std::marker::PhantomData<T>);
+    trait NotWit {
+        fn is_wit(&self) -> bool { false }
+    }
+    impl<T> NotWit for Probe<T> {}
+    impl<T: WitType> Probe<T> {
+        fn is_wit(&self) -> bool { true }
+    }
+
+    #[test]
+    fn types_containing_i128_are_not_wit_types() {
+        assert!(Probe::<MyStruct>(std::marker::PhantomData).is_wit());
+        assert!(!Probe::<i128>(std::marker::PhantomData).is_wit());
+        assert!(!Probe::<Big>(std::marker::PhantomData).is_wit());
+        assert!(!Probe::<HasBig>(std::marker::PhantomData).is_wit());
+    }
+
+    #[test]
+    fn generated_world_is_valid_wit() {
+        let wit = wit_world::<MyStruct>("wasm-type-gen:test", "guest");
+        let mut resolve = wit_parser::Resolve::default();
+        let unresolved = wit_parser::UnresolvedPackage::parse("guest.wit".as_ref(), &wit).unwrap();
+        let pkg = resolve.push(unresolved).unwrap();
+        let world = resolve.select_world(pkg, Some("guest")).unwrap();
+        assert_eq!(resolve.worlds[world].exports.len(), 1);
+    }
+}
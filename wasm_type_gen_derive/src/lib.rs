@@ -135,7 +135,14 @@ pub fn generate_parsing_traits(_item: proc_macro::TokenStream) -> proc_macro::To
         }
 
         pub trait WasmIncludeString {
-            fn include_in_rs_wasm() -> String;
+            /// adds (type name, definition) for this type, and then for the types it uses.
+            /// types that are already in defs are skipped, so each is only defined once
+            fn include_defs(defs: &mut Vec<(String, String)>);
+            fn include_in_rs_wasm() -> String {
+                let mut defs = vec![];
+                Self::include_defs(&mut defs);
+                defs.into_iter().map(|(_, def)| def).collect::<Vec<_>>().join("\n")
+            }
             fn gen_entrypoint() -> &'static str;
         }
 
@@ -705,16 +712,697 @@ pub fn generate_parsing_traits(_item: proc_macro::TokenStream) -> proc_macro::To
         }
     };
 
-    let trait_stuff_str = trait_stuff.to_string();
+    // the canonical ABI is only used by guests that are built as components (see wasm_type_gen::component),
+    // so unlike the traits above, this is only part of WASM_PARSING_TRAIT_STR
+    let canonical_primitives = [
+        "i8", "u8", "i16", "u16", "i32", "u32", "i64", "u64", "isize", "usize", "f32", "f64", "bool", "char",
+    ].map(|ty| {
+        let ty_ident = format_ident!("{ty}");
+        // how its stored in memory. isize/usize are s64/u64 in WIT, but 4 bytes in a wasm32 guest
+        let (mem_ty, size) = match ty {
+            "i8" | "u8" | "bool" => (format_ident!("u8"), 1usize),
+            "i16" | "u16" => (format_ident!("{ty}"), 2),
+            "i32" | "u32" | "f32" => (format_ident!("{ty}"), 4),
+            "char" => (format_ident!("u32"), 4),
+            "isize" => (format_ident!("i64"), 8),
+            "usize" => (format_ident!("u64"), 8),
+            _ => (format_ident!("{ty}"), 8),
+        };
+        let (from_mem, to_mem, from_flat, to_flat) = match ty {
+            "bool" => (quote!(v != 0), quote!(*self as u8), quote!(flat[0] != 0), quote!(*self as u64)),
+            "char" => (
+                quote!(char::from_u32(v).unwrap_or_default()), quote!(*self as u32),
+                quote!(char::from_u32(flat[0] as u32).unwrap_or_default()), quote!(*self as u64),
+            ),
+            "f32" => (quote!(v), quote!(*self), quote!(f32::from_bits(flat[0] as u32)), quote!(self.to_bits() as u64)),
+            "f64" => (quote!(v), quote!(*self), quote!(f64::from_bits(flat[0])), quote!(self.to_bits())),
+            _ => (quote!(v as #ty_ident), quote!(*self as #mem_ty), quote!(flat[0] as #ty_ident), quote!(*self as u64)),
+        };
+        quote! {
+            impl CanonicalAbi for #ty_ident {
+                const ALIGN: usize = #size;
+                const SIZE: usize = #size;
+                const FLAT: usize = 1;
+                unsafe fn load(ptr: *const u8) -> Self {
+                    let v = (ptr as *const #mem_ty).read_unaligned();
+                    #from_mem
+                }
+                unsafe fn store(&self, ptr: *mut u8) {
+                    (ptr as *mut #mem_ty).write_unaligned(#to_mem);
+                }
+                fn lift_flat(flat: &[u64]) -> Self {
+                    #from_flat
+                }
+                fn lower_flat(&self, flat: &mut Vec<u64>) {
+                    flat.push(#to_flat);
+                }
+            }
+        }
+    });
+    let canonical_abi_stuff = quote! {
+        /// the component model's canonical ABI, which is how a component's values are laid out in its memory
+        /// (load / store) and passed as function parameters and results (lift_flat / lower_flat).
+        /// flat values are passed around as their bits, zero extended to 64 bits
+        pub trait CanonicalAbi: Sized {
+            const ALIGN: usize;
+            const SIZE: usize;
+            /// how many flat values this is passed as
+            const FLAT: usize;
+            unsafe fn load(ptr: *const u8) -> Self;
+            unsafe fn store(&self, ptr: *mut u8);
+            /// flat starts at this value's flat values, and may have more after them
+            fn lift_flat(flat: &[u64]) -> Self;
+            fn lower_flat(&self, flat: &mut Vec<u64>);
+        }
+
+        pub const fn canonical_align_to(offset: usize, align: usize) -> usize {
+            (offset + align - 1) / align * align
+        }
+
+        pub const fn canonical_max(a: usize, b: usize) -> usize {
+            if a > b { a } else { b }
+        }
+
+        /// memory for values that the host reads after the call. its never freed, a guest instance only runs once
+        pub unsafe fn canonical_alloc(size: usize, align: usize) -> *mut u8 {
+            if size == 0 {
+                return align as *mut u8;
+            }
+            ::std::alloc::alloc(::std::alloc::Layout::from_size_align_unchecked(size, align))
+        }
+
+        /// lists are a pointer to their items, and their length
+        pub unsafe fn canonical_load_list<T: CanonicalAbi>(list: usize, len: usize) -> Vec<T> {
+            (0..len).map(|i| T::load((list + i * T::SIZE) as *const u8)).collect()
+        }
+
+        pub fn canonical_store_list<T: CanonicalAbi>(items: &[T]) -> (usize, usize) {
+            unsafe {
+                let list = canonical_alloc(items.len() * T::SIZE, T::ALIGN);
+                for (i, item) in items.iter().enumerate() {
+                    item.store(list.add(i * T::SIZE));
+                }
+                (list as usize, items.len())
+            }
+        }
+
+        pub unsafe fn canonical_load_ptr_len(ptr: *const u8) -> (usize, usize) {
+            let list = (ptr as *const u32).read_unaligned() as usize;
+            let len = (ptr.add(4) as *const u32).read_unaligned() as usize;
+            (list, len)
+        }
+
+        pub unsafe fn canonical_store_ptr_len(ptr: *mut u8, (list, len): (usize, usize)) {
+            (ptr as *mut u32).write_unaligned(list as u32);
+            (ptr.add(4) as *mut u32).write_unaligned(len as u32);
+        }
+
+        #(#canonical_primitives)*
+
+        impl CanonicalAbi for String {
+            const ALIGN: usize = 4;
+            const SIZE: usize = 8;
+            const FLAT: usize = 2;
+            unsafe fn load(ptr: *const u8) -> Self {
+                let (list, len) = canonical_load_ptr_len(ptr);
+                Self::lift_flat(&[list as u64, len as u64])
+            }
+            unsafe fn store(&self, ptr: *mut u8) {
+                canonical_store_ptr_len(ptr, canonical_store_list(self.as_bytes()));
+            }
+            fn lift_flat(flat: &[u64]) -> Self {
+                let bytes = unsafe { ::std::slice::from_raw_parts(flat[0] as usize as *const u8, flat[1] as usize) };
+                String::from_utf8_lossy(bytes).into_owned()
+            }
+            fn lower_flat(&self, flat: &mut Vec<u64>) {
+                let (list, len) = canonical_store_list(self.as_bytes());
+                flat.extend([list as u64, len as u64]);
+            }
+        }
+
+        impl<T: CanonicalAbi> CanonicalAbi for Vec<T> {
+            const ALIGN: usize = 4;
+            const SIZE: usize = 8;
+            const FLAT: usize = 2;
+            unsafe fn load(ptr: *const u8) -> Self {
+                let (list, len) = canonical_load_ptr_len(ptr);
+                canonical_load_list(list, len)
+            }
+            unsafe fn store(&self, ptr: *mut u8) {
+                canonical_store_ptr_len(ptr, canonical_store_list(self));
+            }
+            fn lift_flat(flat: &[u64]) -> Self {
+                unsafe { canonical_load_list(flat[0] as usize, flat[1] as usize) }
+            }
+            fn lower_flat(&self, flat: &mut Vec<u64>) {
+                let (list, len) = canonical_store_list(self);
+                flat.extend([list as u64, len as u64]);
+            }
+        }
+
+        impl<T: CanonicalAbi, const N: usize> CanonicalAbi for [T; N] {
+            const ALIGN: usize = 4;
+            const SIZE: usize = 8;
+            const FLAT: usize = 2;
+            unsafe fn load(ptr: *const u8) -> Self {
+                let (list, len) = canonical_load_ptr_len(ptr);
+                Self::lift_flat(&[list as u64, len as u64])
+            }
+            unsafe fn store(&self, ptr: *mut u8) {
+                canonical_store_ptr_len(ptr, canonical_store_list(self));
+            }
+            fn lift_flat(flat: &[u64]) -> Self {
+                let items: Vec<T> = unsafe { canonical_load_list(flat[0] as usize, flat[1] as usize) };
+                match ::std::convert::TryInto::try_into(items) {
+                    Ok(items) => items,
+                    Err(items) => panic!("expected a list of {} items, got {}", N, items.len()),
+                }
+            }
+            fn lower_flat(&self, flat: &mut Vec<u64>) {
+                let (list, len) = canonical_store_list(self);
+                flat.extend([list as u64, len as u64]);
+            }
+        }
+
+        /// tuple<T, U>, which is what a HashMap's entries are
+        impl<T: CanonicalAbi, U: CanonicalAbi> CanonicalAbi for (T, U) {
+            const ALIGN: usize = canonical_max(T::ALIGN, U::ALIGN);
+            const SIZE: usize = canonical_align_to(canonical_align_to(T::SIZE, U::ALIGN) + U::SIZE, Self::ALIGN);
+            const FLAT: usize = T::FLAT + U::FLAT;
+            unsafe fn load(ptr: *const u8) -> Self {
+                (T::load(ptr), U::load(ptr.add(canonical_align_to(T::SIZE, U::ALIGN))))
+            }
+            unsafe fn store(&self, ptr: *mut u8) {
+                self.0.store(ptr);
+                self.1.store(ptr.add(canonical_align_to(T::SIZE, U::ALIGN)));
+            }
+            fn lift_flat(flat: &[u64]) -> Self {
+                (T::lift_flat(flat), U::lift_flat(&flat[T::FLAT..]))
+            }
+            fn lower_flat(&self, flat: &mut Vec<u64>) {
+                self.0.lower_flat(flat);
+                self.1.lower_flat(flat);
+            }
+        }
+
+        impl<T: CanonicalAbi + ::std::hash::Hash + Eq, U: CanonicalAbi> CanonicalAbi for ::std::collections::HashMap<T, U> {
+            const ALIGN: usize = 4;
+            const SIZE: usize = 8;
+            const FLAT: usize = 2;
+            unsafe fn load(ptr: *const u8) -> Self {
+                let (list, len) = canonical_load_ptr_len(ptr);
+                Self::lift_flat(&[list as u64, len as u64])
+            }
+            unsafe fn store(&self, ptr: *mut u8) {
+                let mut flat = vec![];
+                self.lower_flat(&mut flat);
+                canonical_store_ptr_len(ptr, (flat[0] as usize, flat[1] as usize));
+            }
+            fn lift_flat(flat: &[u64]) -> Self {
+                let entries: Vec<(T, U)> = unsafe { canonical_load_list(flat[0] as usize, flat[1] as usize) };
+                entries.into_iter().collect()
+            }
+            fn lower_flat(&self, flat: &mut Vec<u64>) {
+                // the entries are laid out like (T, U), but we only have references to them
+                unsafe {
+                    let list = canonical_alloc(self.len() * <(T, U)>::SIZE, <(T, U)>::ALIGN);
+                    for (i, (key, value)) in self.iter().enumerate() {
+                        let entry = list.add(i * <(T, U)>::SIZE);
+                        key.store(entry);
+                        value.store(entry.add(canonical_align_to(T::SIZE, U::ALIGN)));
+                    }
+                    flat.extend([list as usize as u64, self.len() as u64]);
+                }
+            }
+        }
+
+        /// option<T> is a variant with the cases none and some(T)
+        impl<T: CanonicalAbi> CanonicalAbi for Option<T> {
+            const ALIGN: usize = canonical_max(1, T::ALIGN);
+            const SIZE: usize = canonical_align_to(canonical_align_to(1, T::ALIGN) + T::SIZE, Self::ALIGN);
+            const FLAT: usize = 1 + T::FLAT;
+            unsafe fn load(ptr: *const u8) -> Self {
+                match *ptr {
+                    0 => None,
+                    _ => Some(T::load(ptr.add(canonical_align_to(1, T::ALIGN)))),
+                }
+            }
+            unsafe fn store(&self, ptr: *mut u8) {
+                match self {
+                    None => *ptr = 0,
+                    Some(t) => {
+                        *ptr = 1;
+                        t.store(ptr.add(canonical_align_to(1, T::ALIGN)));
+                    }
+                }
+            }
+            fn lift_flat(flat: &[u64]) -> Self {
+                match flat[0] {
+                    0 => None,
+                    _ => Some(T::lift_flat(&flat[1..])),
+                }
+            }
+            fn lower_flat(&self, flat: &mut Vec<u64>) {
+                let start = flat.len();
+                match self {
+                    None => flat.push(0),
+                    Some(t) => {
+                        flat.push(1);
+                        t.lower_flat(flat);
+                    }
+                }
+                flat.resize(start + Self::FLAT, 0);
+            }
+        }
+
+        /// result<T, E> is a variant with the cases ok(T) and err(E)
+        impl<T: CanonicalAbi, E: CanonicalAbi> CanonicalAbi for Result<T, E> {
+            const ALIGN: usize = canonical_max(1, canonical_max(T::ALIGN, E::ALIGN));
+            const SIZE: usize = canonical_align_to(
+                canonical_align_to(1, canonical_max(T::ALIGN, E::ALIGN)) + canonical_max(T::SIZE, E::SIZE),
+                Self::ALIGN,
+            );
+            const FLAT: usize = 1 + canonical_max(T::FLAT, E::FLAT);
+            unsafe fn load(ptr: *const u8) -> Self {
+                let payload = ptr.add(canonical_align_to(1, canonical_max(T::ALIGN, E::ALIGN)));
+                match *ptr {
+                    0 => Ok(T::load(payload)),
+                    _ => Err(E::load(payload)),
+                }
+            }
+            unsafe fn store(&self, ptr: *mut u8) {
+                let payload = ptr.add(canonical_align_to(1, canonical_max(T::ALIGN, E::ALIGN)));
+                match self {
+                    Ok(t) => {
+                        *ptr = 0;
+                        t.store(payload);
+                    }
+                    Err(e) => {
+                        *ptr = 1;
+                        e.store(payload);
+                    }
+                }
+            }
+            fn lift_flat(flat: &[u64]) -> Self {
+                match flat[0] {
+                    0 => Ok(T::lift_flat(&flat[1..])),
+                    _ => Err(E::lift_flat(&flat[1..])),
+                }
+            }
+            fn lower_flat(&self, flat: &mut Vec<u64>) {
+                let start = flat.len();
+                match self {
+                    Ok(t) => {
+                        flat.push(0);
+                        t.lower_flat(flat);
+                    }
+                    Err(e) => {
+                        flat.push(1);
+                        e.lower_flat(flat);
+                    }
+                }
+                flat.resize(start + Self::FLAT, 0);
+            }
+        }
+    };
+
+    // WIT is only used by the host, so this is not part of WASM_PARSING_TRAIT_STR
+    let wit_primitives = [
+        ("String", "string"), ("bool", "bool"), ("char", "char"),
+        ("i8", "s8"), ("u8", "u8"), ("i16", "s16"), ("u16", "u16"), ("i32", "s32"), ("u32", "u32"),
+        ("i64", "s64"), ("u64", "u64"), ("isize", "s64"), ("usize", "u64"), ("f32", "f32"), ("f64", "f64"),
+    ].map(|(ty, wit)| {
+        let ty = format_ident!("{ty}");
+        quote! {
+            impl WitType for #ty {
+                fn wit_type_name() -> String { #wit.to_string() }
+            }
+        }
+    });
+    let wit_stuff = quote! {
+        /// maps a type to its WIT (WebAssembly Interface Types) equivalent. see `wasm_type_gen::wit`
+        pub trait WitType {
+            /// how this type is referred to in WIT, eg: `list<string>`
+            fn wit_type_name() -> String;
+            /// push the WIT definitions (records, variants, etc.) of this type, and of every type it contains
+            fn add_wit_definitions(_defs: &mut Vec<String>) {}
+        }
+
+        #(#wit_primitives)*
+
+        impl<T: WitType> WitType for Option<T> {
+            fn wit_type_name() -> String { format!("option<{}>", T::wit_type_name()) }
+            fn add_wit_definitions(defs: &mut Vec<String>) { T::add_wit_definitions(defs) }
+        }
+        impl<T: WitType, U: WitType> WitType for Result<T, U> {
+            fn wit_type_name() -> String { format!("result<{}, {}>", T::wit_type_name(), U::wit_type_name()) }
+            fn add_wit_definitions(defs: &mut Vec<String>) {
+                T::add_wit_definitions(defs);
+                U::add_wit_definitions(defs);
+            }
+        }
+        impl<T: WitType> WitType for Vec<T> {
+            fn wit_type_name() -> String { format!("list<{}>", T::wit_type_name()) }
+            fn add_wit_definitions(defs: &mut Vec<String>) { T::add_wit_definitions(defs) }
+        }
+        impl<T: WitType, const N: usize> WitType for [T; N] {
+            fn wit_type_name() -> String { format!("list<{}>", T::wit_type_name()) }
+            fn add_wit_definitions(defs: &mut Vec<String>) { T::add_wit_definitions(defs) }
+        }
+        impl<T: WitType, U: WitType> WitType for std::collections::HashMap<T, U> {
+            fn wit_type_name() -> String { format!("list<tuple<{}, {}>>", T::wit_type_name(), U::wit_type_name()) }
+            fn add_wit_definitions(defs: &mut Vec<String>) {
+                T::add_wit_definitions(defs);
+                U::add_wit_definitions(defs);
+            }
+        }
+    };
+
+    let trait_stuff_str = quote! { #trait_stuff #canonical_abi_stuff }.to_string();
     let expanded = quote! {
         #trait_stuff
 
+        #wit_stuff
+
         pub const WASM_PARSING_TRAIT_STR: &'static str = #trait_stuff_str;
     };
 
     TokenStream::from(expanded)
 }
 
+const WIT_KEYWORDS: [&str; 40] = [
+    "as", "bool", "borrow", "char", "constructor", "enum", "export", "f32", "f64", "flags",
+    "float32", "float64", "from", "func", "future", "import", "include", "interface", "list", "own",
+    "option", "package", "record", "resource", "result", "s16", "s32", "s64", "s8", "static",
+    "stream", "string", "tuple", "type", "u16", "u32", "u64", "u8", "use", "variant",
+];
+
+/// rust identifier -> WIT identifier. WIT identifiers are kebab-case,
+/// eg: `MyStruct` -> `my-struct`, `some_field` -> `some-field`. keywords get escaped with `%`
+fn to_wit_ident(name: &str) -> String {
+    let name = name.trim_start_matches("r#");
+    let chars: Vec<char> = name.chars().collect();
+    let mut words: Vec<String> = vec![];
+    let mut current = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        if *c == '_' {
+            if !current.is_empty() {
+                words.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        let prev = if i > 0 { chars.get(i - 1) } else { None };
+        let next = chars.get(i + 1);
+        // a new word starts at an uppercase letter that follows a lowercase letter/digit (myStruct),
+        // or that is followed by a lowercase letter after an acronym (HTTPServer)
+        let starts_word = c.is_uppercase() && prev.map(|p| {
+            p.is_lowercase() || p.is_ascii_digit() || (p.is_uppercase() && next.map(|n| n.is_lowercase()).unwrap_or(false))
+        }).unwrap_or(false);
+        if starts_word && !current.is_empty() {
+            words.push(std::mem::take(&mut current));
+        }
+        current.extend(c.to_lowercase());
+    }
+    if !current.is_empty() {
+        words.push(current);
+    }
+    // WIT words cant start with a digit, so those get merged with the previous word
+    let mut out = String::new();
+    for word in words {
+        let starts_with_digit = word.chars().next().map(|c| c.is_ascii_digit()).unwrap_or(false);
+        if !out.is_empty() && !starts_with_digit {
+            out.push('-');
+        }
+        out.push_str(&word);
+    }
+    if WIT_KEYWORDS.contains(&out.as_str()) {
+        out.insert(0, '%');
+    }
+    out
+}
+
+/// `impl WitType for #name`. structs become records (tuple structs get fields named f0, f1, ...),
+/// enums with only unit variants become enums, and all other enums become variants.
+/// a variant case with more than one field gets a tuple payload.
+/// the impl only applies if every field type is a WitType. so a type containing eg: an i128 still derives,
+/// it just cant be used with WIT. the `for<'__wit>` is what lets a bound that is never true compile.
+fn wit_type_impl(name: &proc_macro2::Ident, data: &Data) -> proc_macro2::TokenStream {
+    let wit_name = to_wit_ident(&name.to_string());
+    let mut contained_types: Vec<&Type> = vec![];
+    let build_def = match data {
+        Data::Struct(data) => {
+            let fields = data.fields.iter().enumerate().map(|(i, field)| {
+                let field_name = match &field.ident {
+                    Some(ident) => to_wit_ident(&ident.to_string()),
+                    None => format!("f{i}"),
+                };
+                let ty = &field.ty;
+                contained_types.push(ty);
+                quote! {
+                    def.push_str(&format!("    {}: {},\n", #field_name, <#ty as WitType>::wit_type_name()));
+                }
+            }).collect::<Vec<_>>();
+            quote! {
+                let mut def = format!("record {} {{\n", #wit_name);
+                #(#fields)*
+                def.push('}');
+            }
+        }
+        Data::Enum(data) => {
+            let all_unit = data.variants.iter().all(|v| matches!(v.fields, Fields::Unit));
+            let keyword = if all_unit { "enum" } else { "variant" };
+            let cases = data.variants.iter().map(|v| {
+                let case_name = to_wit_ident(&v.ident.to_string());
+                let tys: Vec<&Type> = v.fields.iter().map(|f| &f.ty).collect();
+                contained_types.extend(tys.iter().cloned());
+                match tys.len() {
+                    0 => quote! {
+                        def.push_str(&format!("    {},\n", #case_name));
+                    },
+                    1 => {
+                        let ty = tys[0];
+                        quote! {
+                            def.push_str(&format!("    {}({}),\n", #case_name, <#ty as WitType>::wit_type_name()));
+                        }
+                    }
+                    _ => quote! {
+                        let tys: Vec<String> = vec![#(<#tys as WitType>::wit_type_name()),*];
+                        def.push_str(&format!("    {}(tuple<{}>),\n", #case_name, tys.join(", ")));
+                    },
+                }
+            }).collect::<Vec<_>>();
+            quote! {
+                let mut def = format!("{} {} {{\n", #keyword, #wit_name);
+                #(#cases)*
+                def.push('}');
+            }
+        }
+        Data::Union(_) => unimplemented!("WasmTypeGen not implemented for Unions"),
+    };
+    quote! {
+        impl WitType for #name where #(for<'__wit> #contained_types: WitType,)* {
+            fn wit_type_name() -> String {
+                #wit_name.to_string()
+            }
+            fn add_wit_definitions(defs: &mut Vec<String>) {
+                #build_def
+                if defs.contains(&def) {
+                    return;
+                }
+                defs.push(def);
+                #(<#contained_types as WitType>::add_wit_definitions(defs);)*
+            }
+        }
+    }
+}
+
+/// the fields of a record, or of a variant case's payload, which is laid out like a tuple of its fields.
+/// returns the pattern / constructor that binds them to `__c0`, `__c1`, ... and their types
+fn canonical_fields(path: proc_macro2::TokenStream, fields: &Fields) -> (proc_macro2::TokenStream, Vec<proc_macro2::Ident>, Vec<&Type>) {
+    let vars: Vec<_> = (0..fields.len()).map(|i| format_ident!("__c{i}")).collect();
+    let tys = fields.iter().map(|f| &f.ty).collect();
+    let pattern = match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|f| &f.ident);
+            quote! { #path { #(#names: #vars),* } }
+        }
+        Fields::Unnamed(_) => quote! { #path(#(#vars),*) },
+        Fields::Unit => path,
+    };
+    (pattern, vars, tys)
+}
+
+/// the CanonicalAbi impl that guests built as components use to pass this type to the host (see WitType for how
+/// the type maps to WIT). like WitType, it only applies if every field type implements it. this only goes to the
+/// guest, which is why this returns the impl as a string
+fn canonical_abi_impl(name: &proc_macro2::Ident, data: &Data) -> String {
+    // the alignment of a list of fields, and their size when laid out like a record
+    let fields_align = |tys: &[&Type]| quote! {
+        { let mut align = 1; #(align = canonical_max(align, <#tys as CanonicalAbi>::ALIGN);)* align }
+    };
+    let fields_size = |tys: &[&Type]| {
+        let align = fields_align(tys);
+        quote! {
+            { let mut size = 0; #(size = canonical_align_to(size, <#tys as CanonicalAbi>::ALIGN) + <#tys as CanonicalAbi>::SIZE;)* canonical_align_to(size, #align) }
+        }
+    };
+    let load_fields = |vars: &[proc_macro2::Ident], tys: &[&Type]| quote! {
+        let mut offset = 0;
+        #(
+            offset = canonical_align_to(offset, <#tys as CanonicalAbi>::ALIGN);
+            let #vars = <#tys as CanonicalAbi>::load(ptr.add(offset));
+            offset += <#tys as CanonicalAbi>::SIZE;
+        )*
+    };
+    let store_fields = |vars: &[proc_macro2::Ident], tys: &[&Type]| quote! {
+        let mut offset = 0;
+        #(
+            offset = canonical_align_to(offset, <#tys as CanonicalAbi>::ALIGN);
+            #vars.store(ptr.add(offset));
+            offset += <#tys as CanonicalAbi>::SIZE;
+        )*
+    };
+    let lift_fields = |vars: &[proc_macro2::Ident], tys: &[&Type]| quote! {
+        let mut i = 0;
+        #(
+            let #vars = <#tys as CanonicalAbi>::lift_flat(&flat[i..]);
+            i += <#tys as CanonicalAbi>::FLAT;
+        )*
+    };
+
+    let (contained_types, body) = match data {
+        Data::Struct(data) => {
+            let (pattern, vars, tys) = canonical_fields(quote!(Self), &data.fields);
+            let align = fields_align(&tys);
+            let size = fields_size(&tys);
+            let load = load_fields(&vars, &tys);
+            let store = store_fields(&vars, &tys);
+            let lift = lift_fields(&vars, &tys);
+            let body = quote! {
+                const ALIGN: usize = #align;
+                const SIZE: usize = #size;
+                const FLAT: usize = 0 #(+ <#tys as CanonicalAbi>::FLAT)*;
+                unsafe fn load(ptr: *const u8) -> Self {
+                    #load
+                    #pattern
+                }
+                unsafe fn store(&self, ptr: *mut u8) {
+                    let #pattern = self;
+                    #store
+                }
+                fn lift_flat(flat: &[u64]) -> Self {
+                    #lift
+                    #pattern
+                }
+                fn lower_flat(&self, flat: &mut Vec<u64>) {
+                    let #pattern = self;
+                    #(#vars.lower_flat(flat);)*
+                }
+            };
+            (tys, body)
+        }
+        // enums are variants without payloads, so this handles both
+        Data::Enum(data) => {
+            let (disc_ty, disc_size) = match data.variants.len() {
+                0..=256 => (quote!(u8), 1usize),
+                257..=65536 => (quote!(u16), 2),
+                _ => (quote!(u32), 4),
+            };
+            let mut all_tys = vec![];
+            let mut case_sizes = vec![];
+            let mut case_flats = vec![];
+            let mut loads = vec![];
+            let mut stores = vec![];
+            let mut lifts = vec![];
+            let mut lowers = vec![];
+            for (i, v) in data.variants.iter().enumerate() {
+                let ident = &v.ident;
+                let (pattern, vars, tys) = canonical_fields(quote!(Self::#ident), &v.fields);
+                let disc = proc_macro2::Literal::usize_unsuffixed(i);
+                let load = load_fields(&vars, &tys);
+                let store = store_fields(&vars, &tys);
+                let lift = lift_fields(&vars, &tys);
+                case_sizes.push(fields_size(&tys));
+                case_flats.push(quote! { 0 #(+ <#tys as CanonicalAbi>::FLAT)* });
+                loads.push(quote! {
+                    #disc => {
+                        let ptr = payload;
+                        #load
+                        #pattern
+                    }
+                });
+                stores.push(quote! {
+                    #pattern => {
+                        (ptr as *mut #disc_ty).write_unaligned(#disc);
+                        let ptr = payload;
+                        #store
+                    }
+                });
+                lifts.push(quote! {
+                    #disc => {
+                        let flat = &flat[1..];
+                        #lift
+                        #pattern
+                    }
+                });
+                lowers.push(quote! {
+                    #pattern => {
+                        flat.push(#disc);
+                        #(#vars.lower_flat(flat);)*
+                    }
+                });
+                all_tys.extend(tys);
+            }
+            let payload_align = fields_align(&all_tys);
+            let payload_offset = quote! { canonical_align_to(#disc_size, #payload_align) };
+            let body = quote! {
+                const ALIGN: usize = canonical_max(#disc_size, #payload_align);
+                const SIZE: usize = {
+                    let mut size = 0;
+                    #(size = canonical_max(size, #case_sizes);)*
+                    canonical_align_to(#payload_offset + size, Self::ALIGN)
+                };
+                const FLAT: usize = { let mut flat = 0; #(flat = canonical_max(flat, #case_flats);)* 1 + flat };
+                unsafe fn load(ptr: *const u8) -> Self {
+                    let payload = ptr.add(#payload_offset);
+                    match (ptr as *const #disc_ty).read_unaligned() {
+                        #(#loads)*
+                        disc => panic!("invalid discriminant {}", disc),
+                    }
+                }
+                unsafe fn store(&self, ptr: *mut u8) {
+                    let payload = ptr.add(#payload_offset);
+                    match self {
+                        #(#stores)*
+                    }
+                }
+                fn lift_flat(flat: &[u64]) -> Self {
+                    match flat[0] {
+                        #(#lifts)*
+                        disc => panic!("invalid discriminant {}", disc),
+                    }
+                }
+                fn lower_flat(&self, flat: &mut Vec<u64>) {
+                    // every case is passed as the same number of values
+                    let start = flat.len();
+                    match self {
+                        #(#lowers)*
+                    }
+                    flat.resize(start + Self::FLAT, 0);
+                }
+            };
+            (all_tys, body)
+        }
+        Data::Union(_) => unimplemented!("WasmTypeGen not implemented for Unions"),
+    };
+    quote! {
+        #[allow(unused_assignments, unused_variables, unused_mut)]
+        impl CanonicalAbi for #name where #(for<'__c> #contained_types: CanonicalAbi,)* {
+            #body
+        }
+    }.to_string()
+}
+
 fn set_include_wasm(add_includes: &mut Vec<proc_macro2::TokenStream>, unique_types: &mut Vec<Type>, ty: &Type) {
     match ty {
         Type::Path(p) => {
@@ -739,7 +1427,7 @@ fn set_include_wasm(add_includes: &mut Vec<proc_macro2::TokenStream>, unique_typ
                     if !unique_types.contains(ty) {
                         unique_types.push(ty.clone());
                         add_includes.push(quote! {
-                            #ty::include_in_rs_wasm(),
+                            #ty::include_defs(defs);
                         });
                     }
                 }
@@ -1081,6 +1769,27 @@ fn wasm_type_gen_struct_unnamed_fields(
 }
 
 
+/// the WasmIncludeString impl. def is the type's own definition, add_includes adds the types it uses
+fn include_string_impl(name: &proc_macro2::Ident, def: &str, add_includes: &[proc_macro2::TokenStream], entrypoint_str: &str) -> proc_macro2::TokenStream {
+    let name_str = name.to_string();
+    quote! {
+        impl WasmIncludeString for #name {
+            fn include_defs(defs: &mut Vec<(String, String)>) {
+                // a type used by several of our fields' types is only defined once
+                if defs.iter().any(|(name, _)| name == #name_str) {
+                    return;
+                }
+                defs.push((#name_str.to_string(), #def.to_string()));
+                #(#add_includes)*
+            }
+
+            fn gen_entrypoint() -> &'static str {
+                #entrypoint_str
+            }
+        }
+    }
+}
+
 #[proc_macro_derive(WasmTypeGen)]
 pub fn module(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let item_cloned = item.clone();
@@ -1158,39 +1867,61 @@ pub fn module(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
         }
     };
 
+    let wit_impl_block = wit_type_impl(&name, &thing.data);
+
     let transfer_impl_block_str = transfer_impl_block.to_string();
     let transfer_impl_block2_str = transfer_impl_block2.to_string();
     let entrypoint_str = entrypoint.to_string();
 
+    let canonical_abi_str = canonical_abi_impl(&name, &thing.data);
+
+    let def = [structdef, transfer_impl_block_str, transfer_impl_block2_str, canonical_abi_str, "".into()].join("\n");
+    let include_impl = include_string_impl(&name, &def, &add_includes, &entrypoint_str);
+
     let expanded = quote! {
         #transfer_impl_block
         #transfer_impl_block2
-
-        impl WasmIncludeString for #name {
-            fn include_in_rs_wasm() -> String {
-                let strings = [
-                    #structdef,
-                    #transfer_impl_block_str,
-                    #transfer_impl_block2_str,
-                    "",
-                ];
-                let mut out = strings.join("\n").to_string();
-                let extras: &[String] = &[
-                    #(#add_includes)*
-                ];
-                for extra in extras {
-                    out.push('\n');
-                    out.push_str(&extra);
-                }
-                out
-            }
-
-            fn gen_entrypoint() -> &'static str {
-                #entrypoint_str
-            }
-        }
+        #wit_impl_block
+        #include_impl
     };
 
     // Hand the output tokens back to the compiler
     TokenStream::from(expanded)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_used_type_is_included_once() {
+        let mut add_includes = vec![];
+        let mut unique_types = vec![];
+        let types: [Type; 5] = [
+            syn::parse_quote!(Leaf),
+            syn::parse_quote!(Vec<Leaf>),
+            syn::parse_quote!(Option<HashMap<String, Leaf>>),
+            syn::parse_quote!([Other; 2]),
+            syn::parse_quote!(u32),
+        ];
+        for ty in types.iter() {
+            set_include_wasm(&mut add_includes, &mut unique_types, ty);
+        }
+        let includes: Vec<String> = add_includes.iter().map(|i| i.to_string()).collect();
+        assert_eq!(includes, [quote! { Leaf::include_defs(defs); }.to_string(), quote! { Other::include_defs(defs); }.to_string()]);
+    }
+
+    #[test]
+    fn includes_are_keyed_by_type_name() {
+        let name = format_ident!("Root");
+        let add_includes = [quote! { Leaf::include_defs(defs); }];
+        let generated = include_string_impl(&name, "pub struct Root { leaf: Leaf }", &add_includes, "").to_string();
+        // skip the type if its already there, otherwise add it and then the types it uses
+        let expected = [
+            quote! { if defs.iter().any(|(name, _)| name == "Root") { return; } }.to_string(),
+            quote! { defs.push(("Root".to_string(), "pub struct Root { leaf: Leaf }".to_string())); }.to_string(),
+            quote! { Leaf::include_defs(defs); }.to_string(),
+        ].join(" ");
+        assert!(generated.contains(&expected), "{generated}");
+    }
+}